@@ -0,0 +1,457 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::dns::{DNSPacket, DNSQuestion, DNSRecord, DNSRecordClass, DNSRecordType, ToBytes};
+use crate::rdata::RData;
+
+/// Upper bound on how long anything is kept, however long its TTL.
+pub const MAX_CACHE_TTL: u32 = 7 * 24 * 60 * 60;
+
+/// What a cached response answers. Names compare case-insensitively.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    name: String,
+    r#type: DNSRecordType,
+    class: DNSRecordClass,
+}
+
+impl CacheKey {
+    pub fn new(name: &str, r#type: DNSRecordType, class: DNSRecordClass) -> Self {
+        Self {
+            name: name.trim_end_matches('.').to_ascii_lowercase(),
+            r#type,
+            class,
+        }
+    }
+}
+
+impl From<&DNSQuestion> for CacheKey {
+    fn from(question: &DNSQuestion) -> Self {
+        Self::new(&question.name, question.r#type, question.class)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// How many responses to keep at most.
+    pub max_entries: Option<usize>,
+    /// Roughly how much memory to use at most, going by the wire size
+    /// of each response and its name.
+    pub max_bytes: Option<usize>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: Some(10_000),
+            max_bytes: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries pushed out to stay within the configured limits.
+    pub evictions: u64,
+    /// Entries dropped because their TTL ran out.
+    pub expirations: u64,
+}
+
+/// Entries that each expire after their own TTL, kept within the limits
+/// in a [CacheConfig] by dropping the least recently used. Both caches
+/// are built on this.
+#[derive(Debug)]
+struct Lru<K, V> {
+    config: CacheConfig,
+    entries: HashMap<K, LruEntry<V>>,
+    /// Every entry's key, oldest use first.
+    recency: BTreeMap<u64, K>,
+    clock: u64,
+    bytes: usize,
+    stats: CacheStats,
+}
+
+#[derive(Debug, Clone)]
+struct LruEntry<V> {
+    value: V,
+    inserted_at: Instant,
+    ttl: u32,
+    size: usize,
+    /// When the entry was last handed out, as a position in `Lru::recency`.
+    last_used: u64,
+}
+
+impl<V> LruEntry<V> {
+    fn expires_at(&self) -> Instant {
+        self.inserted_at + Duration::from_secs(self.ttl as u64)
+    }
+}
+
+impl<K, V> Default for Lru<K, V> {
+    fn default() -> Self {
+        Self {
+            config: CacheConfig::default(),
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            bytes: 0,
+            stats: CacheStats::default(),
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash, V> Lru<K, V> {
+    fn with_config(config: CacheConfig) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    /// Store `value` in place of whatever `key` had, counting `size`
+    /// bytes against the limits. Values too big to ever fit are ignored.
+    fn insert(&mut self, key: K, value: V, ttl: u32, size: usize, now: Instant) {
+        if self
+            .config
+            .max_bytes
+            .is_some_and(|max_bytes| size > max_bytes)
+        {
+            return;
+        }
+
+        self.remove(&key);
+        let last_used = self.tick();
+        self.recency.insert(last_used, key.clone());
+        self.bytes += size;
+        self.entries.insert(
+            key,
+            LruEntry {
+                value,
+                inserted_at: now,
+                ttl,
+                size,
+                last_used,
+            },
+        );
+
+        while self.over_budget() {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.bytes -= entry.size;
+            }
+            self.stats.evictions += 1;
+        }
+    }
+
+    /// The entry for `key` if it hasn't expired, which now counts as its
+    /// most recent use. An expired one is dropped on the way.
+    fn get<Q>(&mut self, key: &Q, now: Instant) -> Option<&LruEntry<V>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let Some(entry) = self.entries.get(key) else {
+            self.stats.misses += 1;
+            return None;
+        };
+        if now >= entry.expires_at() {
+            self.remove(key);
+            self.stats.expirations += 1;
+            self.stats.misses += 1;
+            return None;
+        }
+        self.stats.hits += 1;
+
+        let last_used = self.tick();
+        let entry = self.entries.get_mut(key)?;
+        let previous = std::mem::replace(&mut entry.last_used, last_used);
+        if let Some(key) = self.recency.remove(&previous) {
+            self.recency.insert(last_used, key);
+        }
+        self.entries.get(key)
+    }
+
+    fn remove<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+            self.bytes -= entry.size;
+        }
+    }
+
+    /// Drop everything that has outlived its TTL, returning how many went.
+    fn remove_expired(&mut self, now: Instant) -> usize {
+        let expired: Vec<K> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| now >= entry.expires_at())
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired.iter() {
+            self.remove(key);
+        }
+        self.stats.expirations += expired.len() as u64;
+        expired.len()
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn over_budget(&self) -> bool {
+        let too_many = self
+            .config
+            .max_entries
+            .is_some_and(|max_entries| self.entries.len() > max_entries);
+        let too_big = self
+            .config
+            .max_bytes
+            .is_some_and(|max_bytes| self.bytes > max_bytes);
+        too_many || too_big
+    }
+}
+
+/// Responses we've resolved before, kept for as long as their TTLs allow
+/// and the configured limits have room for. When there isn't room, the
+/// least recently used entries make way.
+///
+/// The `_at` variants take the current time so that expiry can be tested
+/// without waiting around.
+#[derive(Debug, Default)]
+pub struct Cache {
+    entries: Lru<CacheKey, DNSPacket>,
+}
+
+impl Cache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(config: CacheConfig) -> Self {
+        Self {
+            entries: Lru::with_config(config),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.entries.is_empty()
+    }
+
+    /// The approximate memory used by the entries, as counted against `max_bytes`.
+    pub fn size_in_bytes(&self) -> usize {
+        self.entries.bytes
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.entries.stats
+    }
+
+    pub fn insert(&mut self, key: CacheKey, packet: DNSPacket) {
+        self.insert_at(key, packet, Instant::now())
+    }
+
+    /// Store `packet` for the smallest TTL in its answer section, or for
+    /// its negative TTL if it says the name or records don't exist.
+    /// Packets that shouldn't be kept at all are ignored.
+    pub fn insert_at(&mut self, key: CacheKey, mut packet: DNSPacket, now: Instant) {
+        let ttl = match packet.negative_ttl() {
+            Some(negative_ttl) => {
+                // The SOA is handed out with the negative TTL (RFC 2308 3).
+                for record in packet.authorities.iter_mut() {
+                    if let RData::SOA(_) = record.data {
+                        record.ttl = negative_ttl;
+                    }
+                }
+                negative_ttl
+            }
+            None => match packet.answers.iter().map(|record| record.ttl).min() {
+                Some(ttl) => ttl,
+                None => return,
+            },
+        };
+        let ttl = ttl.min(MAX_CACHE_TTL);
+        if ttl == 0 {
+            return;
+        }
+
+        let mut wire = vec![];
+        if packet.to_bytes(&mut wire).is_err() {
+            return;
+        }
+        let size = key.name.len() + wire.len();
+        self.entries.insert(key, packet, ttl, size, now);
+    }
+
+    pub fn get(&mut self, key: &CacheKey) -> Option<DNSPacket> {
+        self.get_at(key, Instant::now())
+    }
+
+    /// Look up `key`, with every TTL in the returned packet lowered by
+    /// the time it has spent in the cache.
+    pub fn get_at(&mut self, key: &CacheKey, now: Instant) -> Option<DNSPacket> {
+        let entry = self.entries.get(key, now)?;
+        let elapsed = now.duration_since(entry.inserted_at).as_secs() as u32;
+        let mut packet = entry.value.clone();
+        for record in packet
+            .answers
+            .iter_mut()
+            .chain(packet.authorities.iter_mut())
+            .chain(packet.additionals.iter_mut())
+        {
+            record.ttl = record.ttl.saturating_sub(elapsed);
+        }
+        Some(packet)
+    }
+
+    /// Sweep out expired responses, as `Lru::remove_expired` does.
+    pub fn remove_expired(&mut self, now: Instant) -> usize {
+        self.entries.remove_expired(now)
+    }
+}
+
+/// A zone cut: the nameservers a referral handed us for `zone`, along
+/// with whatever addresses (glue) came with them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delegation {
+    pub zone: String,
+    pub nameservers: Vec<(String, Vec<IpAddr>)>,
+    /// The smallest TTL among the NS and glue records.
+    pub ttl: u32,
+}
+
+impl Delegation {
+    /// The delegation `response` refers us to, if it is a referral.
+    pub fn from_referral(response: &DNSPacket) -> Option<Self> {
+        let ns_records: Vec<&DNSRecord> = response
+            .authorities
+            .iter()
+            .filter(|record| matches!(record.data, RData::NS(_)))
+            .collect();
+        let zone = ns_records.first()?.name.clone();
+
+        let mut ttl = MAX_CACHE_TTL;
+        let mut nameservers = vec![];
+        for record in ns_records {
+            let RData::NS(name) = &record.data else {
+                continue;
+            };
+            if !record.name.eq_ignore_ascii_case(&zone) {
+                continue;
+            }
+            ttl = ttl.min(record.ttl);
+            for glue in response
+                .additionals
+                .iter()
+                .filter(|glue| glue.name.eq_ignore_ascii_case(name))
+                .filter(|glue| matches!(glue.data, RData::A(_) | RData::AAAA(_)))
+            {
+                ttl = ttl.min(glue.ttl);
+            }
+            nameservers.push((name.clone(), response.get_glue(name)));
+        }
+
+        Some(Self {
+            zone,
+            nameservers,
+            ttl,
+        })
+    }
+}
+
+/// The zone cuts we've been referred to, so that resolution can start
+/// from the closest known nameservers instead of from the root.
+///
+/// Zones compare case-insensitively. Like [Cache], it's kept within the
+/// limits in its [CacheConfig] by dropping the least recently used zones,
+/// with the size of each going by the names and addresses in it.
+#[derive(Debug, Default)]
+pub struct DelegationCache {
+    zones: Lru<String, Delegation>,
+}
+
+impl DelegationCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(config: CacheConfig) -> Self {
+        Self {
+            zones: Lru::with_config(config),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.zones.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.entries.is_empty()
+    }
+
+    pub fn insert(&mut self, delegation: Delegation) {
+        self.insert_at(delegation, Instant::now())
+    }
+
+    /// Remember `delegation` for its TTL. Ones that aren't to be kept at all are ignored.
+    pub fn insert_at(&mut self, delegation: Delegation, now: Instant) {
+        if delegation.ttl == 0 || delegation.nameservers.is_empty() {
+            return;
+        }
+        let key = zone_key(&delegation.zone);
+        let size = key.len()
+            + delegation
+                .nameservers
+                .iter()
+                .map(|(name, glue)| name.len() + 16 * glue.len())
+                .sum::<usize>();
+        let ttl = delegation.ttl;
+        self.zones.insert(key, delegation, ttl, size, now);
+    }
+
+    pub fn closest(&mut self, name: &str) -> Option<Delegation> {
+        self.closest_at(name, Instant::now())
+    }
+
+    /// The deepest unexpired delegation that `name` falls under.
+    pub fn closest_at(&mut self, name: &str, now: Instant) -> Option<Delegation> {
+        let name = zone_key(name);
+        let mut zone = name.as_str();
+        loop {
+            if let Some(entry) = self.zones.get(zone, now) {
+                return Some(entry.value.clone());
+            }
+            if zone.is_empty() {
+                return None;
+            }
+            zone = zone.split_once('.').map_or("", |(_, parent)| parent);
+        }
+    }
+
+    pub fn remove(&mut self, zone: &str) {
+        self.zones.remove(zone_key(zone).as_str());
+    }
+
+    /// Sweep out expired delegations, as `Lru::remove_expired` does.
+    pub fn remove_expired(&mut self, now: Instant) -> usize {
+        self.zones.remove_expired(now)
+    }
+}
+
+fn zone_key(zone: &str) -> String {
+    zone.trim_end_matches('.').to_ascii_lowercase()
+}
@@ -1,19 +1,28 @@
-use std::io::{Cursor, Read, Seek};
-use std::net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs, UdpSocket};
+use std::io::{Read, Seek};
+use std::net::{
+    AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket,
+};
 use std::num::TryFromIntError;
 use std::string::FromUtf8Error;
+use std::time::{Duration, Instant};
+
+use log::debug;
 
 use rand::prelude::*;
 use rand::thread_rng;
 use structure::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use thiserror::Error;
 
+use crate::edns::{Edns, DEFAULT_EDNS_PAYLOAD_SIZE, MIN_UDP_PAYLOAD_SIZE};
+use crate::rdata::{self, RData, SOAData};
+use crate::transport::{self, NetworkTransport, Transport};
+
 pub type Int = u16;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct DNSHeader {
     pub id: Int,
-    pub flags: DNSHeaderFlag,
+    pub flags: DNSHeaderFlags,
     pub num_questions: Int,
     pub num_answers: Int,
     pub num_authorities: Int,
@@ -42,75 +51,259 @@ impl ToBytes for DNSHeader {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A record type. Types without a variant of their own are kept as
+/// [DNSRecordType::Unknown], so that records of any type can be passed
+/// through (RFC 3597).
+#[derive(Debug, Clone, Copy)]
 pub enum DNSRecordType {
-    A = 1,
-    AAAA = 28,
-    NS = 2,
+    A,
+    AAAA,
+    NS,
     // MD = 3,
     // MF = 4,
-    CNAME = 5,
-    TXT = 16,
-    OPT = 41,
-    SOA = 6,
-    // add more,
+    CNAME,
+    PTR,
+    MX,
+    TXT,
+    SRV,
+    NAPTR,
+    OPT,
+    SOA,
+    DS,
+    RRSIG,
+    NSEC,
+    DNSKEY,
+    NSEC3,
+    NSEC3PARAM,
+    CAA,
+    /// Build these with `From<Int>`, which picks the named variant when
+    /// there is one. Types compare by number, so one made by hand for a
+    /// type we know is still equal to its named variant.
+    Unknown(Int),
 }
 
-impl TryFrom<Int> for DNSRecordType {
-    type Error = DNSError;
-    fn try_from(value: Int) -> Result<Self, Self::Error> {
+impl PartialEq for DNSRecordType {
+    fn eq(&self, other: &Self) -> bool {
+        Int::from(*self) == Int::from(*other)
+    }
+}
+
+impl Eq for DNSRecordType {}
+
+impl std::hash::Hash for DNSRecordType {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        Int::from(*self).hash(state);
+    }
+}
+
+impl From<Int> for DNSRecordType {
+    fn from(value: Int) -> Self {
         match value {
-            1 => Ok(Self::A),
-            2 => Ok(Self::NS),
-            5 => Ok(Self::CNAME),
-            6 => Ok(Self::SOA),
-            16 => Ok(Self::TXT),
-            28 => Ok(Self::AAAA),
-            41 => Ok(Self::OPT),
-            _ => Err(DNSError::BadRecordType(value)),
+            1 => Self::A,
+            2 => Self::NS,
+            5 => Self::CNAME,
+            6 => Self::SOA,
+            12 => Self::PTR,
+            15 => Self::MX,
+            16 => Self::TXT,
+            28 => Self::AAAA,
+            33 => Self::SRV,
+            35 => Self::NAPTR,
+            41 => Self::OPT,
+            43 => Self::DS,
+            46 => Self::RRSIG,
+            47 => Self::NSEC,
+            48 => Self::DNSKEY,
+            50 => Self::NSEC3,
+            51 => Self::NSEC3PARAM,
+            257 => Self::CAA,
+            _ => Self::Unknown(value),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+impl From<DNSRecordType> for Int {
+    fn from(r#type: DNSRecordType) -> Self {
+        match r#type {
+            DNSRecordType::A => 1,
+            DNSRecordType::NS => 2,
+            DNSRecordType::CNAME => 5,
+            DNSRecordType::SOA => 6,
+            DNSRecordType::PTR => 12,
+            DNSRecordType::MX => 15,
+            DNSRecordType::TXT => 16,
+            DNSRecordType::AAAA => 28,
+            DNSRecordType::SRV => 33,
+            DNSRecordType::NAPTR => 35,
+            DNSRecordType::OPT => 41,
+            DNSRecordType::DS => 43,
+            DNSRecordType::RRSIG => 46,
+            DNSRecordType::NSEC => 47,
+            DNSRecordType::DNSKEY => 48,
+            DNSRecordType::NSEC3 => 50,
+            DNSRecordType::NSEC3PARAM => 51,
+            DNSRecordType::CAA => 257,
+            DNSRecordType::Unknown(value) => value,
+        }
+    }
+}
+
+impl DNSRecordType {
+    /// The type's mnemonic, or the generic `TYPEnnn` form from
+    /// RFC 3597 section 5 for types we don't know.
+    pub fn mnemonic(value: Int) -> String {
+        Self::from(value).to_string()
+    }
+}
+
+impl std::fmt::Display for DNSRecordType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unknown(value) => write!(f, "TYPE{}", value),
+            r#type => write!(f, "{:?}", r#type),
+        }
+    }
+}
+
+/// Parse a type's mnemonic, as in zone files, or its `TYPEnnn` form.
+impl std::str::FromStr for DNSRecordType {
+    type Err = DNSError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "A" => Ok(Self::A),
+            "NS" => Ok(Self::NS),
+            "CNAME" => Ok(Self::CNAME),
+            "SOA" => Ok(Self::SOA),
+            "PTR" => Ok(Self::PTR),
+            "MX" => Ok(Self::MX),
+            "TXT" => Ok(Self::TXT),
+            "AAAA" => Ok(Self::AAAA),
+            "SRV" => Ok(Self::SRV),
+            "NAPTR" => Ok(Self::NAPTR),
+            "OPT" => Ok(Self::OPT),
+            "DS" => Ok(Self::DS),
+            "RRSIG" => Ok(Self::RRSIG),
+            "NSEC" => Ok(Self::NSEC),
+            "DNSKEY" => Ok(Self::DNSKEY),
+            "NSEC3" => Ok(Self::NSEC3),
+            "NSEC3PARAM" => Ok(Self::NSEC3PARAM),
+            "CAA" => Ok(Self::CAA),
+            other => match other.strip_prefix("TYPE").map(str::parse::<Int>) {
+                Some(Ok(value)) => Ok(Self::from(value)),
+                _ => Err(DNSError::UnknownMnemonic(s.to_string())),
+            },
+        }
+    }
+}
+
+/// A record class, with the ones we don't know kept as [DNSRecordClass::Unknown].
+#[derive(Debug, Clone, Copy)]
 pub enum DNSRecordClass {
-    IN = 1,
-    CS = 2,
-    CH = 3,
-    HS = 4,
+    IN,
+    CS,
+    CH,
+    HS,
+    /// Like [DNSRecordType::Unknown], build these with `From<Int>`.
+    /// Classes compare by number too.
+    Unknown(Int),
 }
 
-impl TryFrom<Int> for DNSRecordClass {
-    type Error = DNSError;
-    fn try_from(value: Int) -> Result<Self, Self::Error> {
+impl PartialEq for DNSRecordClass {
+    fn eq(&self, other: &Self) -> bool {
+        Int::from(*self) == Int::from(*other)
+    }
+}
+
+impl Eq for DNSRecordClass {}
+
+impl std::hash::Hash for DNSRecordClass {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        Int::from(*self).hash(state);
+    }
+}
+
+impl From<Int> for DNSRecordClass {
+    fn from(value: Int) -> Self {
         match value {
-            1 => Ok(Self::IN),
-            2 => Ok(Self::CS),
-            3 => Ok(Self::CH),
-            4 => Ok(Self::HS),
-            _ => Err(DNSError::BadRecordClass(value)),
+            1 => Self::IN,
+            2 => Self::CS,
+            3 => Self::CH,
+            4 => Self::HS,
+            _ => Self::Unknown(value),
+        }
+    }
+}
+
+impl From<DNSRecordClass> for Int {
+    fn from(class: DNSRecordClass) -> Self {
+        match class {
+            DNSRecordClass::IN => 1,
+            DNSRecordClass::CS => 2,
+            DNSRecordClass::CH => 3,
+            DNSRecordClass::HS => 4,
+            DNSRecordClass::Unknown(value) => value,
+        }
+    }
+}
+
+/// The class's mnemonic, or `CLASSnnn` (RFC 3597 section 5).
+impl std::fmt::Display for DNSRecordClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unknown(value) => write!(f, "CLASS{}", value),
+            class => write!(f, "{:?}", class),
         }
     }
 }
 
 #[derive(Error, Debug)]
 pub enum DNSError {
-    #[error("Found a part with more than 255 chars in the url. (part: {part}, domain_name: {domain_name})")]
+    #[error("Label {part} in {domain_name} is longer than the 63 bytes allowed.")]
     DomainNameHasTooLongPart { part: String, domain_name: String },
     #[error(transparent)]
     IOError(#[from] std::io::Error),
     #[error(transparent)]
     InvalidUTF8(#[from] FromUtf8Error),
+    #[error("Domain name takes up {0} bytes, more than the 255 allowed.")]
+    DomainNameTooLong(usize),
+    #[error("Label length {0:#04x} uses a reserved label type.")]
+    BadLabelType(u8),
+    #[error("Name pointer to {pointer} is past the end of the {message_length} byte message.")]
+    NamePointerOutOfBounds { pointer: u16, message_length: u64 },
+    #[error("Name pointer at {position} points forward to {pointer}.")]
+    ForwardNamePointer { pointer: u16, position: u64 },
+    #[error("Name pointer at {position} to {pointer} would loop.")]
+    NamePointerLoop { pointer: u16, position: u64 },
     #[error("Could not recognize header flag: {0}")]
     BadHeader(Int),
     #[error("Could not recognize dns record type: {0}")]
-    BadRecordType(Int),
-    #[error("Could not recognize dns record class: {0}")]
-    BadRecordClass(Int),
+    UnknownMnemonic(String),
     #[error(transparent)]
     IntTooLarge(#[from] TryFromIntError),
     #[error(transparent)]
     BadAddress(#[from] AddrParseError),
+    #[error("RDATA was {expected} bytes long but decoding it used {found}.")]
+    RDataLengthMismatch { expected: Int, found: u64 },
+    #[error("Malformed type bitmap: {0}.")]
+    BadTypeBitmap(&'static str),
+    #[error("Found more than one OPT record in the additional section.")]
+    MultipleOptRecords,
+    #[error("Nameserver couldn't answer: {0:?}")]
+    NameserverFailed(ResponseCode),
+    #[error("{0} is an alias for itself, by way of other aliases.")]
+    CnameLoop(String),
+    #[error("Too many aliases to follow for {0}.")]
+    CnameChainTooLong(String),
+    #[error("Response doesn't match the query: {0}.")]
+    ResponseMismatch(&'static str),
+    #[error("Referral to {zone} from a server for {from} is out of bailiwick.")]
+    OutOfBailiwick { zone: String, from: String },
+    #[error("Couldn't read root hints: {0}")]
+    BadRootHints(String),
+    #[error("DNSSEC validation failed: {0}.")]
+    Bogus(String),
+    #[error("None of the nameservers for {0} gave a usable response.")]
+    NoNameserverResponded(String),
     #[error("Couldn't find an ip address in the answer section.")]
     NoIpAddressFound,
     #[error("ToSocketAddrs produced no addresses when at least one was expected.")]
@@ -119,6 +312,20 @@ pub enum DNSError {
     Other,
 }
 
+impl DNSError {
+    /// Whether this is a read or connect that ran out of time, as
+    /// opposed to one that failed outright.
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Self::IOError(err) => matches!(
+                err.kind(),
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+            ),
+            _ => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DNSQuestion {
     pub name: String,
@@ -136,27 +343,58 @@ impl DNSQuestion {
     }
 }
 
+impl DNSQuestion {
+    /// Like [ToBytes::to_bytes], with names compressed by an [encode::Compressor].
+    pub fn to_bytes_compressed(
+        &self,
+        buf: &mut Vec<u8>,
+        compressor: &mut encode::Compressor,
+    ) -> Result<usize, DNSError> {
+        let dns_name_size = compressor.dns_name(buf, &self.name)?;
+        buf.write_u16::<BigEndian>(Int::from(self.r#type))?;
+        buf.write_u16::<BigEndian>(Int::from(self.class))?;
+
+        Ok(dns_name_size + 4)
+    }
+}
+
 impl ToBytes for DNSQuestion {
     fn to_bytes<W: std::io::Write>(&self, writer: &mut W) -> Result<usize, DNSError> {
         let dns_name_size = encode::dns_name(writer, &self.name)?;
-        writer.write_u16::<BigEndian>(self.r#type as u16)?;
-        writer.write_u16::<BigEndian>(self.class as u16)?;
+        writer.write_u16::<BigEndian>(Int::from(self.r#type))?;
+        writer.write_u16::<BigEndian>(Int::from(self.class))?;
 
         Ok(dns_name_size + 4)
     }
 }
 
+/// How long [DNSQuery::query] and the resolver wait for a nameserver
+/// to respond, unless told otherwise.
+pub const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
 #[derive(Debug, Clone)]
 pub struct DNSQuery {
     pub header: DNSHeader,
     pub question: DNSQuestion,
+    pub edns: Option<Edns>,
+    /// Set by [DNSQuery::with_randomized_case], after which responses
+    /// have to echo the name spelled exactly as it was sent.
+    pub randomized_case: bool,
 }
 
 impl ToBytes for DNSQuery {
     fn to_bytes<W: std::io::Write>(&self, writer: &mut W) -> Result<usize, DNSError> {
+        let header = DNSHeader {
+            num_additionals: self.edns.is_some() as Int,
+            ..self.header
+        };
+
         let mut total_bytes = 0;
-        total_bytes += self.header.to_bytes(writer)?;
+        total_bytes += header.to_bytes(writer)?;
         total_bytes += self.question.to_bytes(writer)?;
+        if let Some(edns) = &self.edns {
+            total_bytes += edns.to_bytes(writer)?;
+        }
 
         Ok(total_bytes)
     }
@@ -168,43 +406,194 @@ impl FromBytes for DNSQuery {
         let header = DNSHeader::from_bytes(reader)?;
         let question = DNSQuestion::from_bytes(reader)?;
 
-        Ok(Self { header, question })
+        // Queries don't normally carry answers or authorities, but
+        // they have to be stepped over to get to the OPT record.
+        for _ in 0..(header.num_answers as usize + header.num_authorities as usize) {
+            DNSRecord::from_bytes(reader)?;
+        }
+        let (_, edns) = decode::additionals(reader, header.num_additionals)?;
+
+        Ok(Self {
+            header,
+            question,
+            edns,
+            randomized_case: false,
+        })
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum DNSHeaderFlag {
-    RecursionDesired,
-    None,
-    Other(Int),
+/// The kind of query carried by a message (RFC 1035 4.1.1, RFC 1996, RFC 2136).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Opcode {
+    #[default]
+    Query,
+    IQuery,
+    Status,
+    Notify,
+    Update,
+    Other(u8),
 }
 
-impl From<DNSHeaderFlag> for Int {
-    fn from(value: DNSHeaderFlag) -> Self {
+impl From<u8> for Opcode {
+    fn from(value: u8) -> Self {
         match value {
-            DNSHeaderFlag::None => 0,
-            DNSHeaderFlag::RecursionDesired => 1 << 8,
-            DNSHeaderFlag::Other(v) => v,
+            0 => Self::Query,
+            1 => Self::IQuery,
+            2 => Self::Status,
+            4 => Self::Notify,
+            5 => Self::Update,
+            _ => Self::Other(value),
         }
     }
 }
 
-impl From<Int> for DNSHeaderFlag {
+impl From<Opcode> for u8 {
+    fn from(value: Opcode) -> Self {
+        match value {
+            Opcode::Query => 0,
+            Opcode::IQuery => 1,
+            Opcode::Status => 2,
+            Opcode::Notify => 4,
+            Opcode::Update => 5,
+            Opcode::Other(v) => v,
+        }
+    }
+}
+
+/// The outcome of a query (RFC 1035 4.1.1, RFC 6895).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResponseCode {
+    #[default]
+    NoError,
+    FormErr,
+    ServFail,
+    NXDomain,
+    NotImp,
+    Refused,
+    Other(Int),
+}
+
+impl From<Int> for ResponseCode {
     fn from(value: Int) -> Self {
         match value {
-            0 => Self::None,
-            256 => Self::RecursionDesired,
+            0 => Self::NoError,
+            1 => Self::FormErr,
+            2 => Self::ServFail,
+            3 => Self::NXDomain,
+            4 => Self::NotImp,
+            5 => Self::Refused,
             _ => Self::Other(value),
         }
     }
 }
 
+impl From<ResponseCode> for Int {
+    fn from(value: ResponseCode) -> Self {
+        match value {
+            ResponseCode::NoError => 0,
+            ResponseCode::FormErr => 1,
+            ResponseCode::ServFail => 2,
+            ResponseCode::NXDomain => 3,
+            ResponseCode::NotImp => 4,
+            ResponseCode::Refused => 5,
+            ResponseCode::Other(v) => v,
+        }
+    }
+}
+
+/// The second 16 bits of the header, broken out into its fields.
+///
+/// ```text
+///   0  1  2  3  4  5  6  7  8  9  10 11 12 13 14 15
+/// +--+-----------+--+--+--+--+--+--+--+-----------+
+/// |QR|  OPCODE   |AA|TC|RD|RA|Z |AD|CD|   RCODE   |
+/// +--+-----------+--+--+--+--+--+--+--+-----------+
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DNSHeaderFlags {
+    /// QR: set on responses, clear on queries.
+    pub response: bool,
+    pub opcode: Opcode,
+    /// AA: the responding server is an authority for the question name.
+    pub authoritative: bool,
+    /// TC: the message was cut short to fit the transport.
+    pub truncated: bool,
+    /// RD: the client wants the server to recurse on its behalf.
+    pub recursion_desired: bool,
+    /// RA: the server is willing to recurse.
+    pub recursion_available: bool,
+    /// Reserved, must be zero but is carried through as-is.
+    pub z: bool,
+    /// AD: all the data in the answer and authority sections was validated.
+    pub authentic_data: bool,
+    /// CD: the client will do its own DNSSEC validation.
+    pub checking_disabled: bool,
+    /// Only the low four bits are carried in the header.
+    pub rcode: ResponseCode,
+}
+
+impl DNSHeaderFlags {
+    pub const NONE: Self = Self {
+        response: false,
+        opcode: Opcode::Query,
+        authoritative: false,
+        truncated: false,
+        recursion_desired: false,
+        recursion_available: false,
+        z: false,
+        authentic_data: false,
+        checking_disabled: false,
+        rcode: ResponseCode::NoError,
+    };
+
+    pub const RECURSION_DESIRED: Self = Self {
+        recursion_desired: true,
+        ..Self::NONE
+    };
+}
+
+impl From<DNSHeaderFlags> for Int {
+    fn from(value: DNSHeaderFlags) -> Self {
+        let opcode = u8::from(value.opcode) as Int & 0b1111;
+        let rcode = Int::from(value.rcode) & 0b1111;
+
+        (value.response as Int) << 15
+            | opcode << 11
+            | (value.authoritative as Int) << 10
+            | (value.truncated as Int) << 9
+            | (value.recursion_desired as Int) << 8
+            | (value.recursion_available as Int) << 7
+            | (value.z as Int) << 6
+            | (value.authentic_data as Int) << 5
+            | (value.checking_disabled as Int) << 4
+            | rcode
+    }
+}
+
+impl From<Int> for DNSHeaderFlags {
+    fn from(value: Int) -> Self {
+        let bit = |n: Int| value & (1 << n) != 0;
+        Self {
+            response: bit(15),
+            opcode: (((value >> 11) & 0b1111) as u8).into(),
+            authoritative: bit(10),
+            truncated: bit(9),
+            recursion_desired: bit(8),
+            recursion_available: bit(7),
+            z: bit(6),
+            authentic_data: bit(5),
+            checking_disabled: bit(4),
+            rcode: (value & 0b1111).into(),
+        }
+    }
+}
+
 impl DNSQuery {
     pub fn new(
         domain_name: &str,
         record_type: DNSRecordType,
         record_class: DNSRecordClass,
-        flags: DNSHeaderFlag,
+        flags: DNSHeaderFlags,
     ) -> Self {
         let question = DNSQuestion::new(domain_name, record_type, record_class);
         let header_id = thread_rng().gen();
@@ -218,10 +607,67 @@ impl DNSQuery {
                 num_answers: 0,
                 num_authorities: 0,
             },
+            edns: None,
+            randomized_case: false,
         }
     }
 
+    /// Flip the case of each letter in the name at random, for the response
+    /// to echo back (draft-vixie-dnsext-dns0x20). Anyone spoofing a response
+    /// then has to guess the casing as well as the ID.
+    pub fn with_randomized_case(mut self) -> Self {
+        let mut rng = thread_rng();
+        self.question.name = self
+            .question
+            .name
+            .chars()
+            .map(|c| match rng.gen::<bool>() {
+                true => c.to_ascii_uppercase(),
+                false => c.to_ascii_lowercase(),
+            })
+            .collect();
+        self.randomized_case = true;
+        self
+    }
+
+    /// Advertise EDNS(0) support, and with it a bigger UDP payload size.
+    pub fn with_edns(mut self, edns: Edns) -> Self {
+        self.edns = Some(edns);
+        self
+    }
+
+    /// The most a response to this query may take up over UDP.
+    pub fn max_response_size(&self) -> usize {
+        match &self.edns {
+            Some(edns) => edns.max_payload_size(),
+            None => MIN_UDP_PAYLOAD_SIZE as usize,
+        }
+    }
+
+    /// Ask over UDP, and again over TCP if the answer didn't fit.
     pub fn query(&self, addr: impl ToSocketAddrs) -> Result<DNSPacket, DNSError> {
+        self.query_with_timeout(addr, DEFAULT_QUERY_TIMEOUT)
+    }
+
+    /// Like [DNSQuery::query] but gives up on each exchange after `timeout`.
+    pub fn query_with_timeout(
+        &self,
+        addr: impl ToSocketAddrs,
+        timeout: Duration,
+    ) -> Result<DNSPacket, DNSError> {
+        let recipient = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or(DNSError::ToSocketAddrsProducedNoAddrs)?;
+
+        NetworkTransport.query(self, recipient, timeout)
+    }
+
+    pub fn query_udp(
+        &self,
+        addr: impl ToSocketAddrs,
+        timeout: Duration,
+    ) -> Result<DNSPacket, DNSError> {
         let mut contents = vec![];
         self.to_bytes(&mut contents)?;
 
@@ -230,19 +676,106 @@ impl DNSQuery {
             .next()
             .ok_or(DNSError::ToSocketAddrsProducedNoAddrs)?;
 
-        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        let local: SocketAddr = match recipient {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
         socket.send_to(&contents, recipient)?;
 
-        let mut recv_buf = [0; 1024];
-        let (size, _) = socket.recv_from(&mut recv_buf)?;
-        let mut cursor = std::io::Cursor::new(&recv_buf[0..size]);
+        // Anyone can send us a datagram, so keep listening until the
+        // real response turns up or time runs out (RFC 5452 9.1).
+        let deadline = Instant::now() + timeout;
+        let mut recv_buf = vec![0; self.max_response_size()];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into());
+            }
+            socket.set_read_timeout(Some(remaining))?;
+            let (size, sender) = socket.recv_from(&mut recv_buf)?;
+            if sender != recipient {
+                debug!(
+                    "Ignoring datagram from {} while waiting on {}",
+                    sender, recipient
+                );
+                continue;
+            }
+            let mut cursor = std::io::Cursor::new(&recv_buf[0..size]);
+            let response = match DNSPacket::from_bytes(&mut cursor) {
+                Ok(response) => response,
+                Err(err) => {
+                    debug!("Ignoring unreadable response from {}: {}", sender, err);
+                    continue;
+                }
+            };
+            match self.check_response(&response) {
+                Ok(()) => return Ok(response),
+                Err(err) => debug!("Ignoring response from {}: {}", sender, err),
+            }
+        }
+    }
+
+    pub fn query_tcp(
+        &self,
+        addr: impl ToSocketAddrs,
+        timeout: Duration,
+    ) -> Result<DNSPacket, DNSError> {
+        let mut contents = vec![];
+        self.to_bytes(&mut contents)?;
+
+        let recipient = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or(DNSError::ToSocketAddrsProducedNoAddrs)?;
+
+        let mut stream = TcpStream::connect_timeout(&recipient, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        transport::write_framed(&mut stream, &contents)?;
+        let response = transport::read_framed(&mut stream)?;
+
+        let response = DNSPacket::from_bytes(&mut std::io::Cursor::new(response))?;
+        self.check_response(&response)?;
+        Ok(response)
+    }
 
-        DNSPacket::from_bytes(&mut cursor)
+    /// Whether `response` really answers this query: it has to carry
+    /// our ID and repeat our question (RFC 5452 4.3). The name only has
+    /// to be spelled exactly as we spelled it if we randomised its case,
+    /// since some servers lowercase the question otherwise.
+    pub fn check_response(&self, response: &DNSPacket) -> Result<(), DNSError> {
+        if !response.header.flags.response {
+            return Err(DNSError::ResponseMismatch("it isn't a response"));
+        }
+        if response.header.id != self.header.id {
+            return Err(DNSError::ResponseMismatch("the ID is different"));
+        }
+        match response.questions.as_slice() {
+            [question]
+                if question.r#type == self.question.r#type
+                    && question.class == self.question.class
+                    && self.echoes_name(&question.name) =>
+            {
+                Ok(())
+            }
+            _ => Err(DNSError::ResponseMismatch("the question is different")),
+        }
+    }
+
+    fn echoes_name(&self, name: &str) -> bool {
+        let (ours, theirs) = (
+            self.question.name.trim_end_matches('.'),
+            name.trim_end_matches('.'),
+        );
+        match self.randomized_case {
+            true => ours == theirs,
+            false => ours.eq_ignore_ascii_case(theirs),
+        }
     }
 
     #[cfg(test)]
     pub fn send_to_8_8_8_8(&self) -> Result<Vec<u8>, DNSError> {
-        use std::net::SocketAddr;
         let mut contents = vec![];
         self.to_bytes(&mut contents)?;
         let socket = UdpSocket::bind("0.0.0.0:0")?;
@@ -264,7 +797,7 @@ pub struct DNSRecord {
     pub r#type: DNSRecordType,
     pub class: DNSRecordClass,
     pub ttl: u32,
-    pub data: Vec<u8>,
+    pub data: RData,
 }
 
 impl ToBytes for DNSRecord {
@@ -272,23 +805,36 @@ impl ToBytes for DNSRecord {
         let mut total = 0;
         total += encode::dns_name(writer, &self.name)?;
 
-        writer.write_u16::<BigEndian>(self.r#type as Int)?;
-        writer.write_u16::<BigEndian>(self.class as Int)?;
+        writer.write_u16::<BigEndian>(Int::from(self.r#type))?;
+        writer.write_u16::<BigEndian>(Int::from(self.class))?;
         writer.write_u32::<BigEndian>(self.ttl)?;
-        writer.write_u16::<BigEndian>(self.data.len().try_into()?)?;
+
+        let mut data = vec![];
+        self.data.to_bytes(&mut data)?;
+        writer.write_u16::<BigEndian>(data.len().try_into()?)?;
 
         total += 10;
-        total += self.data.len();
+        total += data.len();
 
-        writer.write_all(&self.data)?;
+        writer.write_all(&data)?;
 
         Ok(total)
     }
 }
 
+/// The record in zone file presentation format, as in
+/// `example.com. 3600 IN A 93.184.216.34`.
 impl core::fmt::Display for DNSRecord {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        core::fmt::Debug::fmt(&self, f)
+        write!(
+            f,
+            "{} {} {} {} {}",
+            rdata::fqdn(&self.name),
+            self.ttl,
+            self.class,
+            self.r#type,
+            self.data
+        )
     }
 }
 
@@ -305,47 +851,34 @@ impl core::fmt::Debug for DNSRecord {
 }
 
 impl DNSRecord {
-    fn try_parse_aaaa_record(&self) -> Result<Ipv6Addr, DNSError> {
-        let mut cursor = Cursor::new(&self.data);
-        let ipv6 = Ipv6Addr::new(
-            cursor.read_u16::<BigEndian>()?,
-            cursor.read_u16::<BigEndian>()?,
-            cursor.read_u16::<BigEndian>()?,
-            cursor.read_u16::<BigEndian>()?,
-            cursor.read_u16::<BigEndian>()?,
-            cursor.read_u16::<BigEndian>()?,
-            cursor.read_u16::<BigEndian>()?,
-            cursor.read_u16::<BigEndian>()?,
-        );
-        Ok(ipv6)
-    }
+    /// Like [ToBytes::to_bytes], with names compressed by an [encode::Compressor].
+    pub fn to_bytes_compressed(
+        &self,
+        buf: &mut Vec<u8>,
+        compressor: &mut encode::Compressor,
+    ) -> Result<usize, DNSError> {
+        let start = buf.len();
+        compressor.dns_name(buf, &self.name)?;
 
-    fn try_parse_a_record(&self) -> Result<Ipv4Addr, DNSError> {
-        let mut cursor = Cursor::new(&self.data);
-        Ok(Ipv4Addr::new(
-            cursor.read_u8()?,
-            cursor.read_u8()?,
-            cursor.read_u8()?,
-            cursor.read_u8()?,
-        ))
+        buf.write_u16::<BigEndian>(Int::from(self.r#type))?;
+        buf.write_u16::<BigEndian>(Int::from(self.class))?;
+        buf.write_u32::<BigEndian>(self.ttl)?;
+
+        // The length isn't known until the data has been compressed,
+        // so leave room for it and fill it in afterwards.
+        let length_at = buf.len();
+        buf.write_u16::<BigEndian>(0)?;
+        let data_len: Int = self.data.to_bytes_compressed(buf, compressor)?.try_into()?;
+        buf[length_at..length_at + 2].copy_from_slice(&data_len.to_be_bytes());
+
+        Ok(buf.len() - start)
     }
 
+    /// The record's data in presentation format, as it would appear at
+    /// the end of a zone file line. Every kind of data can be shown, so
+    /// this never returns `None`; the `Option` is only kept for callers.
     pub fn try_get_data_as_string(&self) -> Option<String> {
-        match self.r#type {
-            DNSRecordType::A => self
-                .try_parse_a_record()
-                .map(|record| record.to_string())
-                .ok(),
-            DNSRecordType::NS | DNSRecordType::CNAME => {
-                let mut cursor = Cursor::new(&self.data);
-                decode::dns_name(&mut cursor).map(|(name, _)| name).ok()
-            }
-            DNSRecordType::AAAA => self
-                .try_parse_aaaa_record()
-                .map(|record| record.to_string())
-                .ok(),
-            _ => Some(format!("{:?}", self.data)),
-        }
+        Some(self.data.to_string())
     }
 }
 
@@ -357,8 +890,8 @@ impl FromBytes for DNSHeader {
             flags: data.read_u16::<BigEndian>()?.into(),
             num_questions: data.read_u16::<BigEndian>()?,
             num_answers: data.read_u16::<BigEndian>()?,
-            num_additionals: data.read_u16::<BigEndian>()?,
             num_authorities: data.read_u16::<BigEndian>()?,
+            num_additionals: data.read_u16::<BigEndian>()?,
         })
     }
 }
@@ -366,14 +899,14 @@ impl FromBytes for DNSHeader {
 impl FromBytes for DNSQuestion {
     type Error = DNSError;
     fn from_bytes<R: Read + Seek>(reader: &mut R) -> Result<Self, Self::Error> {
-        match decode::dns_name_simple(reader) {
+        match decode::dns_name(reader) {
             Ok((domain_name, _)) => {
                 let r#type = reader.read_u16::<BigEndian>()?;
                 let class = reader.read_u16::<BigEndian>()?;
                 Ok(Self {
                     name: domain_name,
-                    r#type: r#type.try_into()?,
-                    class: class.try_into()?,
+                    r#type: r#type.into(),
+                    class: class.into(),
                 })
             }
             Err(err) => Err(err),
@@ -386,19 +919,18 @@ impl FromBytes for DNSRecord {
     fn from_bytes<R: Read + Seek>(reader: &mut R) -> Result<Self, Self::Error> {
         let (domain_name, _) = decode::dns_name(reader)?;
 
-        let r#type = reader.read_u16::<BigEndian>()?.try_into()?;
-        let class = reader.read_u16::<BigEndian>()?.try_into()?;
+        let r#type = reader.read_u16::<BigEndian>()?.into();
+        let class = reader.read_u16::<BigEndian>()?.into();
         let ttl = reader.read_u32::<BigEndian>()?;
         let data_len = reader.read_u16::<BigEndian>()?;
+        let data = RData::from_bytes(reader, r#type, class, data_len)?;
 
-        let mut buf = vec![0; data_len as usize];
-        reader.read_exact(&mut buf)?;
         Ok(Self {
             name: domain_name,
             r#type,
             class,
             ttl,
-            data: buf,
+            data,
         })
     }
 }
@@ -410,110 +942,285 @@ pub struct DNSPacket {
     pub answers: Vec<DNSRecord>,
     pub authorities: Vec<DNSRecord>,
     pub additionals: Vec<DNSRecord>,
+    /// The OPT pseudo-record, pulled out of the additional section.
+    pub edns: Option<Edns>,
 }
 
 impl ToBytes for DNSPacket {
+    /// Names are compressed, so the whole message is built up in memory
+    /// first and then handed to the writer in one go.
+    ///
+    /// The section counts are taken from the sections themselves rather
+    /// than from `header`.
     fn to_bytes<W: std::io::Write>(&self, writer: &mut W) -> Result<usize, DNSError> {
-        let mut total_bytes_written = 0;
-        total_bytes_written += self.header.to_bytes(writer)?;
+        let mut buf = vec![];
+        let mut compressor = encode::Compressor::default();
+        let header = DNSHeader {
+            num_questions: self.questions.len().try_into()?,
+            num_answers: self.answers.len().try_into()?,
+            num_authorities: self.authorities.len().try_into()?,
+            num_additionals: (self.additionals.len() + self.edns.is_some() as usize).try_into()?,
+            ..self.header
+        };
+        header.to_bytes(&mut buf)?;
 
         for question in self.questions.iter() {
-            total_bytes_written += question.to_bytes(writer)?
+            question.to_bytes_compressed(&mut buf, &mut compressor)?;
         }
         for record in self.answers.iter() {
-            total_bytes_written += record.to_bytes(writer)?
+            record.to_bytes_compressed(&mut buf, &mut compressor)?;
         }
         for record in self.authorities.iter() {
-            total_bytes_written += record.to_bytes(writer)?
+            record.to_bytes_compressed(&mut buf, &mut compressor)?;
         }
         for record in self.additionals.iter() {
-            total_bytes_written += record.to_bytes(writer)?
+            record.to_bytes_compressed(&mut buf, &mut compressor)?;
         }
-        Ok(total_bytes_written)
+        if let Some(edns) = &self.edns {
+            edns.to_bytes(&mut buf)?;
+        }
+
+        writer.write_all(&buf)?;
+        Ok(buf.len())
     }
 }
 
 impl DNSPacket {
-    pub fn ip(&self) -> Option<String> {
-        self.answers.iter().find_map(|answer| {
-            if answer.r#type == DNSRecordType::A {
-                answer.try_parse_a_record().map(|ip| ip.to_string()).ok()
-            } else {
-                None
-            }
-        })
+    /// A response to the query with `header` that carries nothing but
+    /// `rcode`, echoing the query's ID and, if it could be read, its question.
+    pub fn error_response(
+        header: &DNSHeader,
+        question: Option<DNSQuestion>,
+        rcode: ResponseCode,
+    ) -> Self {
+        let questions: Vec<DNSQuestion> = question.into_iter().collect();
+        Self {
+            header: DNSHeader {
+                id: header.id,
+                flags: DNSHeaderFlags {
+                    response: true,
+                    opcode: header.flags.opcode,
+                    recursion_desired: header.flags.recursion_desired,
+                    recursion_available: true,
+                    checking_disabled: header.flags.checking_disabled,
+                    rcode,
+                    ..DNSHeaderFlags::NONE
+                },
+                num_questions: questions.len() as Int,
+                num_answers: 0,
+                num_authorities: 0,
+                num_additionals: 0,
+            },
+            questions,
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+            edns: None,
+        }
+    }
+
+    /// Turn a packet from whichever server answered last into our
+    /// response to `query`. Its flags and OPT record describe that
+    /// exchange rather than ours with the client.
+    pub fn prepare_response(&mut self, query: &DNSQuery) {
+        // The cache matches names case-insensitively, so the
+        // question we saved may not be spelled like this one.
+        self.questions = vec![query.question.clone()];
+        let dnssec_ok = query.edns.as_ref().is_some_and(|edns| edns.dnssec_ok);
+        // The header only has room for the low four bits of the RCODE.
+        // The rest goes in our OPT record, or if the client didn't send
+        // one there's nowhere for it and all we can say is SERVFAIL.
+        let extended_rcode = (Int::from(self.rcode()) >> 4) as u8;
+        self.edns = query.edns.as_ref().map(|_| Edns {
+            extended_rcode,
+            dnssec_ok,
+            ..Edns::new(DEFAULT_EDNS_PAYLOAD_SIZE)
+        });
+        if self.edns.is_none() && extended_rcode != 0 {
+            self.header.flags.rcode = ResponseCode::ServFail;
+        }
+
+        // Clients that didn't ask for DNSSEC records don't get
+        // them unless they asked for one by type (RFC 4035 3.2.1).
+        if !dnssec_ok {
+            let asked_for = query.question.r#type;
+            let wanted = |record: &DNSRecord| {
+                record.r#type == asked_for
+                    || !matches!(
+                        record.r#type,
+                        DNSRecordType::RRSIG | DNSRecordType::NSEC | DNSRecordType::NSEC3
+                    )
+            };
+            self.answers.retain(wanted);
+            self.authorities.retain(wanted);
+        }
+
+        let flags = &mut self.header.flags;
+        flags.response = true;
+        flags.opcode = query.header.flags.opcode;
+        flags.authoritative = false;
+        flags.recursion_desired = query.header.flags.recursion_desired;
+        flags.recursion_available = true;
+        flags.checking_disabled = query.header.flags.checking_disabled;
+        // Only for clients that said they'd understand it (RFC 6840 5.8).
+        flags.authentic_data &= dnssec_ok || query.header.flags.authentic_data;
+    }
+
+    /// The full RCODE, including any upper bits carried in the OPT record.
+    pub fn rcode(&self) -> ResponseCode {
+        match &self.edns {
+            Some(edns) => edns.rcode(self.header.flags.rcode),
+            None => self.header.flags.rcode,
+        }
     }
 
-    pub(crate) fn get_nameserver_from_authorities(&self) -> Option<String> {
+    /// The SOA record from the authority section, which
+    /// negative responses carry (RFC 2308 3).
+    pub fn get_soa(&self) -> Option<(&DNSRecord, &SOAData)> {
         self.authorities
             .iter()
-            .find_map(|answer| match answer.r#type == DNSRecordType::NS {
-                true => {
-                    let mut cursor = Cursor::new(&answer.data);
-                    Some(decode::dns_name(&mut cursor).map(|(name, _)| name).unwrap())
-                }
-                false => None,
+            .find_map(|record| match &record.data {
+                RData::SOA(soa) => Some((record, soa)),
+                _ => None,
             })
     }
 
-    pub(crate) fn get_nameserver_from_additionals(&self) -> Option<String> {
-        self.additionals
-            .iter()
-            .find_map(|answer| match answer.r#type == DNSRecordType::NS {
-                true => {
-                    let mut cursor = Cursor::new(&answer.data);
-                    Some(decode::dns_name(&mut cursor).map(|(name, _)| name).unwrap())
-                }
-                false => None,
-            })
+    /// Whether this is an authoritative "no": either the name doesn't exist
+    /// (NXDOMAIN) or it has no records of the type asked for (NODATA).
+    pub fn is_negative(&self) -> bool {
+        match self.rcode() {
+            ResponseCode::NXDomain => true,
+            ResponseCode::NoError => self.answers.is_empty() && self.get_soa().is_some(),
+            _ => false,
+        }
+    }
+
+    /// How long a negative response may be cached: the lesser of the
+    /// SOA's own TTL and its MINIMUM field (RFC 2308 5). Without an SOA
+    /// it mustn't be cached at all.
+    pub fn negative_ttl(&self) -> Option<u32> {
+        if !self.is_negative() {
+            return None;
+        }
+        self.get_soa()
+            .map(|(record, soa)| record.ttl.min(soa.minimum))
     }
 
-    pub(crate) fn get_nameserver(&self) -> Option<String> {
-        if let Some(result) = self.get_nameserver_from_authorities() {
-            return Some(result);
+    /// Serialise the packet so that it fits in `limit` bytes.
+    ///
+    /// The additional section goes first since it is only a courtesy.
+    /// If that isn't enough, the answer and authority sections are
+    /// dropped too and TC is set so the client knows to retry over TCP.
+    pub fn to_bytes_with_limit(&self, limit: usize) -> Result<Vec<u8>, DNSError> {
+        let mut buf = vec![];
+        self.to_bytes(&mut buf)?;
+        if buf.len() <= limit {
+            return Ok(buf);
+        }
+
+        let mut packet = self.clone();
+        packet.additionals.clear();
+        buf.clear();
+        packet.to_bytes(&mut buf)?;
+        if buf.len() <= limit {
+            return Ok(buf);
         }
-        self.get_nameserver_from_additionals()
+
+        packet.answers.clear();
+        packet.authorities.clear();
+        packet.header.flags.truncated = true;
+        buf.clear();
+        packet.to_bytes(&mut buf)?;
+        Ok(buf)
     }
 
-    pub(crate) fn get_cname_record(&self) -> Option<String> {
+    /// The first address in [DNSPacket::ips].
+    pub fn ip(&self) -> Option<String> {
+        self.get_answer().map(|ip| ip.to_string())
+    }
+
+    /// Every answer of the type that was asked for, leaving out any
+    /// CNAMEs that led to them.
+    pub fn get_answers(&self) -> Vec<&DNSRecord> {
+        let asked_for = self.questions.first().map(|question| question.r#type);
         self.answers
             .iter()
-            .find_map(|answer| match answer.r#type == DNSRecordType::CNAME {
-                true => {
-                    let mut cursor = Cursor::new(&answer.data);
-                    Some(decode::dns_name(&mut cursor).map(|(name, _)| name).unwrap())
-                }
-                false => None,
+            .filter(|record| asked_for.is_none_or(|r#type| record.r#type == r#type))
+            .collect()
+    }
+
+    /// The addresses among [DNSPacket::get_answers], so
+    /// the IPv6 ones when AAAA records were asked for.
+    pub fn ips(&self) -> Vec<IpAddr> {
+        self.get_answers()
+            .into_iter()
+            .filter_map(|record| match record.data {
+                RData::A(ip) => Some(IpAddr::V4(ip)),
+                RData::AAAA(ip) => Some(IpAddr::V6(ip)),
+                _ => None,
             })
+            .collect()
     }
 
-    pub fn get_nameserver_ip(&self) -> Option<IpAddr> {
+    /// The answers of type `record_type` owned by `name`.
+    pub fn answers_for<'a>(
+        &'a self,
+        name: &'a str,
+        record_type: DNSRecordType,
+    ) -> impl Iterator<Item = &'a DNSRecord> {
+        self.answers.iter().filter(move |record| {
+            record.r#type == record_type
+                && record
+                    .name
+                    .trim_end_matches('.')
+                    .eq_ignore_ascii_case(name.trim_end_matches('.'))
+        })
+    }
+
+    /// The names of the nameservers a referral points at.
+    pub fn get_nameservers(&self) -> Vec<String> {
+        self.authorities
+            .iter()
+            .filter_map(|record| match &record.data {
+                RData::NS(name) => Some(name.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The IPv4 and IPv6 addresses the additional section gives for `nameserver`.
+    pub fn get_glue(&self, nameserver: &str) -> Vec<IpAddr> {
         self.additionals
             .iter()
-            .find_map(|answer| match answer.r#type == DNSRecordType::A {
-                true => {
-                    if let Ok(a_record) = answer.try_parse_a_record() {
-                        return Some(IpAddr::V4(a_record));
-                    }
-                    answer.try_parse_aaaa_record().map(IpAddr::V6).ok()
-                }
-                false => None,
+            .filter(|record| record.name.eq_ignore_ascii_case(nameserver))
+            .filter_map(|record| match record.data {
+                RData::A(ip) => Some(IpAddr::V4(ip)),
+                RData::AAAA(ip) => Some(IpAddr::V6(ip)),
+                _ => None,
             })
+            .collect()
     }
 
-    pub fn get_answer(&self) -> Option<IpAddr> {
-        self.answers
+    pub fn get_cname_record(&self) -> Option<String> {
+        self.answers.iter().find_map(|answer| match &answer.data {
+            RData::CNAME(name) => Some(name.clone()),
+            _ => None,
+        })
+    }
+
+    pub fn get_nameserver_ip(&self) -> Option<IpAddr> {
+        self.additionals
             .iter()
-            .find_map(|answer| match answer.r#type == DNSRecordType::A {
-                true => {
-                    if let Ok(a_record) = answer.try_parse_a_record() {
-                        return Some(IpAddr::V4(a_record));
-                    }
-                    answer.try_parse_aaaa_record().map(IpAddr::V6).ok()
-                }
-                false => None,
+            .find_map(|answer| match answer.data {
+                RData::A(ip) => Some(IpAddr::V4(ip)),
+                RData::AAAA(ip) => Some(IpAddr::V6(ip)),
+                _ => None,
             })
     }
+
+    pub fn get_answer(&self) -> Option<IpAddr> {
+        self.ips().into_iter().next()
+    }
 }
 
 impl FromBytes for DNSPacket {
@@ -533,9 +1240,7 @@ impl FromBytes for DNSPacket {
             .map(|_| DNSRecord::from_bytes(data))
             .collect::<Result<Vec<DNSRecord>, DNSError>>()?;
 
-        let additionals = (0..header.num_additionals)
-            .map(|_| DNSRecord::from_bytes(data))
-            .collect::<Result<Vec<DNSRecord>, DNSError>>()?;
+        let (additionals, edns) = decode::additionals(data, header.num_additionals)?;
 
         Ok(Self {
             header,
@@ -543,6 +1248,7 @@ impl FromBytes for DNSPacket {
             additionals,
             authorities,
             questions,
+            edns,
         })
     }
 }
@@ -553,7 +1259,7 @@ pub fn lookup_domain(domain_name: &str) -> Result<String, DNSError> {
         domain_name,
         DNSRecordType::A,
         DNSRecordClass::IN,
-        DNSHeaderFlag::RecursionDesired,
+        DNSHeaderFlags::RECURSION_DESIRED,
     );
     let response = query.send_to_8_8_8_8()?;
     let mut reader = std::io::Cursor::new(response);
@@ -562,8 +1268,90 @@ pub fn lookup_domain(domain_name: &str) -> Result<String, DNSError> {
 }
 
 pub mod encode {
+    use super::decode::MAX_NAME_LENGTH;
     use super::DNSError;
-    use structure::byteorder::WriteBytesExt;
+    use std::collections::HashMap;
+    use structure::byteorder::{BigEndian, WriteBytesExt};
+
+    /// Pointers only have 14 bits for the offset.
+    const MAX_POINTER_OFFSET: usize = 0x3fff;
+
+    /// The most a single label may hold, since the top two bits
+    /// of its length octet mark pointers (RFC 1035 2.3.4).
+    pub const MAX_LABEL_LENGTH: usize = 63;
+
+    /// Split `domain_name` into its labels, making sure
+    /// they and the name as a whole fit on the wire.
+    ///
+    /// The root is written as a lone zero byte and a trailing
+    /// dot (as in "example.com.") adds nothing.
+    fn labels(domain_name: &str) -> Result<Vec<&str>, DNSError> {
+        let parts: Vec<&str> = domain_name
+            .split('.')
+            .filter(|part| !part.is_empty())
+            .collect();
+
+        let mut name_length = 1;
+        for part in &parts {
+            if part.len() > MAX_LABEL_LENGTH {
+                return Err(DNSError::DomainNameHasTooLongPart {
+                    part: part.to_string(),
+                    domain_name: domain_name.to_string(),
+                });
+            }
+            name_length += 1 + part.len();
+        }
+        if name_length > MAX_NAME_LENGTH {
+            return Err(DNSError::DomainNameTooLong(name_length));
+        }
+        Ok(parts)
+    }
+
+    /// Remembers where every name (and each of its suffixes) was written
+    /// in a message, so that later names can point back at them instead
+    /// of being written out again (RFC 1035 4.1.4).
+    ///
+    /// Everything written with one goes into the same buffer, which holds
+    /// the message from its very first byte and is only ever appended to.
+    #[derive(Debug, Default)]
+    pub struct Compressor {
+        offsets: HashMap<String, u16>,
+    }
+
+    impl Compressor {
+        /// Append `domain_name` to `buf`, replacing the longest suffix
+        /// that was already written with a pointer to it. Suffixes only
+        /// match when spelled the same, so that every name keeps its case.
+        ///
+        /// `buf` must hold the message from its very first byte since
+        /// pointers are offsets from the start of the message.
+        pub fn dns_name(
+            &mut self,
+            buf: &mut Vec<u8>,
+            domain_name: &str,
+        ) -> Result<usize, DNSError> {
+            let start = buf.len();
+            let parts = labels(domain_name)?;
+
+            for index in 0..parts.len() {
+                let suffix = parts[index..].join(".");
+                if let Some(&offset) = self.offsets.get(&suffix) {
+                    buf.write_u16::<BigEndian>(0b1100_0000_0000_0000 | offset)?;
+                    return Ok(buf.len() - start);
+                }
+                if buf.len() <= MAX_POINTER_OFFSET {
+                    self.offsets.insert(suffix, buf.len() as u16);
+                }
+
+                let part_as_bytes = parts[index].as_bytes();
+                buf.write_u8(part_as_bytes.len() as u8)?;
+                buf.extend_from_slice(part_as_bytes);
+            }
+            buf.write_u8(0)?;
+
+            Ok(buf.len() - start)
+        }
+    }
 
     /// Given a domain name encode it into bytes.
     pub fn dns_name<W: std::io::Write>(
@@ -572,55 +1360,123 @@ pub mod encode {
     ) -> Result<usize, DNSError> {
         let mut total_bytes_written = 0;
 
-        for part in domain_name.split('.') {
+        for part in labels(domain_name)? {
             let part_as_bytes = part.as_bytes();
-            let len: u8 = part_as_bytes.len().try_into()?;
 
-            writer.write_u8(len)?;
+            writer.write_u8(part_as_bytes.len() as u8)?;
             total_bytes_written += 1;
 
             writer.write_all(part_as_bytes)?;
             total_bytes_written += part_as_bytes.len();
         }
         writer.write_u8(0)?;
+        total_bytes_written += 1;
 
         Ok(total_bytes_written)
     }
 }
 
 pub mod decode {
-    use super::DNSError;
+    use super::{DNSError, DNSRecord, DNSRecordType, FromBytes, Int};
+    use crate::edns::Edns;
     use std::io::{Read, Seek, SeekFrom};
-    use structure::byteorder::ReadBytesExt;
+    use structure::byteorder::{BigEndian, ReadBytesExt};
+
+    /// The most a name may take up on the wire, counting every
+    /// length octet and the final zero (RFC 1035 2.3.4).
+    pub const MAX_NAME_LENGTH: usize = 255;
+
+    /// Only reachable if the pointer checks below are wrong, but cheap
+    /// enough to keep as a last line of defence.
+    const MAX_POINTER_HOPS: usize = 128;
+
+    /// Read a label's bytes and account for them against [MAX_NAME_LENGTH].
+    ///
+    /// A length over 63 always has one of the top two bits set, so it
+    /// is turned away as a reserved label type rather than read as a
+    /// long label.
+    fn label<R: Read>(
+        reader: &mut R,
+        length: u8,
+        name_length: &mut usize,
+    ) -> Result<String, DNSError> {
+        if length & 0b1100_0000 != 0 {
+            return Err(DNSError::BadLabelType(length));
+        }
+        *name_length += 1 + length as usize;
+        if *name_length > MAX_NAME_LENGTH {
+            return Err(DNSError::DomainNameTooLong(*name_length));
+        }
+
+        let mut buf = vec![0; length as usize];
+        reader.read_exact(&mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+
+    /// Read `count` records from the additional section, setting aside
+    /// the OPT pseudo-record since it can't be read as a [DNSRecord].
+    pub fn additionals<R: Read + Seek>(
+        reader: &mut R,
+        count: Int,
+    ) -> Result<(Vec<DNSRecord>, Option<Edns>), DNSError> {
+        let mut records = vec![];
+        let mut edns = None;
+
+        for _ in 0..count {
+            let start = reader.stream_position()?;
+            dns_name(reader)?;
+            if reader.read_u16::<BigEndian>()? != Int::from(DNSRecordType::OPT) {
+                reader.seek(SeekFrom::Start(start))?;
+                records.push(DNSRecord::from_bytes(reader)?);
+                continue;
+            }
+            // There may be at most one (RFC 6891 6.1.1).
+            if edns.is_some() {
+                return Err(DNSError::MultipleOptRecords);
+            }
+            edns = Some(Edns::from_bytes_after_type(reader)?);
+        }
+        Ok((records, edns))
+    }
 
+    /// Decode a name that is known to be written out in full.
     pub fn dns_name_simple<R: Read>(reader: &mut R) -> Result<(String, usize), DNSError> {
         let mut parts = vec![];
-        let mut total_bytes_read: usize = 0;
+        let mut name_length = 1;
 
         loop {
             let length = reader.read_u8()?;
-            total_bytes_read += 1;
-
             if length == 0 {
                 break;
             }
-            let mut buf = vec![0; length as usize];
-            reader.read_exact(&mut buf)?;
-            total_bytes_read += length as usize;
-
-            let part = String::from_utf8(buf)?;
-            parts.push(part);
+            parts.push(label(reader, length, &mut name_length)?);
         }
-        Ok((parts.join("."), total_bytes_read))
+        Ok((parts.join("."), name_length))
     }
 
+    /// Decode a possibly compressed name, returning it along with the
+    /// number of bytes it took up at the reader's position.
+    ///
+    /// Every pointer has to land inside the message and strictly before
+    /// the stretch of labels it was reached from. Names are only ever
+    /// compressed against earlier names, so anything else is either a
+    /// forward reference or a loop and is rejected.
     pub fn dns_name<R: Read + Seek>(reader: &mut R) -> Result<(String, usize), DNSError> {
+        let start = reader.stream_position()?;
+        let message_length = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(start))?;
+
         let mut parts = vec![];
-        let mut total_bytes_read: usize = 0;
+        let mut name_length = 1;
+        // Where the labels we're currently reading began.
+        let mut segment_start = start;
+        // Where to leave the reader once we've followed a pointer.
+        let mut resume_at = None;
+        let mut hops = 0;
 
         loop {
+            let position = reader.stream_position()?;
             let length = reader.read_u8()?;
-            total_bytes_read += 1;
 
             if length == 0 {
                 break;
@@ -628,35 +1484,35 @@ pub mod decode {
 
             // Both of the two first bits set implies
             // we need to decompress the value.
-            match length & 0b1100_0000 > 0 {
-                true => {
-                    let top_half = length & 0b0011_1111;
-                    let (part, bytes_read) = dns_name_compressed(reader, top_half)?;
-                    total_bytes_read += bytes_read;
-                    parts.push(part);
-                    break;
-                }
-                false => {
-                    let mut buf = vec![0; length as usize];
-                    reader.read_exact(&mut buf)?;
-                    total_bytes_read += length as usize;
-                    parts.push(String::from_utf8(buf)?);
-                }
+            if length & 0b1100_0000 != 0b1100_0000 {
+                parts.push(label(reader, length, &mut name_length)?);
+                continue;
+            }
+
+            let pointer = ((length as u16 & 0b0011_1111) << 8) | reader.read_u8()? as u16;
+            if pointer as u64 >= message_length {
+                return Err(DNSError::NamePointerOutOfBounds {
+                    pointer,
+                    message_length,
+                });
+            }
+            if pointer as u64 > position {
+                return Err(DNSError::ForwardNamePointer { pointer, position });
             }
+            hops += 1;
+            if pointer as u64 >= segment_start || hops > MAX_POINTER_HOPS {
+                return Err(DNSError::NamePointerLoop { pointer, position });
+            }
+
+            resume_at.get_or_insert(position + 2);
+            segment_start = pointer as u64;
+            reader.seek(SeekFrom::Start(pointer as u64))?;
         }
-        Ok((parts.join("."), total_bytes_read))
-    }
 
-    pub fn dns_name_compressed<R: Read + Seek>(
-        reader: &mut R,
-        top_half: u8,
-    ) -> Result<(String, usize), DNSError> {
-        let bottom_half = reader.read_u8()? as u16;
-        let pointer: u16 = ((top_half as u16) << 8) | bottom_half;
-        let current_position = reader.stream_position()?;
-        reader.seek(SeekFrom::Start(pointer as u64))?;
-        let (result, bytes_read) = dns_name(reader)?;
-        reader.seek(SeekFrom::Start(current_position))?;
-        Ok((result, bytes_read))
+        let end = match resume_at {
+            Some(resume_at) => reader.seek(SeekFrom::Start(resume_at))?,
+            None => reader.stream_position()?,
+        };
+        Ok((parts.join("."), (end - start) as usize))
     }
 }
@@ -0,0 +1,567 @@
+use std::cmp::Ordering;
+
+use data_encoding::{BASE32HEX_NOPAD, HEXUPPER};
+use ring::{digest, signature};
+
+use crate::dns::{encode, DNSError, DNSRecord, DNSRecordType, Int, ToBytes};
+use crate::rdata::{DNSKEYData, DSData, NSEC3Data, NSECData, RData, RRSIGData};
+use crate::resolver::is_subdomain;
+
+/// Compare two names in canonical DNS name order (RFC 4034 6.1).
+///
+/// Names are compared label by label starting from the root, with each
+/// label compared as lowercase bytes, so `example.com` sorts before
+/// `a.example.com`, which sorts before `b.example.com`.
+pub fn canonical_name_cmp(a: &str, b: &str) -> Ordering {
+    let labels = |name: &str| {
+        name.split('.')
+            .filter(|label| !label.is_empty())
+            .rev()
+            .map(|label| label.to_ascii_lowercase())
+            .collect::<Vec<_>>()
+    };
+    labels(a).cmp(&labels(b))
+}
+
+impl DNSRecord {
+    /// The record in canonical form (RFC 4034 6.2), with its owner and
+    /// the names inside its data lowercased.
+    ///
+    /// Names are never compressed by [ToBytes::to_bytes], so writing the
+    /// result out gives the canonical wire form.
+    pub fn to_canonical(&self) -> DNSRecord {
+        let mut record = self.clone();
+        record.name = record.name.trim_end_matches('.').to_ascii_lowercase();
+        for name in record.data.names_mut() {
+            *name = name.to_ascii_lowercase();
+        }
+        record
+    }
+
+    /// The canonical wire form of the record's data.
+    pub fn canonical_rdata(&self) -> Result<Vec<u8>, DNSError> {
+        let mut data = vec![];
+        self.to_canonical().data.to_bytes(&mut data)?;
+        Ok(data)
+    }
+}
+
+/// Put an RRset in canonical form and order (RFC 4034 6.3), sorted by
+/// the canonical wire form of each record's data, with duplicates removed.
+pub fn canonical_rrset(records: &[DNSRecord]) -> Result<Vec<DNSRecord>, DNSError> {
+    let mut keyed = records
+        .iter()
+        .map(|record| Ok((record.canonical_rdata()?, record.to_canonical())))
+        .collect::<Result<Vec<_>, DNSError>>()?;
+    keyed.sort_by(|(a, _), (b, _)| a.cmp(b));
+    keyed.dedup_by(|(a, _), (b, _)| a == b);
+    Ok(keyed.into_iter().map(|(_, record)| record).collect())
+}
+
+/// The DNSKEY flag for keys that sign zone data (RFC 4034 2.1.1).
+pub const ZONE_KEY_FLAG: u16 = 0x0100;
+
+/// The DNSKEY flag for keys that have been revoked (RFC 5011 3).
+pub const REVOKE_FLAG: u16 = 0x0080;
+
+/// The NSEC3 flag for spans that may hide unsigned delegations (RFC 5155 3.1.2.1).
+pub const OPT_OUT_FLAG: u8 = 0x01;
+
+/// The most extra NSEC3 hash iterations a proof may ask for before
+/// we refuse to believe it (RFC 9276 3.2).
+pub const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+/// The DS records for the root zone's key signing keys, KSK-2017 and
+/// KSK-2024, as published by IANA in `root-anchors.xml`.
+pub fn root_trust_anchors() -> Vec<DSData> {
+    [
+        (
+            20326,
+            "E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D",
+        ),
+        (
+            38696,
+            "683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16",
+        ),
+    ]
+    .into_iter()
+    .map(|(key_tag, digest)| DSData {
+        key_tag,
+        algorithm: 8,
+        digest_type: 2,
+        digest: HEXUPPER.decode(digest.as_bytes()).expect("digest is hex"),
+    })
+    .collect()
+}
+
+/// Whether we can check signatures made with `algorithm`: the RSA,
+/// ECDSA and Ed25519 ones that RFC 8624 3.1 says validators should.
+pub fn is_supported_algorithm(algorithm: u8) -> bool {
+    matches!(algorithm, 5 | 7 | 8 | 10 | 13 | 14 | 15)
+}
+
+fn ds_digest_algorithm(digest_type: u8) -> Option<&'static digest::Algorithm> {
+    match digest_type {
+        1 => Some(&digest::SHA1_FOR_LEGACY_USE_ONLY),
+        2 => Some(&digest::SHA256),
+        4 => Some(&digest::SHA384),
+        _ => None,
+    }
+}
+
+/// Whether a DS record's digest is one we can compute (RFC 8624 3.3).
+pub fn is_supported_digest(digest_type: u8) -> bool {
+    ds_digest_algorithm(digest_type).is_some()
+}
+
+fn bogus(reason: impl Into<String>) -> DNSError {
+    DNSError::Bogus(reason.into())
+}
+
+fn labels(name: &str) -> Vec<&str> {
+    name.split('.').filter(|label| !label.is_empty()).collect()
+}
+
+/// The deepest name that both `a` and `b` are at or below.
+fn common_ancestor(a: &str, b: &str) -> String {
+    let (a, b) = (labels(a), labels(b));
+    let shared = a
+        .iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(a, b)| a.eq_ignore_ascii_case(b))
+        .count();
+    a[a.len() - shared..].join(".")
+}
+
+impl DNSKEYData {
+    fn rdata(&self) -> Vec<u8> {
+        let mut data = self.flags.to_be_bytes().to_vec();
+        data.extend([self.protocol, self.algorithm]);
+        data.extend(&self.public_key);
+        data
+    }
+
+    /// The tag that RRSIG and DS records use to refer to
+    /// this key (RFC 4034 Appendix B).
+    pub fn key_tag(&self) -> u16 {
+        let mut total: u32 = 0;
+        for (index, byte) in self.rdata().into_iter().enumerate() {
+            total += match index % 2 {
+                0 => (byte as u32) << 8,
+                _ => byte as u32,
+            };
+        }
+        total += total >> 16;
+        total as u16
+    }
+
+    /// Whether the key may be used to check signatures over zone data.
+    pub fn is_zone_key(&self) -> bool {
+        self.flags & ZONE_KEY_FLAG != 0 && self.flags & REVOKE_FLAG == 0 && self.protocol == 3
+    }
+}
+
+impl DSData {
+    /// Whether this is a digest of `key`, a DNSKEY owned by `owner` (RFC 4034 5.1.4).
+    pub fn matches(&self, owner: &str, key: &DNSKEYData) -> bool {
+        if self.key_tag != key.key_tag() || self.algorithm != key.algorithm {
+            return false;
+        }
+        let Some(algorithm) = ds_digest_algorithm(self.digest_type) else {
+            return false;
+        };
+        let mut data = vec![];
+        if encode::dns_name(&mut data, &owner.to_ascii_lowercase()).is_err() {
+            return false;
+        }
+        data.extend(key.rdata());
+        digest::digest(algorithm, &data).as_ref() == self.digest
+    }
+}
+
+/// Check that `sig` is a good signature by `key` over `rrset` at `now`,
+/// in seconds since the epoch (RFC 4035 5.3).
+///
+/// The records must all have the same owner, type and class. A record
+/// that was made up from a wildcard is checked against the wildcard it
+/// came from, going by the label count in the signature.
+pub fn verify_rrsig(
+    rrset: &[DNSRecord],
+    sig: &RRSIGData,
+    key: &DNSKEYData,
+    now: u32,
+) -> Result<(), DNSError> {
+    let Some(first) = rrset.first() else {
+        return Err(bogus("nothing to check the signature against"));
+    };
+    let owner = labels(&first.name);
+    if sig.type_covered != Int::from(first.r#type) {
+        return Err(bogus(format!(
+            "signature over {} is for {}",
+            DNSRecordType::mnemonic(Int::from(first.r#type)),
+            DNSRecordType::mnemonic(sig.type_covered)
+        )));
+    }
+    if sig.labels as usize > owner.len() {
+        return Err(bogus(format!(
+            "signature over {} counts too many labels",
+            first.name
+        )));
+    }
+    if !is_subdomain(&first.name, &sig.signer_name) {
+        return Err(bogus(format!(
+            "{} can't sign for {}",
+            sig.signer_name, first.name
+        )));
+    }
+    if key.algorithm != sig.algorithm || key.key_tag() != sig.key_tag || !key.is_zone_key() {
+        return Err(bogus(format!(
+            "key {} didn't make the signature",
+            key.key_tag()
+        )));
+    }
+    // Serial number arithmetic, since the times wrap around in 2106 (RFC 4034 3.1.5).
+    if (now.wrapping_sub(sig.inception) as i32) < 0 {
+        return Err(bogus(format!(
+            "signature over {} isn't valid yet",
+            first.name
+        )));
+    }
+    if (sig.expiration.wrapping_sub(now) as i32) < 0 {
+        return Err(bogus(format!("signature over {} has expired", first.name)));
+    }
+
+    let signed_owner = match (sig.labels as usize) < owner.len() {
+        true => format!("*.{}", owner[owner.len() - sig.labels as usize..].join(".")),
+        false => first.name.clone(),
+    }
+    .to_ascii_lowercase();
+
+    let mut data = vec![];
+    RData::RRSIG(RRSIGData {
+        signer_name: sig.signer_name.to_ascii_lowercase(),
+        signature: vec![],
+        ..sig.clone()
+    })
+    .to_bytes(&mut data)?;
+    for record in canonical_rrset(rrset)? {
+        DNSRecord {
+            name: signed_owner.clone(),
+            ttl: sig.original_ttl,
+            ..record
+        }
+        .to_bytes(&mut data)?;
+    }
+    verify_signature(key, &data, &sig.signature)
+        .map_err(|_| bogus(format!("signature over {} doesn't match", first.name)))
+}
+
+fn verify_signature(
+    key: &DNSKEYData,
+    message: &[u8],
+    sig: &[u8],
+) -> Result<(), ring::error::Unspecified> {
+    let public_key = key.public_key.as_slice();
+    match key.algorithm {
+        5 | 7 | 8 | 10 => {
+            let parameters = match key.algorithm {
+                8 => &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+                10 => &signature::RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY,
+                _ => &signature::RSA_PKCS1_1024_8192_SHA1_FOR_LEGACY_USE_ONLY,
+            };
+            // The exponent's length comes first, in one byte or
+            // in three starting with a zero (RFC 3110 2).
+            let (length, rest) = match public_key {
+                [0, high, low, rest @ ..] => (u16::from_be_bytes([*high, *low]) as usize, rest),
+                [length, rest @ ..] => (*length as usize, rest),
+                [] => return Err(ring::error::Unspecified),
+            };
+            if rest.len() <= length {
+                return Err(ring::error::Unspecified);
+            }
+            let (e, n) = rest.split_at(length);
+            let n = &n[n.iter().take_while(|byte| **byte == 0).count()..];
+            signature::RsaPublicKeyComponents { n, e }.verify(parameters, message, sig)
+        }
+        13 | 14 => {
+            let parameters = match key.algorithm {
+                13 => &signature::ECDSA_P256_SHA256_FIXED,
+                _ => &signature::ECDSA_P384_SHA384_FIXED,
+            };
+            // DNSKEY records leave off the byte that marks an uncompressed point.
+            let mut point = vec![0x04];
+            point.extend(public_key);
+            signature::UnparsedPublicKey::new(parameters, point).verify(message, sig)
+        }
+        15 => {
+            signature::UnparsedPublicKey::new(&signature::ED25519, public_key).verify(message, sig)
+        }
+        _ => Err(ring::error::Unspecified),
+    }
+}
+
+/// The hash of `name` that NSEC3 records are owned by (RFC 5155 5).
+pub fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> Result<Vec<u8>, DNSError> {
+    let mut data = vec![];
+    encode::dns_name(&mut data, &name.to_ascii_lowercase())?;
+    let mut hash = data;
+    for _ in 0..=iterations {
+        hash.extend(salt);
+        hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &hash)
+            .as_ref()
+            .to_vec();
+    }
+    Ok(hash)
+}
+
+fn has_type(types: &[Int], r#type: DNSRecordType) -> bool {
+    types.contains(&Int::from(r#type))
+}
+
+/// Whether records with these types are the parent's side of a zone cut,
+/// below which the zone they're in knows nothing.
+fn is_delegation(types: &[Int]) -> bool {
+    has_type(types, DNSRecordType::NS) && !has_type(types, DNSRecordType::SOA)
+}
+
+/// What a proof that a name has no DS records says about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoDs {
+    /// The name isn't a zone cut, so it's in the same zone as the proof.
+    NotDelegated,
+    /// The name is delegated to a zone that isn't signed.
+    InsecureDelegation,
+}
+
+/// The NSEC and NSEC3 records from an authority section, which between
+/// them prove that names or records don't exist (RFC 4035 5.4, RFC 5155 8).
+///
+/// Their signatures need to have been checked already.
+#[derive(Debug, Default)]
+pub struct DenialOfExistence {
+    nsec: Vec<(String, NSECData)>,
+    nsec3: Vec<(String, NSEC3Data)>,
+}
+
+impl DenialOfExistence {
+    pub fn new(records: &[DNSRecord]) -> Self {
+        let mut denial = Self::default();
+        for record in records {
+            match &record.data {
+                RData::NSEC(nsec) => denial.nsec.push((record.name.clone(), nsec.clone())),
+                RData::NSEC3(nsec3) => denial.nsec3.push((record.name.clone(), nsec3.clone())),
+                _ => {}
+            }
+        }
+        denial
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nsec.is_empty() && self.nsec3.is_empty()
+    }
+
+    /// `name` doesn't exist, and nor does a wildcard that would have
+    /// stood in for it (NXDOMAIN).
+    pub fn proves_name_error(&self, name: &str) -> Result<(), DNSError> {
+        if let Some((owner, nsec)) = self.nsec_covering(name) {
+            let closest_encloser = [owner.as_str(), &nsec.next_domain_name]
+                .iter()
+                .map(|other| common_ancestor(name, other))
+                .max_by_key(|ancestor| labels(ancestor).len())
+                .unwrap_or_default();
+            return match self.nsec_covering(&wildcard(&closest_encloser)) {
+                Some(_) => Ok(()),
+                None => Err(bogus(format!("no proof there's no wildcard for {}", name))),
+            };
+        }
+        let (closest_encloser, _) = self.closest_encloser(name)?;
+        match self.nsec3_covering(&wildcard(&closest_encloser)) {
+            Some(_) => Ok(()),
+            None => Err(bogus(format!("no proof there's no wildcard for {}", name))),
+        }
+    }
+
+    /// `name` exists but has no records of `type`, or of CNAME (NODATA).
+    pub fn proves_no_data(&self, name: &str, r#type: DNSRecordType) -> Result<(), DNSError> {
+        let lacks = |types: &[Int]| {
+            !has_type(types, r#type)
+                && !has_type(types, DNSRecordType::CNAME)
+                && (r#type == DNSRecordType::DS || !is_delegation(types))
+        };
+
+        if let Some((_, nsec)) = self.nsec_matching(name) {
+            return match lacks(&nsec.types) {
+                true => Ok(()),
+                false => Err(bogus(format!("NSEC says {} has {:?}", name, r#type))),
+            };
+        }
+        if let Some((_, nsec)) = self.nsec_covering(name) {
+            // An empty non-terminal, which only has names below it.
+            if is_subdomain(&nsec.next_domain_name, name) {
+                return Ok(());
+            }
+            let closest_encloser = common_ancestor(name, &nsec.next_domain_name);
+            return match self.nsec_matching(&wildcard(&closest_encloser)) {
+                Some((_, wildcard)) if lacks(&wildcard.types) => Ok(()),
+                _ => Err(bogus(format!("no proof {} has no {:?}", name, r#type))),
+            };
+        }
+
+        if let Some((_, nsec3)) = self.nsec3_matching(name) {
+            return match lacks(&nsec3.types) {
+                true => Ok(()),
+                false => Err(bogus(format!("NSEC3 says {} has {:?}", name, r#type))),
+            };
+        }
+        let (closest_encloser, next_closer) = self.closest_encloser(name)?;
+        // An unsigned delegation in an opt-out span (RFC 5155 8.6).
+        if r#type == DNSRecordType::DS && next_closer.flags & OPT_OUT_FLAG != 0 {
+            return Ok(());
+        }
+        match self.nsec3_matching(&wildcard(&closest_encloser)) {
+            Some((_, wildcard)) if lacks(&wildcard.types) => Ok(()),
+            _ => Err(bogus(format!("no proof {} has no {:?}", name, r#type))),
+        }
+    }
+
+    /// There is no DS for `name`, and whether that's because
+    /// it's an unsigned delegation or not a delegation at all.
+    pub fn proves_no_ds(&self, name: &str) -> Result<NoDs, DNSError> {
+        let at_name = self
+            .nsec_matching(name)
+            .map(|(_, nsec)| &nsec.types)
+            .or_else(|| self.nsec3_matching(name).map(|(_, nsec3)| &nsec3.types));
+        if let Some(types) = at_name {
+            if has_type(types, DNSRecordType::DS) {
+                return Err(bogus(format!("{} has a DS after all", name)));
+            }
+            // From the child's apex rather than the parent's side of the cut.
+            if has_type(types, DNSRecordType::SOA) && !name.trim_end_matches('.').is_empty() {
+                return Err(bogus(format!("proof of no DS for {} is from below", name)));
+            }
+            return match is_delegation(types) {
+                true => Ok(NoDs::InsecureDelegation),
+                false => Ok(NoDs::NotDelegated),
+            };
+        }
+        if self.nsec_covering(name).is_some() {
+            return Ok(NoDs::NotDelegated);
+        }
+        let (_, next_closer) = self.closest_encloser(name)?;
+        match next_closer.flags & OPT_OUT_FLAG != 0 {
+            true => Ok(NoDs::InsecureDelegation),
+            false => Ok(NoDs::NotDelegated),
+        }
+    }
+
+    /// `name`, which was answered from a wildcard whose signature counted
+    /// `labels` labels, doesn't exist itself (RFC 4035 5.3.4, RFC 5155 8.8).
+    pub fn proves_wildcard_expansion(
+        &self,
+        name: &str,
+        labels_in_wildcard: u8,
+    ) -> Result<(), DNSError> {
+        if self.nsec_covering(name).is_some() {
+            return Ok(());
+        }
+        let all = labels(name);
+        let keep = (labels_in_wildcard as usize + 1).min(all.len());
+        let next_closer = all[all.len() - keep..].join(".");
+        match self.nsec3_covering(&next_closer) {
+            Some(_) => Ok(()),
+            None => Err(bogus(format!("no proof {} came from a wildcard", name))),
+        }
+    }
+
+    fn nsec_matching(&self, name: &str) -> Option<&(String, NSECData)> {
+        self.nsec
+            .iter()
+            .find(|(owner, _)| canonical_name_cmp(owner, name) == Ordering::Equal)
+    }
+
+    /// The NSEC whose span `name` falls in, showing there's nothing by that name.
+    fn nsec_covering(&self, name: &str) -> Option<&(String, NSECData)> {
+        self.nsec.iter().find(|(owner, nsec)| {
+            // Nothing below a zone cut is the parent's to deny.
+            if is_delegation(&nsec.types) && is_subdomain(name, owner) {
+                return false;
+            }
+            let after_owner = canonical_name_cmp(owner, name) == Ordering::Less;
+            let before_next = canonical_name_cmp(name, &nsec.next_domain_name) == Ordering::Less;
+            match canonical_name_cmp(owner, &nsec.next_domain_name) {
+                Ordering::Less => after_owner && before_next,
+                // The last NSEC in a zone leads back round to the apex.
+                _ => after_owner && is_subdomain(name, &nsec.next_domain_name),
+            }
+        })
+    }
+
+    /// Each NSEC3 with its zone and the hash its owner name spells out.
+    fn nsec3_hashes(&self) -> impl Iterator<Item = (&str, Vec<u8>, &NSEC3Data)> {
+        self.nsec3.iter().filter_map(|(owner, nsec3)| {
+            let (hash, zone) = owner.split_once('.').unwrap_or((owner, ""));
+            let hash = BASE32HEX_NOPAD
+                .decode(hash.to_ascii_uppercase().as_bytes())
+                .ok()?;
+            let usable = nsec3.hash_algorithm == 1 && nsec3.iterations <= MAX_NSEC3_ITERATIONS;
+            usable.then_some((zone, hash, nsec3))
+        })
+    }
+
+    fn nsec3_hash_of(name: &str, zone: &str, nsec3: &NSEC3Data) -> Option<Vec<u8>> {
+        if !is_subdomain(name, zone) {
+            return None;
+        }
+        nsec3_hash(name, &nsec3.salt, nsec3.iterations).ok()
+    }
+
+    fn nsec3_matching(&self, name: &str) -> Option<(&str, &NSEC3Data)> {
+        self.nsec3_hashes().find_map(|(zone, owner, nsec3)| {
+            let hash = Self::nsec3_hash_of(name, zone, nsec3)?;
+            (hash == owner).then_some((zone, nsec3))
+        })
+    }
+
+    fn nsec3_covering(&self, name: &str) -> Option<&NSEC3Data> {
+        self.nsec3_hashes().find_map(|(zone, owner, nsec3)| {
+            let hash = Self::nsec3_hash_of(name, zone, nsec3)?;
+            let next = &nsec3.next_hashed_owner;
+            let covered = match owner < *next {
+                true => owner < hash && hash < *next,
+                false => owner < hash || hash < *next,
+            };
+            covered.then_some(nsec3)
+        })
+    }
+
+    /// The closest encloser proof (RFC 5155 8.3): the deepest ancestor of
+    /// `name` that exists, and the NSEC3 showing that the name one label
+    /// below it on the way to `name` doesn't.
+    fn closest_encloser(&self, name: &str) -> Result<(String, &NSEC3Data), DNSError> {
+        let all = labels(name);
+        for depth in (0..all.len()).rev() {
+            let candidate = all[all.len() - depth..].join(".");
+            let Some((_, nsec3)) = self.nsec3_matching(&candidate) else {
+                continue;
+            };
+            if is_delegation(&nsec3.types) {
+                return Err(bogus(format!("{} is below a delegation", name)));
+            }
+            let next_closer = all[all.len() - depth - 1..].join(".");
+            return match self.nsec3_covering(&next_closer) {
+                Some(covering) => Ok((candidate, covering)),
+                None => Err(bogus(format!("no proof {} doesn't exist", next_closer))),
+            };
+        }
+        Err(bogus(format!("no proof {} doesn't exist", name)))
+    }
+}
+
+/// The wildcard directly below `name`.
+fn wildcard(name: &str) -> String {
+    match name.trim_end_matches('.') {
+        "" => "*".into(),
+        name => format!("*.{}", name),
+    }
+}
@@ -0,0 +1,134 @@
+use std::io::{Read, Seek};
+
+use structure::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::dns::{DNSError, DNSRecordType, Int, ResponseCode, ToBytes};
+
+/// The largest response a client can be sent over UDP
+/// unless it says otherwise with EDNS (RFC 1035 4.2.1).
+pub const MIN_UDP_PAYLOAD_SIZE: Int = 512;
+
+/// What we advertise ourselves. Big enough for most answers while
+/// staying clear of IP fragmentation (see DNS Flag Day 2020).
+pub const DEFAULT_EDNS_PAYLOAD_SIZE: Int = 1232;
+
+/// The DO bit in the OPT record's TTL field.
+const DNSSEC_OK: u32 = 1 << 15;
+
+/// An EDNS(0) OPT pseudo-record (RFC 6891 6.1).
+///
+/// It sits in the additional section but repurposes the CLASS and TTL
+/// fields, so it is kept on its own instead of as a [crate::DNSRecord].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edns {
+    /// The largest UDP payload the sender can reassemble.
+    pub udp_payload_size: Int,
+    /// The upper eight bits of the twelve bit RCODE.
+    pub extended_rcode: u8,
+    pub version: u8,
+    /// The sender can handle DNSSEC records (RFC 3225).
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: Int,
+    pub data: Vec<u8>,
+}
+
+impl Edns {
+    pub fn new(udp_payload_size: Int) -> Self {
+        Self {
+            udp_payload_size,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: vec![],
+        }
+    }
+
+    /// Values below 512 are to be treated as 512 (RFC 6891 6.2.3).
+    pub fn max_payload_size(&self) -> usize {
+        self.udp_payload_size.max(MIN_UDP_PAYLOAD_SIZE) as usize
+    }
+
+    /// Combine our upper bits with the lower four from the header.
+    pub fn rcode(&self, header_rcode: ResponseCode) -> ResponseCode {
+        let low = Int::from(header_rcode) & 0b1111;
+        ResponseCode::from((self.extended_rcode as Int) << 4 | low)
+    }
+
+    /// Read the rest of an OPT record, starting just after its TYPE.
+    pub fn from_bytes_after_type<R: Read + Seek>(reader: &mut R) -> Result<Self, DNSError> {
+        let udp_payload_size = reader.read_u16::<BigEndian>()?;
+        let ttl = reader.read_u32::<BigEndian>()?;
+        let length = reader.read_u16::<BigEndian>()?;
+
+        Ok(Self {
+            udp_payload_size,
+            extended_rcode: (ttl >> 24) as u8,
+            version: (ttl >> 16) as u8,
+            dnssec_ok: ttl & DNSSEC_OK != 0,
+            options: EdnsOption::list_from_bytes(reader, length)?,
+        })
+    }
+}
+
+impl ToBytes for Edns {
+    fn to_bytes<W: std::io::Write>(&self, writer: &mut W) -> Result<usize, DNSError> {
+        let mut options = vec![];
+        for option in self.options.iter() {
+            option.to_bytes(&mut options)?;
+        }
+
+        let mut ttl = (self.extended_rcode as u32) << 24 | (self.version as u32) << 16;
+        if self.dnssec_ok {
+            ttl |= DNSSEC_OK;
+        }
+
+        // The owner is always the root.
+        writer.write_u8(0)?;
+        writer.write_u16::<BigEndian>(Int::from(DNSRecordType::OPT))?;
+        writer.write_u16::<BigEndian>(self.udp_payload_size)?;
+        writer.write_u32::<BigEndian>(ttl)?;
+        writer.write_u16::<BigEndian>(options.len().try_into()?)?;
+        writer.write_all(&options)?;
+
+        Ok(11 + options.len())
+    }
+}
+
+impl EdnsOption {
+    /// Read `length` bytes worth of options.
+    pub fn list_from_bytes<R: Read>(reader: &mut R, length: Int) -> Result<Vec<Self>, DNSError> {
+        let mut options = vec![];
+        let mut remaining = length as usize;
+        while remaining > 0 {
+            let code = reader.read_u16::<BigEndian>()?;
+            let option_length = reader.read_u16::<BigEndian>()?;
+            let mut data = vec![0; option_length as usize];
+            reader.read_exact(&mut data)?;
+
+            let used = 4 + data.len();
+            if used > remaining {
+                return Err(DNSError::RDataLengthMismatch {
+                    expected: length,
+                    found: (length as usize - remaining + used) as u64,
+                });
+            }
+            remaining -= used;
+            options.push(Self { code, data });
+        }
+        Ok(options)
+    }
+}
+
+impl ToBytes for EdnsOption {
+    fn to_bytes<W: std::io::Write>(&self, writer: &mut W) -> Result<usize, DNSError> {
+        writer.write_u16::<BigEndian>(self.code)?;
+        writer.write_u16::<BigEndian>(self.data.len().try_into()?)?;
+        writer.write_all(&self.data)?;
+        Ok(4 + self.data.len())
+    }
+}
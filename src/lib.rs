@@ -1,7 +1,21 @@
+mod cache;
 mod dns;
+mod dnssec;
+mod edns;
+mod rdata;
 mod resolver;
+mod root_hints;
+mod server;
+mod transport;
 
+pub use cache::*;
 pub use dns::*;
+pub use dnssec::*;
+pub use edns::*;
+pub use rdata::*;
 pub use resolver::*;
+pub use root_hints::*;
+pub use server::*;
+pub use transport::*;
 #[cfg(test)]
 mod tests;
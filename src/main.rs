@@ -1,83 +1,238 @@
 use clap::Parser;
-use dns_in_a_weekend::{resolve, DNSError, DNSPacket, DNSQuery, FromBytes, ToBytes};
-use log::{error, info};
-use std::{collections::HashMap, io::Cursor, net::UdpSocket};
+use dns_in_a_weekend::{
+    load_root_hints, read_framed, respond, write_framed, AddressFamily, Cache, CacheConfig,
+    DNSError, Database, Resolver, ResolverConfig, ToBytes, DEFAULT_QUERY_TIMEOUT,
+};
+use log::{debug, error, info};
+use std::{
+    io::Cursor,
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::Arc,
+    time::Duration,
+};
+
+/// How long a TCP client may sit idle before we hang up on it (RFC 7766 6.2.3).
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The largest query that fits in a UDP datagram, so a client
+/// using EDNS options is never cut short on the way in.
+const MAX_UDP_MESSAGE_SIZE: usize = u16::MAX as usize;
 
 #[derive(Debug, Parser)]
 pub struct Opts {
     #[clap(short, long, help = "The port for the dns server to listen on.")]
     port: u16,
+    #[clap(
+        long,
+        default_value_t = 10_000,
+        help = "The most responses to keep in the cache."
+    )]
+    cache_max_entries: usize,
+    #[clap(
+        long,
+        help = "Roughly how many bytes the cache may use before it starts evicting."
+    )]
+    cache_max_bytes: Option<usize>,
+    #[clap(
+        long,
+        default_value_t = DEFAULT_QUERY_TIMEOUT.as_millis() as u64,
+        help = "How many milliseconds to wait for each upstream nameserver."
+    )]
+    query_timeout_ms: u64,
+    #[clap(
+        long,
+        default_value_t = 1,
+        help = "How many times to retry a nameserver that timed out before trying the next."
+    )]
+    retries: usize,
+    #[clap(
+        long,
+        help = "A root hints file (like IANA's named.root) listing the servers to start resolving from."
+    )]
+    root_hints: Option<std::path::PathBuf>,
+    #[clap(long, help = "Try upstream nameservers over IPv6 before IPv4.")]
+    prefer_ipv6: bool,
+    #[clap(
+        long,
+        help = "Randomise the case of names sent upstream and reject responses that don't echo it."
+    )]
+    randomize_case: bool,
+    #[clap(
+        long,
+        help = "Only tell each upstream server as much of the name as it needs (RFC 9156)."
+    )]
+    qname_minimization: bool,
+    #[clap(
+        long,
+        help = "Check DNSSEC signatures, answering SERVFAIL when they don't hold up."
+    )]
+    dnssec_validation: bool,
 }
 
-pub type Shared<T> = std::sync::Arc<std::sync::Mutex<T>>;
-pub type Database = Shared<std::collections::HashMap<String, DNSPacket>>;
+/// How often the cache is swept for entries that have expired.
+const CACHE_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
 
+/// Answer a query that arrived over UDP, trimming the
+/// response down to what the client said it can take.
 pub fn handle_datagram(
-    mut message: Cursor<Vec<u8>>,
+    message: Cursor<Vec<u8>>,
     cache: Database,
-) -> Result<DNSPacket, DNSError> {
-    let query = DNSQuery::from_bytes(&mut message)?;
-    let query_id = query.header.id;
-    info!("Resolving {}", query.question.name);
-    let mut cache_guard = cache.lock().unwrap();
-
-    if let Some(packet) = cache_guard.get(&query.question.name) {
-        // some packet is stored.
-        // just update ids and send it back.
-        let mut packet_cp = packet.clone();
-        info!(
-            "looked up {} from cache (packet: {:#?})",
-            query.question.name, packet_cp
-        );
-        packet_cp.header.id = query_id;
-        return Ok(packet_cp);
+    resolver: Arc<Resolver>,
+) -> Result<Option<Vec<u8>>, DNSError> {
+    match respond(message, cache, resolver) {
+        Some((packet, limit)) => packet.to_bytes_with_limit(limit).map(Some),
+        None => Ok(None),
     }
+}
 
-    let (mut packet, _) = resolve(&query.question.name, query.question.r#type)?;
-    cache_guard.insert(query.question.name, packet.clone());
-    packet.header.id = query_id;
-    Ok(packet)
+/// Answer a query that arrived over TCP, where there is no size limit to worry about.
+pub fn handle_stream_message(
+    message: Vec<u8>,
+    cache: Database,
+    resolver: Arc<Resolver>,
+) -> Result<Option<Vec<u8>>, DNSError> {
+    let Some((packet, _)) = respond(Cursor::new(message), cache, resolver) else {
+        return Ok(None);
+    };
+    let mut response = vec![];
+    packet.to_bytes(&mut response)?;
+    Ok(Some(response))
 }
 
-pub fn start_server(socket: UdpSocket, cache: Database) -> Result<(), Box<dyn std::error::Error>> {
+pub fn start_server(
+    socket: UdpSocket,
+    cache: Database,
+    resolver: Arc<Resolver>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut buf = vec![0; MAX_UDP_MESSAGE_SIZE];
     loop {
-        let mut buf = [0; 1024];
         let (bytes_read, sender) = socket.recv_from(&mut buf)?;
         let socket_cp = socket.try_clone()?;
         let contents = Cursor::new(buf[0..bytes_read].to_vec());
         let cache_cp = cache.clone();
-        std::thread::spawn(move || match handle_datagram(contents, cache_cp) {
-            Ok(packet) => {
-                let mut writer = Vec::new();
-                packet.to_bytes(&mut writer).unwrap();
-                socket_cp.send_to(&writer, sender).unwrap();
-                info!(
-                    "Found IP: {:#?} for {} requested by {}",
-                    packet.ip(),
-                    packet.questions[0].name,
-                    sender
-                );
+        let resolver_cp = resolver.clone();
+        std::thread::spawn(
+            move || match handle_datagram(contents, cache_cp, resolver_cp) {
+                Ok(Some(response)) => {
+                    socket_cp.send_to(&response, sender).unwrap();
+                    info!("Responded to {}", sender);
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    error!("{}", err);
+                }
+            },
+        );
+    }
+}
+
+/// Serve queries from one TCP client until it hangs up or goes quiet.
+pub fn handle_stream(
+    mut stream: TcpStream,
+    cache: Database,
+    resolver: Arc<Resolver>,
+) -> Result<(), DNSError> {
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    let peer = stream.peer_addr()?;
+
+    loop {
+        let message = match read_framed(&mut stream) {
+            Ok(message) => message,
+            Err(DNSError::IOError(err)) => {
+                debug!("Closing connection from {}: {}", peer, err);
+                return Ok(());
             }
-            Err(err) => {
+            Err(err) => return Err(err),
+        };
+        if let Some(response) = handle_stream_message(message, cache.clone(), resolver.clone())? {
+            write_framed(&mut stream, &response)?;
+            info!("Responded to {} over TCP", peer);
+        }
+    }
+}
+
+pub fn start_tcp_server(
+    listener: TcpListener,
+    cache: Database,
+    resolver: Arc<Resolver>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let cache_cp = cache.clone();
+        let resolver_cp = resolver.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = handle_stream(stream, cache_cp, resolver_cp) {
                 error!("{}", err);
             }
         });
     }
+    Ok(())
 }
 
 pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     let opts = Opts::parse();
     env_logger::init();
 
-    let db = HashMap::new();
+    let cache_config = CacheConfig {
+        max_entries: Some(opts.cache_max_entries),
+        max_bytes: opts.cache_max_bytes,
+    };
+    let cache = Cache::with_config(cache_config);
+    let cache: Database = std::sync::Arc::new(std::sync::Mutex::new(cache));
+    let mut resolver_config = ResolverConfig {
+        timeout: Duration::from_millis(opts.query_timeout_ms),
+        retries: opts.retries,
+        preferred_family: if opts.prefer_ipv6 {
+            AddressFamily::Ipv6
+        } else {
+            AddressFamily::Ipv4
+        },
+        randomize_case: opts.randomize_case,
+        qname_minimization: opts.qname_minimization,
+        dnssec_validation: opts.dnssec_validation,
+        delegation_cache: cache_config,
+        ..ResolverConfig::default()
+    };
+    if let Some(path) = &opts.root_hints {
+        resolver_config.root_servers = load_root_hints(path)?;
+    }
+    let resolver = Arc::new(Resolver::new(resolver_config));
 
-    let cache: Database = std::sync::Arc::new(std::sync::Mutex::new(db));
+    let sweep_cache = cache.clone();
+    let sweep_resolver = resolver.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(CACHE_SWEEP_INTERVAL);
+        let now = std::time::Instant::now();
+        let removed = sweep_resolver
+            .delegations()
+            .lock()
+            .unwrap()
+            .remove_expired(now);
+        debug!("Removed {} expired delegations", removed);
+        let removed = sweep_resolver.remove_expired_trust(now);
+        debug!("Removed {} expired DNSSEC zone cuts", removed);
+        let mut cache = sweep_cache.lock().unwrap();
+        let removed = cache.remove_expired(now);
+        debug!("Removed {} expired entries from the cache", removed);
+        info!(
+            "Cache holds {} entries ({} bytes): {:?}",
+            cache.len(),
+            cache.size_in_bytes(),
+            cache.stats()
+        );
+    });
 
     let socket = UdpSocket::bind(("0.0.0.0", opts.port))?;
+    let listener = TcpListener::bind(("0.0.0.0", opts.port))?;
+
+    let tcp_cache = cache.clone();
+    let tcp_resolver = resolver.clone();
+    let tcp_server =
+        std::thread::spawn(move || start_tcp_server(listener, tcp_cache, tcp_resolver).unwrap());
+    let udp_server = std::thread::spawn(move || start_server(socket, cache, resolver).unwrap());
 
-    std::thread::spawn(move || start_server(socket, cache).unwrap())
-        .join()
-        .unwrap();
+    udp_server.join().unwrap();
+    tcp_server.join().unwrap();
 
     Ok(())
 }
@@ -0,0 +1,706 @@
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use data_encoding::{BASE32HEX_NOPAD, BASE64, HEXUPPER};
+use structure::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::dns::{decode, encode, DNSError, DNSRecordClass, DNSRecordType, Int, ToBytes};
+use crate::edns::EdnsOption;
+
+/// The decoded RDATA of a resource record.
+///
+/// Any domain names inside are stored fully expanded, so a record can be
+/// moved between messages without worrying about compression pointers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RData {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    NS(String),
+    CNAME(String),
+    SOA(SOAData),
+    /// One or more <character-string>s, kept as raw bytes since they need not be UTF-8.
+    TXT(Vec<Vec<u8>>),
+    MX(MXData),
+    PTR(String),
+    SRV(SRVData),
+    NAPTR(NAPTRData),
+    OPT(Vec<EdnsOption>),
+    DS(DSData),
+    RRSIG(RRSIGData),
+    NSEC(NSECData),
+    DNSKEY(DNSKEYData),
+    NSEC3(NSEC3Data),
+    NSEC3PARAM(NSEC3PARAMData),
+    CAA(CAAData),
+    Unknown(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SOAData {
+    pub mname: String,
+    pub rname: String,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    pub minimum: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MXData {
+    pub preference: u16,
+    pub exchange: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SRVData {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// A rewrite rule for Dynamic Delegation Discovery (RFC 3403 4.1).
+/// The three <character-string>s are kept as raw bytes, like TXT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NAPTRData {
+    pub order: u16,
+    pub preference: u16,
+    pub flags: Vec<u8>,
+    pub services: Vec<u8>,
+    pub regexp: Vec<u8>,
+    pub replacement: String,
+}
+
+/// Which certificate authorities may issue for a name (RFC 8659 4.1).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CAAData {
+    pub flags: u8,
+    /// Letters and digits only. Records with any other tag are
+    /// kept as [RData::Unknown] instead.
+    pub tag: String,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DSData {
+    pub key_tag: u16,
+    pub algorithm: u8,
+    pub digest_type: u8,
+    pub digest: Vec<u8>,
+}
+
+/// Types are kept as plain numbers, here and in the NSEC bitmaps,
+/// so that signatures over types we don't know still parse.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RRSIGData {
+    pub type_covered: Int,
+    pub algorithm: u8,
+    pub labels: u8,
+    pub original_ttl: u32,
+    /// Seconds since the epoch, modulo 2^32 (RFC 4034 3.1.5).
+    pub expiration: u32,
+    pub inception: u32,
+    pub key_tag: u16,
+    pub signer_name: String,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NSECData {
+    pub next_domain_name: String,
+    pub types: Vec<Int>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DNSKEYData {
+    pub flags: u16,
+    pub protocol: u8,
+    pub algorithm: u8,
+    pub public_key: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NSEC3Data {
+    pub hash_algorithm: u8,
+    pub flags: u8,
+    pub iterations: u16,
+    pub salt: Vec<u8>,
+    /// The raw hash, not its base32hex spelling.
+    pub next_hashed_owner: Vec<u8>,
+    pub types: Vec<Int>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NSEC3PARAMData {
+    pub hash_algorithm: u8,
+    pub flags: u8,
+    pub iterations: u16,
+    pub salt: Vec<u8>,
+}
+
+impl RData {
+    /// Decode `length` bytes of RDATA for a record of the given type and class.
+    ///
+    /// The reader must be positioned over the whole message, not just the
+    /// record, so that compressed names can be followed.
+    pub fn from_bytes<R: Read + Seek>(
+        reader: &mut R,
+        r#type: DNSRecordType,
+        class: DNSRecordClass,
+        length: u16,
+    ) -> Result<Self, DNSError> {
+        let start = reader.stream_position()?;
+        // What these hold is only defined for IN, so anywhere
+        // else they're left opaque (RFC 3597 5).
+        let class_specific = matches!(
+            r#type,
+            DNSRecordType::A | DNSRecordType::AAAA | DNSRecordType::SRV
+        );
+
+        let data = match r#type {
+            _ if class_specific && class != DNSRecordClass::IN => {
+                Self::Unknown(rest(reader, start, length)?)
+            }
+            DNSRecordType::A => Self::A(Ipv4Addr::from(reader.read_u32::<BigEndian>()?)),
+            DNSRecordType::AAAA => Self::AAAA(Ipv6Addr::from(reader.read_u128::<BigEndian>()?)),
+            DNSRecordType::NS => Self::NS(decode::dns_name(reader)?.0),
+            DNSRecordType::CNAME => Self::CNAME(decode::dns_name(reader)?.0),
+            DNSRecordType::PTR => Self::PTR(decode::dns_name(reader)?.0),
+            DNSRecordType::SOA => Self::SOA(SOAData {
+                mname: decode::dns_name(reader)?.0,
+                rname: decode::dns_name(reader)?.0,
+                serial: reader.read_u32::<BigEndian>()?,
+                refresh: reader.read_u32::<BigEndian>()?,
+                retry: reader.read_u32::<BigEndian>()?,
+                expire: reader.read_u32::<BigEndian>()?,
+                minimum: reader.read_u32::<BigEndian>()?,
+            }),
+            DNSRecordType::MX => Self::MX(MXData {
+                preference: reader.read_u16::<BigEndian>()?,
+                exchange: decode::dns_name(reader)?.0,
+            }),
+            DNSRecordType::SRV => Self::SRV(SRVData {
+                priority: reader.read_u16::<BigEndian>()?,
+                weight: reader.read_u16::<BigEndian>()?,
+                port: reader.read_u16::<BigEndian>()?,
+                target: decode::dns_name(reader)?.0,
+            }),
+            DNSRecordType::NAPTR => Self::NAPTR(NAPTRData {
+                order: reader.read_u16::<BigEndian>()?,
+                preference: reader.read_u16::<BigEndian>()?,
+                flags: short_bytes(reader)?,
+                services: short_bytes(reader)?,
+                regexp: short_bytes(reader)?,
+                replacement: decode::dns_name(reader)?.0,
+            }),
+            DNSRecordType::CAA => {
+                let flags = reader.read_u8()?;
+                let tag = short_bytes(reader)?;
+                let value = rest(reader, start, length)?;
+                match String::from_utf8(tag) {
+                    Ok(tag) if is_caa_tag(&tag) => Self::CAA(CAAData { flags, tag, value }),
+                    // Not something RFC 8659 4.1 allows, but it's no
+                    // reason to throw away the rest of the message.
+                    _ => {
+                        reader.seek(SeekFrom::Start(start))?;
+                        Self::Unknown(rest(reader, start, length)?)
+                    }
+                }
+            }
+            DNSRecordType::TXT => {
+                let mut strings = vec![];
+                let mut remaining = length as usize;
+                while remaining > 0 {
+                    let len = reader.read_u8()? as usize;
+                    let mut buf = vec![0; len];
+                    reader.read_exact(&mut buf)?;
+                    strings.push(buf);
+                    remaining = remaining.saturating_sub(len + 1);
+                }
+                Self::TXT(strings)
+            }
+            DNSRecordType::OPT => Self::OPT(EdnsOption::list_from_bytes(reader, length)?),
+            DNSRecordType::DS => Self::DS(DSData {
+                key_tag: reader.read_u16::<BigEndian>()?,
+                algorithm: reader.read_u8()?,
+                digest_type: reader.read_u8()?,
+                digest: rest(reader, start, length)?,
+            }),
+            DNSRecordType::RRSIG => Self::RRSIG(RRSIGData {
+                type_covered: reader.read_u16::<BigEndian>()?,
+                algorithm: reader.read_u8()?,
+                labels: reader.read_u8()?,
+                original_ttl: reader.read_u32::<BigEndian>()?,
+                expiration: reader.read_u32::<BigEndian>()?,
+                inception: reader.read_u32::<BigEndian>()?,
+                key_tag: reader.read_u16::<BigEndian>()?,
+                signer_name: decode::dns_name(reader)?.0,
+                signature: rest(reader, start, length)?,
+            }),
+            DNSRecordType::NSEC => Self::NSEC(NSECData {
+                next_domain_name: decode::dns_name(reader)?.0,
+                types: type_bitmap::decode(&rest(reader, start, length)?)?,
+            }),
+            DNSRecordType::DNSKEY => Self::DNSKEY(DNSKEYData {
+                flags: reader.read_u16::<BigEndian>()?,
+                protocol: reader.read_u8()?,
+                algorithm: reader.read_u8()?,
+                public_key: rest(reader, start, length)?,
+            }),
+            DNSRecordType::NSEC3 => Self::NSEC3(NSEC3Data {
+                hash_algorithm: reader.read_u8()?,
+                flags: reader.read_u8()?,
+                iterations: reader.read_u16::<BigEndian>()?,
+                salt: short_bytes(reader)?,
+                next_hashed_owner: short_bytes(reader)?,
+                types: type_bitmap::decode(&rest(reader, start, length)?)?,
+            }),
+            DNSRecordType::NSEC3PARAM => Self::NSEC3PARAM(NSEC3PARAMData {
+                hash_algorithm: reader.read_u8()?,
+                flags: reader.read_u8()?,
+                iterations: reader.read_u16::<BigEndian>()?,
+                salt: short_bytes(reader)?,
+            }),
+            DNSRecordType::Unknown(_) => Self::Unknown(rest(reader, start, length)?),
+        };
+
+        let consumed = reader.stream_position()? - start;
+        if consumed != length as u64 {
+            return Err(DNSError::RDataLengthMismatch {
+                expected: length,
+                found: consumed,
+            });
+        }
+        reader.seek(SeekFrom::Start(start + length as u64))?;
+        Ok(data)
+    }
+}
+
+/// Whatever is left of RDATA that started at `start`.
+fn rest<R: Read + Seek>(reader: &mut R, start: u64, length: u16) -> Result<Vec<u8>, DNSError> {
+    let consumed = reader.stream_position()? - start;
+    let mut buf = vec![0; (length as u64).saturating_sub(consumed) as usize];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn is_caa_tag(tag: &str) -> bool {
+    !tag.is_empty() && tag.bytes().all(|byte| byte.is_ascii_alphanumeric())
+}
+
+/// A field prefixed with a one byte length, like the NSEC3 salt.
+fn short_bytes<R: Read>(reader: &mut R) -> Result<Vec<u8>, DNSError> {
+    let mut buf = vec![0; reader.read_u8()? as usize];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn write_short_bytes<W: std::io::Write>(writer: &mut W, bytes: &[u8]) -> Result<usize, DNSError> {
+    writer.write_u8(bytes.len().try_into()?)?;
+    writer.write_all(bytes)?;
+    Ok(1 + bytes.len())
+}
+
+/// The type bitmaps of NSEC and NSEC3 records (RFC 4034 4.1.2).
+pub mod type_bitmap {
+    use crate::dns::{DNSError, Int};
+
+    /// List the types in a bitmap, in increasing order.
+    pub fn decode(mut bytes: &[u8]) -> Result<Vec<Int>, DNSError> {
+        let mut types = vec![];
+        let mut last_window = None;
+        while !bytes.is_empty() {
+            let [window, length, rest @ ..] = bytes else {
+                return Err(DNSError::BadTypeBitmap("truncated window header"));
+            };
+            if last_window.is_some_and(|last| last >= *window) {
+                return Err(DNSError::BadTypeBitmap("windows out of order"));
+            }
+            let length = *length as usize;
+            if !(1..=32).contains(&length) || rest.len() < length {
+                return Err(DNSError::BadTypeBitmap("bad window length"));
+            }
+            for (index, byte) in rest[..length].iter().enumerate() {
+                for bit in 0..8 {
+                    if byte & (0x80 >> bit) != 0 {
+                        types.push((*window as Int) << 8 | (index * 8 + bit) as Int);
+                    }
+                }
+            }
+            last_window = Some(*window);
+            bytes = &rest[length..];
+        }
+        Ok(types)
+    }
+
+    /// Write out a bitmap holding `types`, which may be in any order.
+    pub fn encode(types: &[Int]) -> Vec<u8> {
+        let mut types = types.to_vec();
+        types.sort_unstable();
+        types.dedup();
+
+        let mut bytes = vec![];
+        for window in types.chunk_by(|a, b| a >> 8 == b >> 8) {
+            let mut bitmap = [0u8; 32];
+            for r#type in window {
+                let low = (r#type & 0xff) as usize;
+                bitmap[low / 8] |= 0x80 >> (low % 8);
+            }
+            let length = (window[window.len() - 1] & 0xff) as usize / 8 + 1;
+            bytes.push((window[0] >> 8) as u8);
+            bytes.push(length as u8);
+            bytes.extend_from_slice(&bitmap[..length]);
+        }
+        bytes
+    }
+}
+
+impl RData {
+    /// Like [ToBytes::to_bytes] but compresses the names that RFC 3597
+    /// section 4 allows to be compressed (NS, CNAME, SOA, MX and PTR).
+    pub fn to_bytes_compressed(
+        &self,
+        buf: &mut Vec<u8>,
+        compressor: &mut encode::Compressor,
+    ) -> Result<usize, DNSError> {
+        let start = buf.len();
+        match self {
+            Self::NS(name) | Self::CNAME(name) | Self::PTR(name) => {
+                compressor.dns_name(buf, name)?;
+            }
+            Self::SOA(soa) => {
+                compressor.dns_name(buf, &soa.mname)?;
+                compressor.dns_name(buf, &soa.rname)?;
+                for value in [soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum] {
+                    buf.write_u32::<BigEndian>(value)?;
+                }
+            }
+            Self::MX(mx) => {
+                buf.write_u16::<BigEndian>(mx.preference)?;
+                compressor.dns_name(buf, &mx.exchange)?;
+            }
+            _ => {
+                self.to_bytes(buf)?;
+            }
+        }
+        Ok(buf.len() - start)
+    }
+
+    /// The domain names in the data, to be rewritten in place.
+    ///
+    /// These are also the names that are lowercased in canonical form
+    /// (RFC 4034 6.2). The next name in an NSEC record is left out since
+    /// RFC 6840 5.1 says it keeps its case.
+    pub fn names_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            Self::NS(name) | Self::CNAME(name) | Self::PTR(name) => vec![name],
+            Self::SOA(soa) => vec![&mut soa.mname, &mut soa.rname],
+            Self::MX(mx) => vec![&mut mx.exchange],
+            Self::SRV(srv) => vec![&mut srv.target],
+            Self::NAPTR(naptr) => vec![&mut naptr.replacement],
+            Self::RRSIG(sig) => vec![&mut sig.signer_name],
+            _ => vec![],
+        }
+    }
+}
+
+impl ToBytes for RData {
+    fn to_bytes<W: std::io::Write>(&self, writer: &mut W) -> Result<usize, DNSError> {
+        match self {
+            Self::A(ip) => {
+                writer.write_all(&ip.octets())?;
+                Ok(4)
+            }
+            Self::AAAA(ip) => {
+                writer.write_all(&ip.octets())?;
+                Ok(16)
+            }
+            Self::NS(name) | Self::CNAME(name) | Self::PTR(name) => encode::dns_name(writer, name),
+            Self::SOA(soa) => {
+                let mut total = 0;
+                total += encode::dns_name(writer, &soa.mname)?;
+                total += encode::dns_name(writer, &soa.rname)?;
+                for value in [soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum] {
+                    writer.write_u32::<BigEndian>(value)?;
+                }
+                Ok(total + 20)
+            }
+            Self::MX(mx) => {
+                writer.write_u16::<BigEndian>(mx.preference)?;
+                Ok(2 + encode::dns_name(writer, &mx.exchange)?)
+            }
+            Self::SRV(srv) => {
+                writer.write_u16::<BigEndian>(srv.priority)?;
+                writer.write_u16::<BigEndian>(srv.weight)?;
+                writer.write_u16::<BigEndian>(srv.port)?;
+                Ok(6 + encode::dns_name(writer, &srv.target)?)
+            }
+            Self::NAPTR(naptr) => {
+                writer.write_u16::<BigEndian>(naptr.order)?;
+                writer.write_u16::<BigEndian>(naptr.preference)?;
+                let mut total = 4;
+                for string in [&naptr.flags, &naptr.services, &naptr.regexp] {
+                    total += write_short_bytes(writer, string)?;
+                }
+                Ok(total + encode::dns_name(writer, &naptr.replacement)?)
+            }
+            Self::CAA(caa) => {
+                writer.write_u8(caa.flags)?;
+                let tag_len = write_short_bytes(writer, caa.tag.as_bytes())?;
+                writer.write_all(&caa.value)?;
+                Ok(1 + tag_len + caa.value.len())
+            }
+            Self::TXT(strings) => {
+                let mut total = 0;
+                for string in strings {
+                    writer.write_u8(string.len().try_into()?)?;
+                    writer.write_all(string)?;
+                    total += 1 + string.len();
+                }
+                Ok(total)
+            }
+            Self::OPT(options) => {
+                let mut total = 0;
+                for option in options {
+                    total += option.to_bytes(writer)?;
+                }
+                Ok(total)
+            }
+            Self::DS(ds) => {
+                writer.write_u16::<BigEndian>(ds.key_tag)?;
+                writer.write_u8(ds.algorithm)?;
+                writer.write_u8(ds.digest_type)?;
+                writer.write_all(&ds.digest)?;
+                Ok(4 + ds.digest.len())
+            }
+            Self::RRSIG(sig) => {
+                writer.write_u16::<BigEndian>(sig.type_covered)?;
+                writer.write_u8(sig.algorithm)?;
+                writer.write_u8(sig.labels)?;
+                writer.write_u32::<BigEndian>(sig.original_ttl)?;
+                writer.write_u32::<BigEndian>(sig.expiration)?;
+                writer.write_u32::<BigEndian>(sig.inception)?;
+                writer.write_u16::<BigEndian>(sig.key_tag)?;
+                let name_len = encode::dns_name(writer, &sig.signer_name)?;
+                writer.write_all(&sig.signature)?;
+                Ok(18 + name_len + sig.signature.len())
+            }
+            Self::NSEC(nsec) => {
+                let name_len = encode::dns_name(writer, &nsec.next_domain_name)?;
+                let bitmap = type_bitmap::encode(&nsec.types);
+                writer.write_all(&bitmap)?;
+                Ok(name_len + bitmap.len())
+            }
+            Self::DNSKEY(key) => {
+                writer.write_u16::<BigEndian>(key.flags)?;
+                writer.write_u8(key.protocol)?;
+                writer.write_u8(key.algorithm)?;
+                writer.write_all(&key.public_key)?;
+                Ok(4 + key.public_key.len())
+            }
+            Self::NSEC3(nsec3) => {
+                writer.write_u8(nsec3.hash_algorithm)?;
+                writer.write_u8(nsec3.flags)?;
+                writer.write_u16::<BigEndian>(nsec3.iterations)?;
+                let mut total = 4;
+                total += write_short_bytes(writer, &nsec3.salt)?;
+                total += write_short_bytes(writer, &nsec3.next_hashed_owner)?;
+                let bitmap = type_bitmap::encode(&nsec3.types);
+                writer.write_all(&bitmap)?;
+                Ok(total + bitmap.len())
+            }
+            Self::NSEC3PARAM(param) => {
+                writer.write_u8(param.hash_algorithm)?;
+                writer.write_u8(param.flags)?;
+                writer.write_u16::<BigEndian>(param.iterations)?;
+                Ok(4 + write_short_bytes(writer, &param.salt)?)
+            }
+            Self::Unknown(data) => {
+                writer.write_all(data)?;
+                Ok(data.len())
+            }
+        }
+    }
+}
+
+/// `name` spelled out in full with its trailing dot, as zone files do.
+pub fn fqdn(name: &str) -> String {
+    format!("{}.", name.trim_end_matches('.'))
+}
+
+/// Turn seconds since the epoch into the YYYYMMDDHHmmSS form
+/// that RRSIG records use in zone files (RFC 4034 3.2).
+pub fn format_timestamp(timestamp: u32) -> String {
+    let (days, seconds) = (timestamp / 86400, timestamp % 86400);
+
+    // Howard Hinnant's days_from_civil, run backwards.
+    let days = days as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn write_types(f: &mut fmt::Formatter<'_>, types: &[Int]) -> fmt::Result {
+    for r#type in types {
+        write!(f, " {}", DNSRecordType::mnemonic(*r#type))?;
+    }
+    Ok(())
+}
+
+/// A <character-string> in quotes, escaping whatever isn't printable.
+fn write_character_string(f: &mut fmt::Formatter<'_>, string: &[u8]) -> fmt::Result {
+    write!(f, "\"")?;
+    for &byte in string {
+        match byte {
+            b'"' | b'\\' => write!(f, "\\{}", byte as char)?,
+            0x20..=0x7e => write!(f, "{}", byte as char)?,
+            _ => write!(f, "\\{:03}", byte)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// The data in zone file presentation format. Anything we can't spell
+/// out field by field uses the generic `\# length hex` form of RFC 3597.
+impl fmt::Display for RData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::A(ip) => write!(f, "{}", ip),
+            Self::AAAA(ip) => write!(f, "{}", ip),
+            Self::NS(name) | Self::CNAME(name) | Self::PTR(name) => write!(f, "{}", fqdn(name)),
+            Self::SOA(soa) => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                fqdn(&soa.mname),
+                fqdn(&soa.rname),
+                soa.serial,
+                soa.refresh,
+                soa.retry,
+                soa.expire,
+                soa.minimum
+            ),
+            Self::TXT(strings) => {
+                for (index, string) in strings.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, " ")?;
+                    }
+                    write_character_string(f, string)?;
+                }
+                Ok(())
+            }
+            Self::MX(mx) => write!(f, "{} {}", mx.preference, fqdn(&mx.exchange)),
+            Self::SRV(srv) => write!(
+                f,
+                "{} {} {} {}",
+                srv.priority,
+                srv.weight,
+                srv.port,
+                fqdn(&srv.target)
+            ),
+            Self::NAPTR(naptr) => {
+                write!(f, "{} {} ", naptr.order, naptr.preference)?;
+                for string in [&naptr.flags, &naptr.services, &naptr.regexp] {
+                    write_character_string(f, string)?;
+                    write!(f, " ")?;
+                }
+                write!(f, "{}", fqdn(&naptr.replacement))
+            }
+            Self::CAA(caa) => {
+                write!(f, "{} {} ", caa.flags, caa.tag)?;
+                write_character_string(f, &caa.value)
+            }
+            Self::DS(ds) => write!(
+                f,
+                "{} {} {} {}",
+                ds.key_tag,
+                ds.algorithm,
+                ds.digest_type,
+                HEXUPPER.encode(&ds.digest)
+            ),
+            Self::RRSIG(sig) => write!(
+                f,
+                "{} {} {} {} {} {} {} {} {}",
+                DNSRecordType::mnemonic(sig.type_covered),
+                sig.algorithm,
+                sig.labels,
+                sig.original_ttl,
+                format_timestamp(sig.expiration),
+                format_timestamp(sig.inception),
+                sig.key_tag,
+                fqdn(&sig.signer_name),
+                BASE64.encode(&sig.signature)
+            ),
+            Self::NSEC(nsec) => {
+                write!(f, "{}", fqdn(&nsec.next_domain_name))?;
+                write_types(f, &nsec.types)
+            }
+            Self::DNSKEY(key) => write!(
+                f,
+                "{} {} {} {}",
+                key.flags,
+                key.protocol,
+                key.algorithm,
+                BASE64.encode(&key.public_key)
+            ),
+            Self::NSEC3(nsec3) => {
+                write!(
+                    f,
+                    "{} {} {} {} {}",
+                    nsec3.hash_algorithm,
+                    nsec3.flags,
+                    nsec3.iterations,
+                    salt(&nsec3.salt),
+                    BASE32HEX_NOPAD.encode(&nsec3.next_hashed_owner)
+                )?;
+                write_types(f, &nsec3.types)
+            }
+            Self::NSEC3PARAM(param) => write!(
+                f,
+                "{} {} {} {}",
+                param.hash_algorithm,
+                param.flags,
+                param.iterations,
+                salt(&param.salt)
+            ),
+            Self::OPT(_) | Self::Unknown(_) => {
+                let mut data = vec![];
+                self.to_bytes(&mut data).map_err(|_| fmt::Error)?;
+                write!(f, "\\# {}", data.len())?;
+                if !data.is_empty() {
+                    write!(f, " {}", HEXUPPER.encode(&data))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// An NSEC3 salt in hex, or `-` when there isn't one (RFC 5155 3.3).
+fn salt(salt: &[u8]) -> String {
+    match salt.is_empty() {
+        true => "-".into(),
+        false => HEXUPPER.encode(salt),
+    }
+}
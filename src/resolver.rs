@@ -1,44 +1,1195 @@
+use std::collections::{HashMap, HashSet};
 use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use log::{debug, error, trace};
 
+use crate::cache::{CacheConfig, Delegation, DelegationCache, MAX_CACHE_TTL};
 use crate::dns::*;
+use crate::dnssec::{
+    is_supported_algorithm, is_supported_digest, root_trust_anchors, verify_rrsig,
+    DenialOfExistence, NoDs,
+};
+use crate::edns::{Edns, DEFAULT_EDNS_PAYLOAD_SIZE};
+use crate::rdata::{DNSKEYData, DSData, RData, RRSIGData};
+use crate::root_hints::default_root_servers;
+use crate::transport::{NetworkTransport, Transport};
 
-pub fn resolve(
-    domain_name: &str,
-    record_type: DNSRecordType,
-) -> Result<(DNSPacket, IpAddr), DNSError> {
-    let mut nameserver = "198.41.0.4:53".parse::<SocketAddr>()?;
+/// The most aliases followed for one name before giving up.
+pub const MAX_CNAME_CHAIN: usize = 8;
 
-    debug!("Resolving {}", domain_name);
+/// Which kind of address nameservers are tried on first. The other
+/// kind is still tried when none of the preferred addresses work out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+    #[default]
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    fn of(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => Self::Ipv4,
+            IpAddr::V6(_) => Self::Ipv6,
+        }
+    }
+
+    fn other(self) -> Self {
+        match self {
+            Self::Ipv4 => Self::Ipv6,
+            Self::Ipv6 => Self::Ipv4,
+        }
+    }
+
+    /// The type of the records that hold addresses of this kind.
+    fn record_type(self) -> DNSRecordType {
+        match self {
+            Self::Ipv4 => DNSRecordType::A,
+            Self::Ipv6 => DNSRecordType::AAAA,
+        }
+    }
+}
+
+/// How many nameservers without glue may be looked up on the way to
+/// resolving one name, counting the lookups those lookups need.
+pub const MAX_NAMESERVER_LOOKUPS: usize = 10;
+
+/// The most queries to spend revealing a name one label at a time
+/// before asking for all of it (RFC 9156 2.3).
+pub const MAX_MINIMISED_QUERIES: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolverConfig {
+    /// Where iteration starts. Normally the root servers, but
+    /// anything that will hand out referrals from the root will do.
+    pub root_servers: Vec<SocketAddr>,
+    /// How long to wait for each nameserver to respond.
+    pub timeout: Duration,
+    /// How many more times to ask a nameserver that timed out
+    /// before moving on to the next one.
+    pub retries: usize,
+    /// Which kind of nameserver address to try first.
+    pub preferred_family: AddressFamily,
+    /// Send names in a random mix of upper and lower case, and only accept
+    /// responses that echo it (the "0x20 bit").
+    pub randomize_case: bool,
+    /// Only tell each server as much of the name as it needs to know
+    /// (RFC 9156).
+    pub qname_minimization: bool,
+    /// Check DNSSEC signatures on everything resolved, failing on any
+    /// that don't hold up and setting AD on the responses that do.
+    pub dnssec_validation: bool,
+    /// The DS records for the root's keys that validation starts from.
+    pub trust_anchors: Vec<DSData>,
+    /// Limits on how many zone cuts are remembered.
+    pub delegation_cache: CacheConfig,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        Self {
+            root_servers: default_root_servers(),
+            timeout: DEFAULT_QUERY_TIMEOUT,
+            retries: 1,
+            preferred_family: AddressFamily::default(),
+            randomize_case: false,
+            qname_minimization: false,
+            dnssec_validation: false,
+            trust_anchors: root_trust_anchors(),
+            delegation_cache: CacheConfig::default(),
+        }
+    }
+}
+
+/// Somewhere to send a query: a server we have an address for, or
+/// one we only know the name of until we've looked it up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Nameserver {
+    Address(SocketAddr),
+    Name(String),
+}
+
+/// The nameservers being looked up on the way to resolving a name, and
+/// how many more lookups are left before giving up on the rest.
+#[derive(Debug)]
+struct NameserverLookups {
+    pending: Vec<String>,
+    remaining: usize,
+}
+
+impl Default for NameserverLookups {
+    fn default() -> Self {
+        Self {
+            pending: vec![],
+            remaining: MAX_NAMESERVER_LOOKUPS,
+        }
+    }
+}
+
+/// What DNSSEC can vouch for about a name.
+#[derive(Debug, Clone)]
+enum Trust {
+    /// The name is in a signed zone, whose keys have been checked.
+    Secure { zone: String, keys: Vec<DNSKEYData> },
+    /// The name is at or below `zone`, which is delegated to without
+    /// being signed, or is signed in ways we can't check.
+    Insecure { zone: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    config: ResolverConfig,
+    transport: Arc<dyn Transport>,
+    delegations: Arc<Mutex<DelegationCache>>,
+    /// What DNSSEC validation has found out about zone cuts so far, and until when.
+    trust: Arc<Mutex<HashMap<String, (Trust, Instant)>>>,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new(ResolverConfig::default())
+    }
+}
+
+/// Resolve with the default configuration. See [Resolver::resolve].
+pub fn resolve(domain_name: &str, record_type: DNSRecordType) -> Result<DNSPacket, DNSError> {
+    Resolver::default().resolve(domain_name, record_type)
+}
+
+impl Resolver {
+    pub fn new(config: ResolverConfig) -> Self {
+        Self::with_transport(config, Arc::new(NetworkTransport))
+    }
+
+    /// A resolver that sends its queries through `transport` instead of the network.
+    pub fn with_transport(config: ResolverConfig, transport: Arc<dyn Transport>) -> Self {
+        Self {
+            delegations: Arc::new(Mutex::new(DelegationCache::with_config(
+                config.delegation_cache,
+            ))),
+            config,
+            transport,
+            trust: Arc::default(),
+        }
+    }
+
+    pub fn config(&self) -> &ResolverConfig {
+        &self.config
+    }
+
+    /// The zone cuts learned from referrals so far.
+    pub fn delegations(&self) -> &Mutex<DelegationCache> {
+        &self.delegations
+    }
+
+    /// Walk down from the root servers to find `record_type` records for `domain_name`.
+    ///
+    /// Aliases are followed wherever they lead, with every CNAME along the
+    /// way put in the answer section ahead of the records they lead to.
+    ///
+    /// A response saying the name or records don't exist is a successful
+    /// resolution too, and is returned as is.
+    ///
+    /// With DNSSEC validation on, the response only comes back if its
+    /// signatures hold up or it's from a zone that isn't signed. AD is
+    /// set on it when everything in it was signed.
+    pub fn resolve(
+        &self,
+        domain_name: &str,
+        record_type: DNSRecordType,
+    ) -> Result<DNSPacket, DNSError> {
+        self.resolve_checking(domain_name, record_type, true)
+    }
+
+    /// [Resolver::resolve] for clients that set CD to do their own
+    /// validation (RFC 4035 3.2.2). Responses that don't validate come
+    /// back anyway, just without AD.
+    pub fn resolve_unchecked(
+        &self,
+        domain_name: &str,
+        record_type: DNSRecordType,
+    ) -> Result<DNSPacket, DNSError> {
+        self.resolve_checking(domain_name, record_type, false)
+    }
+
+    fn resolve_checking(
+        &self,
+        domain_name: &str,
+        record_type: DNSRecordType,
+        checking: bool,
+    ) -> Result<DNSPacket, DNSError> {
+        let mut response =
+            self.lookup(domain_name, record_type, &mut NameserverLookups::default())?;
+        response.header.flags.authentic_data = false;
+        if self.config.dnssec_validation {
+            let secure = match self.validate(&response, domain_name, record_type) {
+                Ok(secure) => secure,
+                Err(err) if !checking => {
+                    debug!(
+                        "{} {:?} didn't validate, passing it on unchecked: {}",
+                        domain_name, record_type, err
+                    );
+                    false
+                }
+                Err(err) => return Err(err),
+            };
+            debug!(
+                "{} {:?} is {}",
+                domain_name,
+                record_type,
+                if secure { "secure" } else { "insecure" }
+            );
+            response.header.flags.authentic_data = secure;
+        }
+        Ok(response)
+    }
+
+    /// [Resolver::resolve] without any DNSSEC validation.
+    fn lookup(
+        &self,
+        domain_name: &str,
+        record_type: DNSRecordType,
+        lookups: &mut NameserverLookups,
+    ) -> Result<DNSPacket, DNSError> {
+        debug!("Resolving {}", domain_name);
+
+        let mut name = domain_name.to_string();
+        let mut aliases = HashSet::from([name.to_ascii_lowercase()]);
+        let mut answers = vec![];
+        loop {
+            let mut response = self.iterate(&name, record_type, lookups)?;
+
+            // The server may have followed some of the chain for us.
+            let mut target = name.clone();
+            while let Some(next) = cname_target(&response, &target, record_type) {
+                if !aliases.insert(next.to_ascii_lowercase()) {
+                    return Err(DNSError::CnameLoop(next));
+                }
+                // The name we started with doesn't count.
+                if aliases.len() > MAX_CNAME_CHAIN + 1 {
+                    return Err(DNSError::CnameChainTooLong(domain_name.to_string()));
+                }
+                target = next;
+            }
+            let chased = target != name;
+            let resolved = response.answers_for(&target, record_type).next().is_some();
+            answers.append(&mut response.answers);
+            if !chased || resolved || response.rcode() != ResponseCode::NoError {
+                response.answers = answers;
+                response.questions = vec![DNSQuestion::new(
+                    domain_name,
+                    record_type,
+                    DNSRecordClass::IN,
+                )];
+                return Ok(response);
+            }
+
+            trace!("{} is an alias, restarting with {}", name, target);
+            name = target;
+        }
+    }
+
+    /// Follow referrals down to a server that knows the answer for
+    /// `domain_name`, or knows there isn't one, starting from the closest
+    /// zone cut we know of.
+    ///
+    /// With QNAME minimisation on, each server is only told as much of the
+    /// name as it needs to refer us onwards (RFC 9156), one label more than
+    /// what we know it's responsible for, and asked for A records rather
+    /// than what we're really after. Servers that trip up over that get
+    /// asked the full question instead.
+    fn iterate(
+        &self,
+        domain_name: &str,
+        record_type: DNSRecordType,
+        lookups: &mut NameserverLookups,
+    ) -> Result<DNSPacket, DNSError> {
+        // DS records live on the parent's side of a zone cut, so they
+        // have to be asked for from above it (RFC 4035 4.2).
+        let start = match record_type {
+            DNSRecordType::DS => parent_name(domain_name),
+            _ => domain_name,
+        };
+        let cached = self.delegations.lock().unwrap().closest(start);
+        let mut from_cache = cached.is_some();
+        // The zone the nameservers we're about to ask are
+        // responsible for, the root being the empty name.
+        let (mut zone, mut nameservers) = match &cached {
+            Some(delegation) => {
+                trace!("Starting from {} for {}", delegation.zone, domain_name);
+                (delegation.zone.clone(), self.nameservers(delegation))
+            }
+            None => (String::new(), self.root_nameservers()),
+        };
+        let mut minimise = self.config.qname_minimization;
+        // How much of the name has been given away so far.
+        let mut revealed = zone.clone();
+        let mut minimised_queries = 0;
+
+        loop {
+            let query_name = match minimise {
+                true => one_label_below(&revealed, domain_name),
+                false => domain_name.to_string(),
+            };
+            let minimising = !is_same_name(&query_name, domain_name);
+            let query_type = match minimising {
+                true => DNSRecordType::A,
+                false => record_type,
+            };
+
+            let response =
+                match self.query_nameservers(&nameservers, &zone, &query_name, query_type, lookups)
+                {
+                    Ok(response) => response,
+                    Err(err) if minimising => {
+                        debug!(
+                            "Nameservers for {:?} failed on {}, asking for {} instead: {}",
+                            zone, query_name, domain_name, err
+                        );
+                        minimise = false;
+                        continue;
+                    }
+                    // What we had cached may have gone stale, so try again from the top.
+                    Err(err) if from_cache => {
+                        debug!("Nameservers for {} failed, starting over: {}", zone, err);
+                        self.delegations.lock().unwrap().remove(&zone);
+                        from_cache = false;
+                        zone = String::new();
+                        revealed = String::new();
+                        nameservers = self.root_nameservers();
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
+            from_cache = false;
+
+            if minimising {
+                minimised_queries += 1;
+                if minimised_queries >= MAX_MINIMISED_QUERIES {
+                    minimise = false;
+                }
+
+                let is_referral = response.answers.is_empty() && !response.is_negative();
+                // NXDOMAIN should mean there's nothing further down either
+                // (RFC 8020), but some servers say it for names that only
+                // exist to hold up others, so it's checked with the full name.
+                // An alias in the way means the full name is needed anyway.
+                if response.rcode() == ResponseCode::NXDomain
+                    || response
+                        .answers_for(&query_name, DNSRecordType::CNAME)
+                        .next()
+                        .is_some()
+                {
+                    trace!(
+                        "{} gave nothing away, asking for {}",
+                        query_name,
+                        domain_name
+                    );
+                    minimise = false;
+                    continue;
+                }
+                // The same servers are responsible for the next label down too.
+                if !is_referral {
+                    revealed = query_name;
+                    continue;
+                }
+            } else {
+                if !response.answers.is_empty() {
+                    return Ok(response);
+                }
+                if response.is_negative() {
+                    debug!(
+                        "{} has no {:?} records ({:?})",
+                        domain_name,
+                        record_type,
+                        response.rcode()
+                    );
+                    return Ok(response);
+                }
+            }
+
+            let Some(delegation) = Delegation::from_referral(&response) else {
+                // Some servers send back an empty NOERROR without an SOA
+                // for names that only exist to hold up others.
+                if minimising {
+                    trace!(
+                        "{} got neither an answer nor a referral, asking for {}",
+                        query_name,
+                        domain_name
+                    );
+                    minimise = false;
+                    continue;
+                }
+                error!(
+                    "Got neither an answer nor a referral for {}. {:#?}",
+                    query_name, response
+                );
+                return Err(DNSError::Other);
+            };
+            // Only referrals further down are any use. Anything else
+            // would have us going round in circles.
+            if delegation.zone.trim_end_matches('.').len() <= zone.trim_end_matches('.').len() {
+                return Err(DNSError::OutOfBailiwick {
+                    zone: delegation.zone,
+                    from: if zone.is_empty() { ".".into() } else { zone },
+                });
+            }
+            nameservers = self.nameservers(&delegation);
+            zone = delegation.zone.clone();
+            revealed = delegation.zone.clone();
+            self.delegations.lock().unwrap().insert(delegation);
+        }
+    }
+
+    fn root_nameservers(&self) -> Vec<Nameserver> {
+        let mut nameservers: Vec<Nameserver> = self
+            .config
+            .root_servers
+            .iter()
+            .copied()
+            .map(Nameserver::Address)
+            .collect();
+        self.by_preference(&mut nameservers);
+        nameservers
+    }
+
+    /// The nameservers for a zone, those with glue first since
+    /// they can be asked without looking anything else up.
+    fn nameservers(&self, delegation: &Delegation) -> Vec<Nameserver> {
+        let mut nameservers = vec![];
+        for (name, glue) in delegation.nameservers.iter() {
+            if glue.is_empty() {
+                nameservers.push(Nameserver::Name(name.clone()));
+            }
+            nameservers.extend(
+                glue.iter()
+                    .map(|ip| Nameserver::Address(SocketAddr::new(*ip, 53))),
+            );
+        }
+        self.by_preference(&mut nameservers);
+        nameservers
+    }
+
+    /// Put the addresses of the preferred family first, keeping the order
+    /// otherwise. Nameservers still to be looked up stay at the back.
+    fn by_preference(&self, nameservers: &mut [Nameserver]) {
+        nameservers.sort_by_key(|nameserver| match nameserver {
+            Nameserver::Address(address)
+                if AddressFamily::of(address.ip()) == self.config.preferred_family =>
+            {
+                0
+            }
+            Nameserver::Address(_) => 1,
+            Nameserver::Name(_) => 2,
+        });
+    }
+
+    /// Ask each of `nameservers` for `zone` in turn until one of them gives a usable response.
+    ///
+    /// Nameservers without glue are looked up first, unless that can't
+    /// work: when they're inside `zone` itself, or are already being
+    /// looked up further out, or too many have been looked up already.
+    fn query_nameservers(
+        &self,
+        nameservers: &[Nameserver],
+        zone: &str,
+        domain_name: &str,
+        record_type: DNSRecordType,
+        lookups: &mut NameserverLookups,
+    ) -> Result<DNSPacket, DNSError> {
+        for nameserver in nameservers {
+            let name = match nameserver {
+                Nameserver::Address(address) => {
+                    match self.query_with_retries(*address, zone, domain_name, record_type) {
+                        Ok(response) => return Ok(response),
+                        Err(err) => {
+                            debug!("{} couldn't answer for {}: {}", address, domain_name, err)
+                        }
+                    }
+                    continue;
+                }
+                Nameserver::Name(name) => name,
+            };
+            if is_subdomain(name, zone) {
+                debug!(
+                    "Skipping nameserver {} for {:?}, which has no glue",
+                    name, zone
+                );
+                continue;
+            }
+            if lookups
+                .pending
+                .iter()
+                .any(|pending| is_same_name(pending, name))
+            {
+                debug!(
+                    "Skipping nameserver {}, which is already being looked up",
+                    name
+                );
+                continue;
+            }
+            if lookups.remaining == 0 {
+                debug!("Skipping nameserver {}, too many have been looked up", name);
+                continue;
+            }
+
+            lookups.remaining -= 1;
+            lookups.pending.push(name.clone());
+            let response = self.query_by_name(name, zone, domain_name, record_type, lookups);
+            lookups.pending.pop();
+            if let Some(response) = response {
+                return Ok(response);
+            }
+        }
+        Err(DNSError::NoNameserverResponded(domain_name.to_string()))
+    }
+
+    /// Look up the nameserver `name` and ask each of its addresses in turn.
+    fn query_by_name(
+        &self,
+        name: &str,
+        zone: &str,
+        domain_name: &str,
+        record_type: DNSRecordType,
+        lookups: &mut NameserverLookups,
+    ) -> Option<DNSPacket> {
+        // Only look up the other family's addresses if none of the
+        // preferred ones get us anywhere.
+        let preferred = self.config.preferred_family;
+        for family in [preferred, preferred.other()] {
+            let addresses = match self.lookup(name, family.record_type(), lookups) {
+                Ok(response) => response.ips(),
+                Err(err) => {
+                    debug!("Couldn't look up nameserver {}: {}", name, err);
+                    continue;
+                }
+            };
+            for ip in addresses {
+                let address = SocketAddr::new(ip, 53);
+                match self.query_with_retries(address, zone, domain_name, record_type) {
+                    Ok(response) => return Some(response),
+                    Err(err) => {
+                        debug!("{} couldn't answer for {}: {}", address, domain_name, err)
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Ask one nameserver, trying again if it doesn't answer in time.
+    fn query_with_retries(
+        &self,
+        address: SocketAddr,
+        zone: &str,
+        domain_name: &str,
+        record_type: DNSRecordType,
+    ) -> Result<DNSPacket, DNSError> {
+        let mut attempts = 0;
+        loop {
+            trace!("Querying {:?} for {}", address, domain_name);
+            let mut query = DNSQuery::new(
+                domain_name,
+                record_type,
+                DNSRecordClass::IN,
+                DNSHeaderFlags::NONE,
+            )
+            .with_edns(Edns {
+                dnssec_ok: self.config.dnssec_validation,
+                ..Edns::new(DEFAULT_EDNS_PAYLOAD_SIZE)
+            });
+            if self.config.randomize_case {
+                query = query.with_randomized_case();
+            }
+
+            match self.transport.query(&query, address, self.config.timeout) {
+                Ok(mut response) => {
+                    query.check_response(&response)?;
+                    if self.config.randomize_case {
+                        restore_case(&mut response, domain_name);
+                    }
+                    return match response.rcode() {
+                        rcode @ (ResponseCode::ServFail
+                        | ResponseCode::Refused
+                        | ResponseCode::NotImp
+                        | ResponseCode::FormErr) => Err(DNSError::NameserverFailed(rcode)),
+                        _ => {
+                            sanitize(&mut response, zone, domain_name, record_type);
+                            Ok(response)
+                        }
+                    };
+                }
+                Err(err) if err.is_timeout() && attempts < self.config.retries => {
+                    attempts += 1;
+                    debug!("{} timed out, retrying ({})", address, attempts);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Check the DNSSEC signatures over everything in the answer and
+    /// authority sections of `response`, and that it proves whatever it
+    /// says doesn't exist. Returns whether it was all signed, as opposed
+    /// to some of it coming from zones that aren't.
+    fn validate(
+        &self,
+        response: &DNSPacket,
+        domain_name: &str,
+        record_type: DNSRecordType,
+    ) -> Result<bool, DNSError> {
+        let now = unix_time();
+        let mut secure = true;
+        let mut checked = vec![];
+        let mut wildcards = vec![];
+        for (rrset, sigs) in rrsets(&response.answers)
+            .into_iter()
+            .chain(rrsets(&response.authorities))
+        {
+            match self.check_rrset(&rrset, &sigs, now)? {
+                Some(labels) => {
+                    if (labels as usize) < label_count(&rrset[0].name) {
+                        wildcards.push((rrset[0].name.clone(), labels));
+                    }
+                    checked.extend(rrset);
+                }
+                None => secure = false,
+            }
+        }
+        if !secure {
+            return Ok(false);
+        }
+
+        let denial = DenialOfExistence::new(&checked);
+        for (name, labels) in wildcards {
+            denial.proves_wildcard_expansion(&name, labels)?;
+        }
+
+        let mut target = domain_name.to_string();
+        for _ in 0..=MAX_CNAME_CHAIN {
+            match cname_target(response, &target, record_type) {
+                Some(next) => target = next,
+                None => break,
+            }
+        }
+        if response.answers_for(&target, record_type).next().is_some() {
+            return Ok(true);
+        }
+        if denial.is_empty() {
+            // Nothing signed says why there's no answer, which is only
+            // to be expected from a zone that isn't signed.
+            let zone = response
+                .get_soa()
+                .map(|(soa, _)| soa.name.as_str())
+                .filter(|zone| is_subdomain(&target, zone))
+                .unwrap_or(&target);
+            return match self.zone_trust(zone)? {
+                Trust::Insecure { .. } => Ok(false),
+                Trust::Secure { zone, .. } => Err(DNSError::Bogus(format!(
+                    "nothing from {:?} proves there's no {:?} for {}",
+                    zone, record_type, target
+                ))),
+            };
+        }
+        match response.rcode() {
+            ResponseCode::NXDomain => denial.proves_name_error(&target)?,
+            _ => denial.proves_no_data(&target, record_type)?,
+        }
+        Ok(true)
+    }
+
+    /// Check the signatures over one RRset. Returns how many labels the
+    /// good signature counted, which is fewer than the owner has when the
+    /// records were made up from a wildcard, or `None` when they're from
+    /// a zone that isn't signed.
+    fn check_rrset(
+        &self,
+        rrset: &[DNSRecord],
+        sigs: &[RRSIGData],
+        now: u32,
+    ) -> Result<Option<u8>, DNSError> {
+        let owner = &rrset[0].name;
+        if sigs.is_empty() {
+            return match self.zone_trust(owner)? {
+                Trust::Insecure { .. } => Ok(None),
+                Trust::Secure { zone, .. } => Err(DNSError::Bogus(format!(
+                    "{} {:?} from {:?} isn't signed",
+                    owner, rrset[0].r#type, zone
+                ))),
+            };
+        }
+
+        let mut failure = None;
+        for sig in sigs {
+            if !is_subdomain(owner, &sig.signer_name) {
+                failure = Some(DNSError::Bogus(format!(
+                    "{} can't sign for {}",
+                    sig.signer_name, owner
+                )));
+                continue;
+            }
+            match self.zone_trust(&sig.signer_name)? {
+                Trust::Insecure { .. } => return Ok(None),
+                trust => match trust.verify(rrset, sig, now) {
+                    Ok(()) => return Ok(Some(sig.labels)),
+                    Err(err) => failure = Some(err),
+                },
+            }
+        }
+        Err(failure.expect("there was at least one signature"))
+    }
+
+    /// What DNSSEC can vouch for about `name`. Trust is worked out from
+    /// the trust anchor down, one zone cut at a time, checking the DS and
+    /// DNSKEY records at each.
+    fn zone_trust(&self, name: &str) -> Result<Trust, DNSError> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(trust) = self.known_trust(&name) {
+            return Ok(trust);
+        }
+
+        let (trust, ttl) = match name.is_empty() {
+            true => self.zone_keys("", &self.config.trust_anchors)?,
+            false => self.find_trust(&name)?,
+        };
+        // Only zone cuts are remembered, not every name that's asked about.
+        if is_same_name(trust.zone(), &name) {
+            let until = Instant::now() + Duration::from_secs(ttl.min(MAX_CACHE_TTL) as u64);
+            let mut known = self.trust.lock().unwrap();
+            if let Some(max_entries) = self.config.delegation_cache.max_entries {
+                if known.len() >= max_entries {
+                    let now = Instant::now();
+                    known.retain(|_, (_, until)| *until > now);
+                }
+                if known.len() >= max_entries {
+                    let soonest = known
+                        .iter()
+                        .min_by_key(|(_, (_, until))| *until)
+                        .map(|(zone, _)| zone.clone());
+                    if let Some(zone) = soonest {
+                        known.remove(&zone);
+                    }
+                }
+            }
+            known.insert(name, (trust.clone(), until));
+        }
+        Ok(trust)
+    }
+
+    /// What's already known about `name` from the closest zone cut above
+    /// it that has been checked. Everything below a zone that isn't signed
+    /// is insecure, but a name in a signed zone could be below a cut we
+    /// don't know about yet, so that has to be asked.
+    fn known_trust(&self, name: &str) -> Option<Trust> {
+        let known = self.trust.lock().unwrap();
+        let now = Instant::now();
+        let mut ancestor = name;
+        loop {
+            if let Some((trust, until)) = known.get(ancestor) {
+                if *until > now {
+                    return match trust {
+                        Trust::Insecure { .. } => Some(trust.clone()),
+                        Trust::Secure { .. } if ancestor == name => Some(trust.clone()),
+                        Trust::Secure { .. } => None,
+                    };
+                }
+            }
+            if ancestor.is_empty() {
+                return None;
+            }
+            ancestor = parent_name(ancestor);
+        }
+    }
+
+    /// Drop what's known about zone cuts whose TTLs have run out by
+    /// `now`. Returns how many were dropped.
+    pub fn remove_expired_trust(&self, now: Instant) -> usize {
+        let mut known = self.trust.lock().unwrap();
+        let before = known.len();
+        known.retain(|_, (_, until)| *until > now);
+        before - known.len()
+    }
+
+    /// How many zone cuts DNSSEC validation has checked and still remembers.
+    pub fn known_zone_cuts(&self) -> usize {
+        self.trust.lock().unwrap().len()
+    }
+
+    /// Ask above `name` for its DS records. If there are any, it's a signed
+    /// zone and its keys are checked against them. If there provably
+    /// aren't, it's either in the same zone as the proof or delegated to
+    /// one that isn't signed. Returns how long the answer holds as well.
+    fn find_trust(&self, name: &str) -> Result<(Trust, u32), DNSError> {
+        let now = unix_time();
+        let response = self.iterate(name, DNSRecordType::DS, &mut NameserverLookups::default())?;
+        let ttl = min_ttl(&response);
+
+        let ds_records: Vec<DNSRecord> = response
+            .answers_for(name, DNSRecordType::DS)
+            .cloned()
+            .collect();
+        if !ds_records.is_empty() {
+            let sigs = sigs_over(&response.answers, name, DNSRecordType::DS);
+            // The zone above signs for them, so trust comes from there.
+            let above = sigs
+                .iter()
+                .map(|sig| sig.signer_name.as_str())
+                .find(|signer| is_strictly_below(name, signer))
+                .unwrap_or_else(|| parent_name(name));
+            let trust = self.zone_trust(above)?;
+            if let Trust::Insecure { .. } = trust {
+                return Ok((trust, ttl));
+            }
+            if !sigs
+                .iter()
+                .any(|sig| trust.verify(&ds_records, sig, now).is_ok())
+            {
+                return Err(DNSError::Bogus(format!(
+                    "DS records for {} aren't signed",
+                    name
+                )));
+            }
+            let ds: Vec<DSData> = ds_records
+                .iter()
+                .filter_map(|record| match &record.data {
+                    RData::DS(ds) => Some(ds.clone()),
+                    _ => None,
+                })
+                .collect();
+            let (trust, keys_ttl) = self.zone_keys(name, &ds)?;
+            return Ok((trust, ttl.min(keys_ttl)));
+        }
+
+        let above = response
+            .get_soa()
+            .map(|(soa, _)| soa.name.as_str())
+            .filter(|zone| is_strictly_below(name, zone))
+            .unwrap_or_else(|| parent_name(name));
+        let trust = self.zone_trust(above)?;
+        if let Trust::Insecure { .. } = trust {
+            return Ok((trust, ttl));
+        }
+        let signed = |rrset: &[DNSRecord], sigs: &[RRSIGData]| {
+            sigs.iter().any(|sig| trust.verify(rrset, sig, now).is_ok())
+        };
+
+        // An alias can't be a zone cut.
+        let cnames: Vec<DNSRecord> = response
+            .answers_for(name, DNSRecordType::CNAME)
+            .cloned()
+            .collect();
+        if !cnames.is_empty() {
+            return match signed(
+                &cnames,
+                &sigs_over(&response.answers, name, DNSRecordType::CNAME),
+            ) {
+                true => Ok((trust, ttl)),
+                false => Err(DNSError::Bogus(format!("CNAME for {} isn't signed", name))),
+            };
+        }
+
+        let proof: Vec<DNSRecord> = rrsets(&response.authorities)
+            .into_iter()
+            .filter(|(rrset, sigs)| signed(rrset, sigs))
+            .flat_map(|(rrset, _)| rrset)
+            .collect();
+        match DenialOfExistence::new(&proof).proves_no_ds(name)? {
+            NoDs::NotDelegated => Ok((trust, ttl)),
+            NoDs::InsecureDelegation => Ok((
+                Trust::Insecure {
+                    zone: name.to_string(),
+                },
+                ttl,
+            )),
+        }
+    }
+
+    /// Fetch the keys for `zone` and check them against its DS records.
+    fn zone_keys(&self, zone: &str, ds: &[DSData]) -> Result<(Trust, u32), DNSError> {
+        let usable: Vec<&DSData> = ds
+            .iter()
+            .filter(|ds| {
+                is_supported_algorithm(ds.algorithm) && is_supported_digest(ds.digest_type)
+            })
+            .collect();
+        // Signed in a way we can't check, which is as good as not signed (RFC 4035 5.2).
+        if usable.is_empty() {
+            return Ok((
+                Trust::Insecure {
+                    zone: zone.to_string(),
+                },
+                MAX_CACHE_TTL,
+            ));
+        }
+
+        let response = self.iterate(
+            zone,
+            DNSRecordType::DNSKEY,
+            &mut NameserverLookups::default(),
+        )?;
+        let records: Vec<DNSRecord> = response
+            .answers_for(zone, DNSRecordType::DNSKEY)
+            .cloned()
+            .collect();
+        let keys: Vec<DNSKEYData> = records
+            .iter()
+            .filter_map(|record| match &record.data {
+                RData::DNSKEY(key) => Some(key.clone()),
+                _ => None,
+            })
+            .collect();
+        let sigs = sigs_over(&response.answers, zone, DNSRecordType::DNSKEY);
+
+        // The keys have to be signed by one that a DS record vouches for.
+        let now = unix_time();
+        let vouched_for = keys
+            .iter()
+            .filter(|key| usable.iter().any(|ds| ds.matches(zone, key)))
+            .any(|key| {
+                sigs.iter().any(|sig| {
+                    is_same_name(&sig.signer_name, zone)
+                        && verify_rrsig(&records, sig, key, now).is_ok()
+                })
+            });
+        if !vouched_for {
+            return Err(DNSError::Bogus(format!(
+                "no DNSKEY for {:?} matches its DS records",
+                zone
+            )));
+        }
+        let keys = keys.into_iter().filter(DNSKEYData::is_zone_key).collect();
+        Ok((
+            Trust::Secure {
+                zone: zone.to_string(),
+                keys,
+            },
+            min_ttl(&response),
+        ))
+    }
+}
+
+impl Trust {
+    /// The zone cut this trust was worked out at.
+    fn zone(&self) -> &str {
+        match self {
+            Trust::Secure { zone, .. } | Trust::Insecure { zone } => zone,
+        }
+    }
+
+    /// Check `sig` over `rrset` with the zone's keys.
+    fn verify(&self, rrset: &[DNSRecord], sig: &RRSIGData, now: u32) -> Result<(), DNSError> {
+        let Trust::Secure { zone, keys } = self else {
+            return Err(DNSError::Bogus("the zone isn't signed".into()));
+        };
+        if !is_same_name(zone, &sig.signer_name) {
+            return Err(DNSError::Bogus(format!(
+                "{} isn't the zone {} is in",
+                sig.signer_name, rrset[0].name
+            )));
+        }
+        let mut failure = DNSError::Bogus(format!("{:?} has no key {}", zone, sig.key_tag));
+        for key in keys {
+            match verify_rrsig(rrset, sig, key, now) {
+                Ok(()) => return Ok(()),
+                Err(err) => failure = err,
+            }
+        }
+        Err(failure)
+    }
+}
+
+/// Group records into RRsets, each with the signatures over it.
+fn rrsets(records: &[DNSRecord]) -> Vec<(Vec<DNSRecord>, Vec<RRSIGData>)> {
+    let mut rrsets: Vec<(Vec<DNSRecord>, Vec<RRSIGData>)> = vec![];
+    for record in records
+        .iter()
+        .filter(|record| record.r#type != DNSRecordType::RRSIG)
+    {
+        let existing = rrsets.iter_mut().find(|(rrset, _)| {
+            rrset[0].r#type == record.r#type && is_same_name(&rrset[0].name, &record.name)
+        });
+        match existing {
+            Some((rrset, _)) => rrset.push(record.clone()),
+            None => rrsets.push((
+                vec![record.clone()],
+                sigs_over(records, &record.name, record.r#type),
+            )),
+        }
+    }
+    rrsets
+}
+
+/// The signatures among `records` over the `record_type` records for `name`.
+fn sigs_over(records: &[DNSRecord], name: &str, record_type: DNSRecordType) -> Vec<RRSIGData> {
+    records
+        .iter()
+        .filter(|record| is_same_name(&record.name, name))
+        .filter_map(|record| match &record.data {
+            RData::RRSIG(sig) if sig.type_covered == Int::from(record_type) => Some(sig.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn min_ttl(response: &DNSPacket) -> u32 {
+    response
+        .answers
+        .iter()
+        .chain(response.authorities.iter())
+        .map(|record| record.ttl)
+        .min()
+        .unwrap_or(0)
+}
+
+/// Seconds since the epoch, wrapping around in 2106 like RRSIG times do.
+fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as u32)
+}
+
+/// The labels an RRSIG over `name` should count, which leaves
+/// out the asterisk of a wildcard (RFC 4034 3.1.3).
+fn label_count(name: &str) -> usize {
+    name.split('.')
+        .filter(|label| !label.is_empty())
+        .skip_while(|label| *label == "*")
+        .count()
+}
+
+/// Where `response` says `name` is an alias to, unless the CNAME itself was asked for.
+fn cname_target(response: &DNSPacket, name: &str, record_type: DNSRecordType) -> Option<String> {
+    if record_type == DNSRecordType::CNAME {
+        return None;
+    }
+    response
+        .answers_for(name, DNSRecordType::CNAME)
+        .find_map(|record| match &record.data {
+            RData::CNAME(target) => Some(target.clone()),
+            _ => None,
+        })
+}
+
+/// Throw out what a server for `zone` has no business telling us about
+/// `domain_name`: records from outside its zone, answers that don't follow
+/// from the question, and authority records for unrelated names. NSEC and
+/// NSEC3 records are the exception, since they're owned by the names
+/// either side of the one they prove doesn't exist.
+fn sanitize(response: &mut DNSPacket, zone: &str, domain_name: &str, record_type: DNSRecordType) {
+    let before = response.answers.len() + response.authorities.len() + response.additionals.len();
+    let in_zone = |record: &DNSRecord| is_subdomain(&record.name, zone);
+
+    response.answers.retain(in_zone);
+    let mut names = vec![domain_name.to_string()];
+    while let Some(target) = cname_target(response, names.last().unwrap(), record_type) {
+        if names.iter().any(|name| name.eq_ignore_ascii_case(&target)) {
+            break;
+        }
+        names.push(target);
+    }
+    response
+        .answers
+        .retain(|record| names.iter().any(|name| is_same_name(name, &record.name)));
 
-    loop {
-        trace!("Querying {:?} for {}", nameserver, domain_name);
-        let query = DNSQuery::new(
+    response.authorities.retain(|record| {
+        in_zone(record) && (is_subdomain(domain_name, &record.name) || is_denial(record))
+    });
+    response.additionals.retain(in_zone);
+
+    let after = response.answers.len() + response.authorities.len() + response.additionals.len();
+    if after < before {
+        debug!(
+            "Dropped {} records about {} that a server for {:?} shouldn't be telling us",
+            before - after,
             domain_name,
-            record_type,
-            DNSRecordClass::IN,
-            DNSHeaderFlag::None,
+            zone
         );
-        let response = query.query(nameserver)?;
-
-        if let Some(answer) = response.get_answer() {
-            return Ok((response, answer));
-        }
-        if let Some(nameserver_ip) = response.get_nameserver_ip() {
-            nameserver.set_ip(nameserver_ip);
-        } else if let Some(nameserver_domain) = response.get_nameserver() {
-            let (_, resolved) = resolve(&nameserver_domain, record_type)?;
-            nameserver.set_ip(resolved);
-        } else if let Some(cname_domain) = response.get_cname_record() {
-            let (_, resolved) = resolve(&cname_domain, record_type)?;
-            nameserver.set_ip(resolved);
-        } else {
-            error!(
-                "Couldn't find any of A/AAAA/NS/CNAME records. {:#?}",
-                response
-            );
-            return Err(DNSError::Other);
+    }
+}
+
+fn is_denial(record: &DNSRecord) -> bool {
+    let r#type = match &record.data {
+        RData::RRSIG(sig) => sig.type_covered,
+        _ => Int::from(record.r#type),
+    };
+    r#type == Int::from(DNSRecordType::NSEC) || r#type == Int::from(DNSRecordType::NSEC3)
+}
+
+/// The name one label up from `name`, the root being its own parent.
+fn parent_name(name: &str) -> &str {
+    name.trim_end_matches('.')
+        .split_once('.')
+        .map_or("", |(_, parent)| parent)
+}
+
+/// `name` cut down to one label more than `ancestor`.
+fn one_label_below(ancestor: &str, name: &str) -> String {
+    let ancestor_labels = ancestor
+        .trim_end_matches('.')
+        .split('.')
+        .filter(|label| !label.is_empty())
+        .count();
+    let labels: Vec<&str> = name.trim_end_matches('.').split('.').collect();
+    let keep = (ancestor_labels + 1).min(labels.len());
+    labels[labels.len() - keep..].join(".")
+}
+
+fn is_same_name(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+fn is_strictly_below(name: &str, zone: &str) -> bool {
+    is_subdomain(name, zone) && !is_same_name(name, zone)
+}
+
+/// Whether `name` is `zone` or falls somewhere below it.
+pub(crate) fn is_subdomain(name: &str, zone: &str) -> bool {
+    let name = name.trim_end_matches('.').as_bytes();
+    let zone = zone.trim_end_matches('.').as_bytes();
+    if zone.is_empty() {
+        return true;
+    }
+    let Some(split) = name.len().checked_sub(zone.len()) else {
+        return false;
+    };
+    name[split..].eq_ignore_ascii_case(zone) && (split == 0 || name[split - 1] == b'.')
+}
+
+/// Spell `domain_name` the way it was asked for again wherever the
+/// randomised spelling of it, or of one of its parents, was echoed back.
+fn restore_case(response: &mut DNSPacket, domain_name: &str) {
+    for question in response.questions.iter_mut() {
+        question.name = domain_name.to_string();
+    }
+    for record in response
+        .answers
+        .iter_mut()
+        .chain(response.authorities.iter_mut())
+        .chain(response.additionals.iter_mut())
+    {
+        restore_name_case(&mut record.name, domain_name);
+        for name in record.data.names_mut() {
+            restore_name_case(name, domain_name);
         }
     }
 }
+
+fn restore_name_case(name: &mut String, domain_name: &str) {
+    let original: Vec<&str> = domain_name.trim_end_matches('.').split('.').collect();
+    let labels: Vec<&str> = name.trim_end_matches('.').split('.').collect();
+    let shared = labels
+        .iter()
+        .rev()
+        .zip(original.iter().rev())
+        .take_while(|(label, original)| label.eq_ignore_ascii_case(original))
+        .count();
+    if shared == 0 {
+        return;
+    }
+    let mut restored = labels[..labels.len() - shared].to_vec();
+    restored.extend_from_slice(&original[original.len() - shared..]);
+    *name = restored.join(".");
+}
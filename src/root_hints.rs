@@ -0,0 +1,118 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+
+use crate::dns::DNSError;
+
+/// The thirteen root servers, as published by IANA in `named.root`.
+pub const ROOT_HINTS: [(&str, Ipv4Addr, Ipv6Addr); 13] = [
+    (
+        "a.root-servers.net",
+        Ipv4Addr::new(198, 41, 0, 4),
+        Ipv6Addr::new(0x2001, 0x503, 0xba3e, 0, 0, 0, 0x2, 0x30),
+    ),
+    (
+        "b.root-servers.net",
+        Ipv4Addr::new(170, 247, 170, 2),
+        Ipv6Addr::new(0x2801, 0x1b8, 0x10, 0, 0, 0, 0, 0xb),
+    ),
+    (
+        "c.root-servers.net",
+        Ipv4Addr::new(192, 33, 4, 12),
+        Ipv6Addr::new(0x2001, 0x500, 0x2, 0, 0, 0, 0, 0xc),
+    ),
+    (
+        "d.root-servers.net",
+        Ipv4Addr::new(199, 7, 91, 13),
+        Ipv6Addr::new(0x2001, 0x500, 0x2d, 0, 0, 0, 0, 0xd),
+    ),
+    (
+        "e.root-servers.net",
+        Ipv4Addr::new(192, 203, 230, 10),
+        Ipv6Addr::new(0x2001, 0x500, 0xa8, 0, 0, 0, 0, 0xe),
+    ),
+    (
+        "f.root-servers.net",
+        Ipv4Addr::new(192, 5, 5, 241),
+        Ipv6Addr::new(0x2001, 0x500, 0x2f, 0, 0, 0, 0, 0xf),
+    ),
+    (
+        "g.root-servers.net",
+        Ipv4Addr::new(192, 112, 36, 4),
+        Ipv6Addr::new(0x2001, 0x500, 0x12, 0, 0, 0, 0, 0xd0d),
+    ),
+    (
+        "h.root-servers.net",
+        Ipv4Addr::new(198, 97, 190, 53),
+        Ipv6Addr::new(0x2001, 0x500, 0x1, 0, 0, 0, 0, 0x53),
+    ),
+    (
+        "i.root-servers.net",
+        Ipv4Addr::new(192, 36, 148, 17),
+        Ipv6Addr::new(0x2001, 0x7fe, 0, 0, 0, 0, 0, 0x53),
+    ),
+    (
+        "j.root-servers.net",
+        Ipv4Addr::new(192, 58, 128, 30),
+        Ipv6Addr::new(0x2001, 0x503, 0xc27, 0, 0, 0, 0x2, 0x30),
+    ),
+    (
+        "k.root-servers.net",
+        Ipv4Addr::new(193, 0, 14, 129),
+        Ipv6Addr::new(0x2001, 0x7fd, 0, 0, 0, 0, 0, 0x1),
+    ),
+    (
+        "l.root-servers.net",
+        Ipv4Addr::new(199, 7, 83, 42),
+        Ipv6Addr::new(0x2001, 0x500, 0x9f, 0, 0, 0, 0, 0x42),
+    ),
+    (
+        "m.root-servers.net",
+        Ipv4Addr::new(202, 12, 27, 33),
+        Ipv6Addr::new(0x2001, 0xdc3, 0, 0, 0, 0, 0, 0x35),
+    ),
+];
+
+/// Every address in [ROOT_HINTS], IPv4 first.
+pub fn default_root_servers() -> Vec<SocketAddr> {
+    let v4 = ROOT_HINTS.iter().map(|(_, v4, _)| IpAddr::V4(*v4));
+    let v6 = ROOT_HINTS.iter().map(|(_, _, v6)| IpAddr::V6(*v6));
+    v4.chain(v6).map(|ip| SocketAddr::new(ip, 53)).collect()
+}
+
+/// Pull the root server addresses out of a hints file in the zone file
+/// format of IANA's `named.root`. Only the A and AAAA records matter,
+/// the NS records naming the servers are skipped. IPv4 addresses come
+/// first, in the order they appear in the file.
+pub fn parse_root_hints(contents: &str) -> Result<Vec<SocketAddr>, DNSError> {
+    let mut v4 = vec![];
+    let mut v6 = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or_default();
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let Some(position) = fields
+            .iter()
+            .position(|field| matches!(field.to_ascii_uppercase().as_str(), "A" | "AAAA"))
+        else {
+            continue;
+        };
+        let address = fields.get(position + 1).ok_or_else(|| {
+            DNSError::BadRootHints(format!("line {}: missing address", number + 1))
+        })?;
+        let address: IpAddr = address
+            .parse()
+            .map_err(|err| DNSError::BadRootHints(format!("line {}: {}", number + 1, err)))?;
+        match address {
+            IpAddr::V4(_) => v4.push(SocketAddr::new(address, 53)),
+            IpAddr::V6(_) => v6.push(SocketAddr::new(address, 53)),
+        }
+    }
+    v4.extend(v6);
+    if v4.is_empty() {
+        return Err(DNSError::BadRootHints("no root server addresses".into()));
+    }
+    Ok(v4)
+}
+
+pub fn load_root_hints(path: impl AsRef<Path>) -> Result<Vec<SocketAddr>, DNSError> {
+    parse_root_hints(&std::fs::read_to_string(path)?)
+}
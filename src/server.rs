@@ -0,0 +1,140 @@
+use std::io::Cursor;
+use std::sync::Arc;
+
+use log::{debug, error, info};
+
+use crate::cache::{Cache, CacheKey};
+use crate::dns::{
+    DNSError, DNSHeader, DNSPacket, DNSQuery, DNSQuestion, DNSRecordClass, FromBytes, Opcode,
+    ResponseCode,
+};
+use crate::edns::MIN_UDP_PAYLOAD_SIZE;
+use crate::resolver::Resolver;
+
+pub type Shared<T> = std::sync::Arc<std::sync::Mutex<T>>;
+pub type Database = Shared<Cache>;
+
+/// Work out what to send back for `message`, along with how big the
+/// client said a UDP response may be.
+///
+/// Every query gets a response, even if it only carries an error code.
+/// The exceptions are messages too short to have an ID to reply to, and
+/// messages that are themselves responses, which we never answer so that
+/// we can't be tricked into an endless back and forth with another server.
+pub fn respond(
+    mut message: Cursor<Vec<u8>>,
+    cache: Database,
+    resolver: Arc<Resolver>,
+) -> Option<(DNSPacket, usize)> {
+    let header = match DNSHeader::from_bytes(&mut message) {
+        Ok(header) => header,
+        Err(err) => {
+            error!("Dropping unreadable message: {}", err);
+            return None;
+        }
+    };
+    if header.flags.response {
+        debug!("Dropping unsolicited response {}", header.id);
+        return None;
+    }
+
+    // Read the question on its own first, so a message that only goes
+    // wrong further in can still have it echoed back in the FORMERR.
+    let question = match header.num_questions {
+        0 => None,
+        _ => DNSQuestion::from_bytes(&mut message).ok(),
+    };
+
+    message.set_position(0);
+    let query = match DNSQuery::from_bytes(&mut message) {
+        Ok(query) if header.num_questions == 1 => query,
+        Ok(_) => {
+            error!(
+                "Query {} has {} questions instead of one",
+                header.id, header.num_questions
+            );
+            let packet = DNSPacket::error_response(&header, question, ResponseCode::FormErr);
+            return Some((packet, MIN_UDP_PAYLOAD_SIZE as usize));
+        }
+        Err(err) => {
+            error!("Couldn't parse query {}: {}", header.id, err);
+            let packet = DNSPacket::error_response(&header, question, ResponseCode::FormErr);
+            return Some((packet, MIN_UDP_PAYLOAD_SIZE as usize));
+        }
+    };
+    let limit = query.max_response_size();
+
+    if query.header.flags.opcode != Opcode::Query {
+        error!(
+            "Query {} uses unsupported opcode {:?}",
+            header.id, query.header.flags.opcode
+        );
+        return Some((error_response(&query, ResponseCode::NotImp), limit));
+    }
+    if query.question.class != DNSRecordClass::IN {
+        error!(
+            "Query {} is for unsupported class {:?}",
+            header.id, query.question.class
+        );
+        return Some((error_response(&query, ResponseCode::Refused), limit));
+    }
+
+    match answer_query(&query, cache, &resolver) {
+        Ok(packet) => {
+            let answers: Vec<String> = packet
+                .get_answers()
+                .iter()
+                .filter_map(|record| record.try_get_data_as_string())
+                .collect();
+            info!("Found {:?} for {}", answers, query.question.name);
+            Some((packet, limit))
+        }
+        Err(err) => {
+            error!("Failed to resolve {}: {}", query.question.name, err);
+            Some((error_response(&query, ResponseCode::ServFail), limit))
+        }
+    }
+}
+
+fn error_response(query: &DNSQuery, rcode: ResponseCode) -> DNSPacket {
+    let mut packet = DNSPacket::error_response(&query.header, Some(query.question.clone()), rcode);
+    packet.prepare_response(query);
+    packet
+}
+
+pub fn answer_query(
+    query: &DNSQuery,
+    cache: Database,
+    resolver: &Resolver,
+) -> Result<DNSPacket, DNSError> {
+    let query_id = query.header.id;
+    info!("Resolving {}", query.question.name);
+    let key = CacheKey::from(&query.question);
+    let cached = cache.lock().unwrap().get(&key);
+
+    if let Some(mut packet_cp) = cached {
+        // some packet is stored, with its ttls already brought up to date.
+        // just update ids and send it back.
+        info!(
+            "looked up {} from cache (packet: {:#?})",
+            query.question.name, packet_cp
+        );
+        packet_cp.header.id = query_id;
+        packet_cp.prepare_response(query);
+        return Ok(packet_cp);
+    }
+
+    // Clients that set CD take whatever comes back, so it's kept out of
+    // the cache where it could reach clients that want it checked.
+    let unchecked = query.header.flags.checking_disabled && resolver.config().dnssec_validation;
+    let mut packet = match unchecked {
+        true => resolver.resolve_unchecked(&query.question.name, query.question.r#type)?,
+        false => resolver.resolve(&query.question.name, query.question.r#type)?,
+    };
+    if !unchecked {
+        cache.lock().unwrap().insert(key, packet.clone());
+    }
+    packet.header.id = query_id;
+    packet.prepare_response(query);
+    Ok(packet)
+}
@@ -1,12 +1,14 @@
 use crate::*;
 use std::io::Cursor;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
 use test_case::test_case;
 
 #[test]
 fn test_header_serde() {
     let header = DNSHeader {
         id: 0x1314,
-        flags: DNSHeaderFlag::None,
+        flags: DNSHeaderFlags::NONE,
         num_questions: 1,
         num_additionals: 0,
         num_authorities: 0,
@@ -23,6 +25,70 @@ fn test_header_serde() {
     assert_eq!(deserialized, header);
 }
 
+#[test]
+fn test_header_counts_roundtrip() {
+    let header = DNSHeader {
+        id: 0xbeef,
+        flags: DNSHeaderFlags::RECURSION_DESIRED,
+        num_questions: 1,
+        num_answers: 2,
+        num_authorities: 3,
+        num_additionals: 4,
+    };
+
+    let mut observed = vec![];
+    header.to_bytes(&mut observed).unwrap();
+    let expected = b"\xbe\xef\x01\0\0\x01\0\x02\0\x03\0\x04";
+    assert_eq!(observed, expected.to_vec());
+
+    let mut cursor = Cursor::new(observed);
+    assert_eq!(DNSHeader::from_bytes(&mut cursor).unwrap(), header);
+}
+
+#[test]
+fn test_header_flags_every_bit() {
+    let flags = DNSHeaderFlags {
+        response: true,
+        opcode: Opcode::Status,
+        authoritative: true,
+        truncated: true,
+        recursion_desired: true,
+        recursion_available: true,
+        z: true,
+        authentic_data: true,
+        checking_disabled: true,
+        rcode: ResponseCode::Refused,
+    };
+    assert_eq!(Int::from(flags), 0b1001_0111_1111_0101);
+    assert_eq!(DNSHeaderFlags::from(Int::from(flags)), flags);
+
+    // A typical recursive answer from 8.8.8.8.
+    let flags = DNSHeaderFlags::from(0x8183);
+    assert!(flags.response);
+    assert!(flags.recursion_desired);
+    assert!(flags.recursion_available);
+    assert!(!flags.authoritative);
+    assert!(!flags.truncated);
+    assert_eq!(flags.opcode, Opcode::Query);
+    assert_eq!(flags.rcode, ResponseCode::NXDomain);
+}
+
+#[test_case(0, Opcode::Query)]
+#[test_case(1, Opcode::IQuery)]
+#[test_case(2, Opcode::Status)]
+#[test_case(4, Opcode::Notify)]
+#[test_case(5, Opcode::Update)]
+#[test_case(9, Opcode::Other(9))]
+fn test_opcode_roundtrip(raw: u8, opcode: Opcode) {
+    assert_eq!(Opcode::from(raw), opcode);
+    assert_eq!(u8::from(opcode), raw);
+    let flags = DNSHeaderFlags {
+        opcode,
+        ..DNSHeaderFlags::NONE
+    };
+    assert_eq!(DNSHeaderFlags::from(Int::from(flags)).opcode, opcode);
+}
+
 #[test]
 fn test_encode_and_decode_dns_name() {
     let initial = "google.com";
@@ -56,7 +122,7 @@ fn test_dns_query_new() {
         "example.com",
         DNSRecordType::A,
         DNSRecordClass::IN,
-        DNSHeaderFlag::RecursionDesired,
+        DNSHeaderFlags::RECURSION_DESIRED,
     );
 
     let mut observed = vec![];
@@ -74,7 +140,7 @@ fn test_dns_query_roundtrip() {
         "www.example.com",
         DNSRecordType::A,
         DNSRecordClass::IN,
-        DNSHeaderFlag::RecursionDesired,
+        DNSHeaderFlags::RECURSION_DESIRED,
     );
     let response = query.send_to_8_8_8_8().unwrap();
     assert!(response.ends_with(&[93, 184, 216, 34]));
@@ -82,7 +148,7 @@ fn test_dns_query_roundtrip() {
 
     let mut cursor = Cursor::new(&response[0..12]);
     let response_header = DNSHeader::from_bytes(&mut cursor).unwrap();
-    assert_eq!(response_header.flags, DNSHeaderFlag::Other(33152));
+    assert_eq!(response_header.flags, DNSHeaderFlags::from(33152));
     assert_eq!(response_header.num_questions, 1);
     assert_eq!(response_header.num_answers, 1);
 }
@@ -93,7 +159,7 @@ fn test_dns_query_record_parsing_roundtrip() {
         "www.example.com",
         DNSRecordType::A,
         DNSRecordClass::IN,
-        DNSHeaderFlag::RecursionDesired,
+        DNSHeaderFlags::RECURSION_DESIRED,
     );
     let response = query.send_to_8_8_8_8().unwrap();
     assert!(response.ends_with(&[93, 184, 216, 34]));
@@ -108,7 +174,7 @@ fn test_dns_name_simple() {
         "www.example.com",
         DNSRecordType::A,
         DNSRecordClass::IN,
-        DNSHeaderFlag::RecursionDesired,
+        DNSHeaderFlags::RECURSION_DESIRED,
     );
     let response = query.send_to_8_8_8_8().unwrap();
 
@@ -120,7 +186,7 @@ fn test_dns_name_simple() {
     assert_eq!(question.r#type, DNSRecordType::A);
     assert_eq!(question.name, "www.example.com".to_string());
 
-    assert_eq!(header.flags, DNSHeaderFlag::Other(33152));
+    assert_eq!(header.flags, DNSHeaderFlags::from(33152));
     assert_eq!(header.num_questions, 1);
     assert_eq!(header.num_answers, 1);
 }
@@ -147,7 +213,7 @@ fn test_dns_record_parsing() {
             class: DNSRecordClass::IN
         }
     );
-    assert_eq!(header.flags, DNSHeaderFlag::Other(33152));
+    assert_eq!(header.flags, DNSHeaderFlags::from(33152));
     assert_eq!(header.num_questions, 1);
     assert_eq!(header.num_answers, 1);
     assert_eq!(header.num_authorities, 0);
@@ -162,7 +228,7 @@ fn test_dns_record_parsing() {
             r#type: DNSRecordType::A,
             class: DNSRecordClass::IN,
             ttl: 21147,
-            data: b"]\xb8\xd8\"".to_vec()
+            data: RData::A(Ipv4Addr::new(93, 184, 216, 34))
         }
     );
 }
@@ -173,7 +239,7 @@ fn test_parse_dns_packet() {
         "www.example.com",
         DNSRecordType::A,
         DNSRecordClass::IN,
-        DNSHeaderFlag::RecursionDesired,
+        DNSHeaderFlags::RECURSION_DESIRED,
     );
     let response = query.send_to_8_8_8_8().unwrap();
 
@@ -197,10 +263,13 @@ fn test_parse_dns_packet() {
     assert_eq!(packet.answers[0].class, DNSRecordClass::IN);
     assert_eq!(packet.answers[0].r#type, DNSRecordType::A);
     assert_eq!(packet.answers[0].name, "www.example.com".to_string());
-    assert_eq!(packet.answers[0].data, b"]\xb8\xd8\"".to_vec());
+    assert_eq!(
+        packet.answers[0].data,
+        RData::A(Ipv4Addr::new(93, 184, 216, 34))
+    );
 
     let header = packet.header;
-    assert_eq!(header.flags, DNSHeaderFlag::Other(33152));
+    assert_eq!(header.flags, DNSHeaderFlags::from(33152));
     assert_eq!(header.num_questions, 1);
     assert_eq!(header.num_answers, 1);
     assert_eq!(header.num_authorities, 0);
@@ -215,7 +284,7 @@ fn test_parse_dns_packet_metafilter() {
         "www.metafilter.com",
         DNSRecordType::A,
         DNSRecordClass::IN,
-        DNSHeaderFlag::RecursionDesired,
+        DNSHeaderFlags::RECURSION_DESIRED,
     );
     let response = query.send_to_8_8_8_8().unwrap();
 
@@ -239,10 +308,13 @@ fn test_parse_dns_packet_metafilter() {
     assert_eq!(packet.answers[0].class, DNSRecordClass::IN);
     assert_eq!(packet.answers[0].r#type, DNSRecordType::CNAME);
     assert_eq!(packet.answers[0].name, "www.metafilter.com".to_string());
-    assert_eq!(packet.answers[1].data, [54, 203, 56, 158]);
+    assert_eq!(
+        packet.answers[1].data,
+        RData::A(Ipv4Addr::new(54, 203, 56, 158))
+    );
 
     let header = packet.header;
-    assert_eq!(header.flags, DNSHeaderFlag::Other(33152));
+    assert_eq!(header.flags, DNSHeaderFlags::from(33152));
     assert_eq!(header.num_questions, 1);
     assert_eq!(header.num_answers, 2);
     assert_eq!(header.num_authorities, 0);
@@ -269,18 +341,3461 @@ fn test_resolve() {
 
 #[test]
 fn foo() {
+    let data = vec![
+        6, 111, 114, 97, 110, 103, 101, 4, 106, 118, 110, 115, 2, 99, 97, 0,
+    ];
+    let mut cursor = Cursor::new(&data);
     let record = DNSRecord {
         r#type: DNSRecordType::CNAME,
         name: "visit-before.wizard107.messwithdns.com".to_string(),
         class: DNSRecordClass::IN,
         ttl: 100,
-        data: vec![
-            6, 111, 114, 97, 110, 103, 101, 4, 106, 118, 110, 115, 2, 99, 97, 0,
-        ],
+        data: RData::from_bytes(
+            &mut cursor,
+            DNSRecordType::CNAME,
+            DNSRecordClass::IN,
+            data.len() as u16,
+        )
+        .unwrap(),
     };
 
     assert_eq!(
         record.try_get_data_as_string(),
-        Some("orange.jvns.ca".to_string())
+        Some("orange.jvns.ca.".to_string())
+    );
+}
+
+/// A response for www.metafilter.com whose CNAME target and SOA names
+/// point back into the question and into each other.
+const COMPRESSED_RDATA_RESPONSE: &[u8] = &[
+    0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 1, 0, 0, // header
+    3, b'w', b'w', b'w', 10, b'm', b'e', b't', b'a', b'f', b'i', b'l', b't', b'e', b'r', 3, b'c',
+    b'o', b'm', 0, 0, 1, 0, 1, // question at offset 12
+    0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 6, // answer: CNAME, rdata at 48
+    3, b'c', b'd', b'n', 0xc0, 16, // -> cdn.metafilter.com
+    0xc0, 16, 0, 6, 0, 1, 0, 0, 0, 60, 0, 27, // authority: SOA
+    2, b'n', b's', 0xc0, 48, // -> ns.cdn.metafilter.com
+    0xc0, 16, // -> metafilter.com
+    0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0, 5,
+];
+
+#[test]
+fn test_rdata_names_resolved_against_message() {
+    let mut cursor = Cursor::new(COMPRESSED_RDATA_RESPONSE);
+    let packet = DNSPacket::from_bytes(&mut cursor).unwrap();
+
+    assert_eq!(
+        packet.answers[0].data,
+        RData::CNAME("cdn.metafilter.com".to_string())
+    );
+    assert_eq!(
+        packet.authorities[0].data,
+        RData::SOA(SOAData {
+            mname: "ns.cdn.metafilter.com".to_string(),
+            rname: "metafilter.com".to_string(),
+            serial: 1,
+            refresh: 2,
+            retry: 3,
+            expire: 4,
+            minimum: 5,
+        })
+    );
+    assert_eq!(
+        packet.get_cname_record(),
+        Some("cdn.metafilter.com".to_string())
+    );
+}
+
+#[test_case(DNSRecordType::A, RData::A(Ipv4Addr::new(10, 0, 0, 1)); "a")]
+#[test_case(DNSRecordType::AAAA, RData::AAAA(Ipv6Addr::LOCALHOST); "aaaa")]
+#[test_case(DNSRecordType::NS, RData::NS("ns1.example.com".into()); "ns")]
+#[test_case(DNSRecordType::PTR, RData::PTR("host.example.com".into()); "ptr")]
+#[test_case(DNSRecordType::TXT, RData::TXT(vec![b"v=spf1 -all".to_vec(), vec![]]); "txt")]
+#[test_case(DNSRecordType::MX, RData::MX(MXData { preference: 10, exchange: "mail.example.com".into() }); "mx")]
+#[test_case(DNSRecordType::SRV, RData::SRV(SRVData { priority: 1, weight: 2, port: 5060, target: "sip.example.com".into() }); "srv")]
+#[test_case(DNSRecordType::NAPTR, RData::NAPTR(sip_naptr()); "naptr")]
+#[test_case(DNSRecordType::CAA, RData::CAA(CAAData { flags: 128, tag: "issue".into(), value: b"ca.example.net; account=230123".to_vec() }); "caa")]
+#[test_case(DNSRecordType::DS, RData::DS(DSData { key_tag: 20326, algorithm: 8, digest_type: 2, digest: vec![0xe0; 32] }); "ds")]
+#[test_case(DNSRecordType::DNSKEY, RData::DNSKEY(DNSKEYData { flags: 257, protocol: 3, algorithm: 13, public_key: vec![7; 64] }); "dnskey")]
+#[test_case(DNSRecordType::RRSIG, RData::RRSIG(dnssec_rrsig()); "rrsig")]
+#[test_case(DNSRecordType::NSEC, RData::NSEC(NSECData { next_domain_name: "host.example.com".into(), types: vec![1, 15, 46, 47, 1234] }); "nsec")]
+#[test_case(DNSRecordType::NSEC3, RData::NSEC3(dnssec_nsec3()); "nsec3")]
+#[test_case(DNSRecordType::NSEC3PARAM, RData::NSEC3PARAM(NSEC3PARAMData { hash_algorithm: 1, flags: 0, iterations: 0, salt: vec![] }); "nsec3param")]
+#[test_case(DNSRecordType::Unknown(65), RData::Unknown(vec![0, 1, 0, 0, 1, 0, 3, 2, b'h', b'2']); "unknown")]
+fn test_rdata_roundtrip(r#type: DNSRecordType, data: RData) {
+    let record = DNSRecord {
+        name: "example.com".to_string(),
+        r#type,
+        class: DNSRecordClass::IN,
+        ttl: 300,
+        data,
+    };
+
+    let mut bytes = vec![];
+    let written = record.to_bytes(&mut bytes).unwrap();
+    assert_eq!(written, bytes.len());
+
+    let mut cursor = Cursor::new(bytes);
+    assert_eq!(DNSRecord::from_bytes(&mut cursor).unwrap(), record);
+}
+
+#[test_case(b"is sue"; "space")]
+#[test_case(&[0xff]; "not utf8")]
+#[test_case(b""; "empty")]
+fn test_caa_with_bad_tag_is_kept_unknown(tag: &[u8]) {
+    let mut rdata = vec![0, tag.len() as u8];
+    rdata.extend(tag);
+    rdata.extend(b"ca.example.net");
+    let record = DNSRecord {
+        name: "example.com".to_string(),
+        r#type: DNSRecordType::CAA,
+        class: DNSRecordClass::IN,
+        ttl: 300,
+        data: RData::Unknown(rdata.clone()),
+    };
+
+    let mut bytes = vec![];
+    record.to_bytes(&mut bytes).unwrap();
+    let mut cursor = Cursor::new(bytes);
+    let parsed = DNSRecord::from_bytes(&mut cursor).unwrap();
+    assert_eq!(parsed, record);
+    assert!(parsed
+        .data
+        .to_string()
+        .starts_with(&format!("\\# {} ", rdata.len())));
+}
+
+#[test]
+fn test_rdata_length_mismatch() {
+    // An A record that claims five bytes of RDATA.
+    let bytes = [0, 0, 1, 0, 1, 0, 0, 0, 60, 0, 5, 10, 0, 0, 1, 0];
+    let mut cursor = Cursor::new(&bytes[..]);
+    assert!(matches!(
+        DNSRecord::from_bytes(&mut cursor),
+        Err(DNSError::RDataLengthMismatch {
+            expected: 5,
+            found: 4
+        })
+    ));
+}
+
+/// A CHAOS class response with an HTTPS record (type 65) in the answer
+/// section and a type 65280 record from the private use range after it.
+const UNKNOWN_TYPES_RESPONSE: &[u8] = &[
+    0x12, 0x34, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0, // header
+    7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0, 65, 0,
+    3, // question
+    0xc0, 12, 0, 65, 0, 3, 0, 0, 0, 60, 0, 10, // answer: HTTPS
+    0, 1, 0, 0, 1, 0, 3, 2, b'h', b'2', // priority 1, target ".", alpn=h2
+    0xc0, 12, 0xff, 0, 0, 3, 0, 0, 0, 60, 0, 0, // answer: TYPE65280, no RDATA
+];
+
+#[test]
+fn test_unknown_types_and_classes_pass_through() {
+    let mut cursor = Cursor::new(UNKNOWN_TYPES_RESPONSE);
+    let packet = DNSPacket::from_bytes(&mut cursor).unwrap();
+
+    assert_eq!(packet.questions[0].r#type, DNSRecordType::Unknown(65));
+    assert_eq!(packet.questions[0].class, DNSRecordClass::CH);
+    assert_eq!(
+        packet.answers[0].data,
+        RData::Unknown(vec![0, 1, 0, 0, 1, 0, 3, 2, b'h', b'2'])
+    );
+    assert_eq!(packet.answers[1].r#type, DNSRecordType::Unknown(65280));
+    assert_eq!(packet.answers[1].data, RData::Unknown(vec![]));
+
+    let mut bytes = vec![];
+    packet.to_bytes(&mut bytes).unwrap();
+    assert_eq!(bytes, UNKNOWN_TYPES_RESPONSE);
+}
+
+#[test]
+fn test_record_type_and_class_numbers() {
+    for value in 0..=Int::MAX {
+        assert_eq!(Int::from(DNSRecordType::from(value)), value);
+        assert_eq!(Int::from(DNSRecordClass::from(value)), value);
+    }
+    assert_eq!(DNSRecordType::from(15), DNSRecordType::MX);
+    assert_eq!(DNSRecordType::from(65), DNSRecordType::Unknown(65));
+    assert_eq!(DNSRecordClass::from(254), DNSRecordClass::Unknown(254));
+}
+
+#[test_case(DNSRecordClass::CH; "chaos")]
+#[test_case(DNSRecordClass::HS; "hesiod")]
+#[test_case(DNSRecordClass::Unknown(254); "unknown class")]
+fn test_class_specific_rdata_outside_in_is_opaque(class: DNSRecordClass) {
+    // Like a Chaosnet A record: a domain name and a 16-bit address.
+    let record = DNSRecord {
+        name: "example.com".to_string(),
+        r#type: DNSRecordType::A,
+        class,
+        ttl: 60,
+        data: RData::Unknown(vec![2, b'm', b'x', 0, 0o1, 0o2]),
+    };
+    let mut packet = response(
+        "example.com",
+        DNSRecordType::A,
+        vec![record.clone()],
+        vec![],
+        vec![],
+    );
+    packet.questions[0].class = class;
+
+    let mut bytes = vec![];
+    packet.to_bytes(&mut bytes).unwrap();
+    let parsed = DNSPacket::from_bytes(&mut Cursor::new(bytes)).unwrap();
+    assert_eq!(parsed.answers, [record]);
+}
+
+#[test]
+fn test_naptr_outside_in_is_decoded() {
+    // NAPTR's layout is the same in every class (RFC 3403 4.1).
+    let record = DNSRecord {
+        name: "example.com".to_string(),
+        r#type: DNSRecordType::NAPTR,
+        class: DNSRecordClass::CH,
+        ttl: 60,
+        data: RData::NAPTR(sip_naptr()),
+    };
+
+    let mut bytes = vec![];
+    record.to_bytes(&mut bytes).unwrap();
+    let parsed = DNSRecord::from_bytes(&mut Cursor::new(bytes)).unwrap();
+    assert_eq!(parsed, record);
+}
+
+#[test]
+fn test_unknown_compares_by_number() {
+    assert_eq!(DNSRecordType::Unknown(1), DNSRecordType::A);
+    assert_ne!(DNSRecordType::Unknown(2), DNSRecordType::A);
+    assert_eq!(DNSRecordClass::Unknown(1), DNSRecordClass::IN);
+
+    let mut cache = Cache::new();
+    cache.insert(
+        CacheKey::new("example.com", DNSRecordType::A, DNSRecordClass::IN),
+        a_response("example.com", 300),
+    );
+    let key = CacheKey::new(
+        "example.com",
+        DNSRecordType::Unknown(1),
+        DNSRecordClass::Unknown(1),
+    );
+    assert!(cache.get(&key).is_some());
+
+    let packet = a_response("example.com", 300);
+    assert_eq!(
+        packet
+            .answers_for("example.com", DNSRecordType::Unknown(1))
+            .count(),
+        1
+    );
+}
+
+#[test_case("MX", DNSRecordType::MX; "mnemonic")]
+#[test_case("nsec3param", DNSRecordType::NSEC3PARAM; "lowercase")]
+#[test_case("TYPE1", DNSRecordType::A; "generic form of a known type")]
+#[test_case("TYPE65", DNSRecordType::Unknown(65); "generic form")]
+fn test_parse_record_type(mnemonic: &str, expected: DNSRecordType) {
+    assert_eq!(mnemonic.parse::<DNSRecordType>().unwrap(), expected);
+}
+
+#[test_case("HTTPS"; "unsupported mnemonic")]
+#[test_case("TYPE65536"; "out of range")]
+#[test_case("TYPE"; "no number")]
+fn test_parse_bad_record_type(mnemonic: &str) {
+    assert!(matches!(
+        mnemonic.parse::<DNSRecordType>(),
+        Err(DNSError::UnknownMnemonic(_))
+    ));
+}
+
+#[test]
+fn test_packet_compression_matches_upstream() {
+    let mut cursor = Cursor::new(COMPRESSED_RDATA_RESPONSE);
+    let packet = DNSPacket::from_bytes(&mut cursor).unwrap();
+
+    let mut observed = vec![];
+    let written = packet.to_bytes(&mut observed).unwrap();
+    assert_eq!(written, observed.len());
+    assert_eq!(observed, COMPRESSED_RDATA_RESPONSE);
+}
+
+#[test]
+fn test_packet_compression_roundtrip() {
+    let record = |name: &str, r#type, data| DNSRecord {
+        name: name.to_string(),
+        r#type,
+        class: DNSRecordClass::IN,
+        ttl: 60,
+        data,
+    };
+    let packet = DNSPacket {
+        header: DNSHeader {
+            id: 1,
+            flags: DNSHeaderFlags::from(0x8180),
+            num_questions: 1,
+            num_answers: 2,
+            num_authorities: 1,
+            num_additionals: 1,
+        },
+        questions: vec![DNSQuestion::new(
+            "www.example.com",
+            DNSRecordType::A,
+            DNSRecordClass::IN,
+        )],
+        answers: vec![
+            record(
+                "www.example.com",
+                DNSRecordType::CNAME,
+                RData::CNAME("cdn.example.com".into()),
+            ),
+            record(
+                "cdn.example.com",
+                DNSRecordType::A,
+                RData::A(Ipv4Addr::new(10, 0, 0, 1)),
+            ),
+        ],
+        authorities: vec![record(
+            "example.com",
+            DNSRecordType::MX,
+            RData::MX(MXData {
+                preference: 10,
+                exchange: "mail.example.com".into(),
+            }),
+        )],
+        additionals: vec![record(
+            "mail.example.com",
+            DNSRecordType::A,
+            RData::A(Ipv4Addr::new(10, 0, 0, 2)),
+        )],
+        edns: None,
+    };
+
+    let mut compressed = vec![];
+    packet.to_bytes(&mut compressed).unwrap();
+
+    let mut uncompressed = vec![];
+    packet.header.to_bytes(&mut uncompressed).unwrap();
+    packet.questions[0].to_bytes(&mut uncompressed).unwrap();
+    for record in packet
+        .answers
+        .iter()
+        .chain(&packet.authorities)
+        .chain(&packet.additionals)
+    {
+        record.to_bytes(&mut uncompressed).unwrap();
+    }
+    assert!(compressed.len() < uncompressed.len());
+
+    // The first answer's owner is the question name, right after the header.
+    assert_eq!(compressed[33..35], [0xc0, 12]);
+
+    let mut cursor = Cursor::new(compressed);
+    let decoded = DNSPacket::from_bytes(&mut cursor).unwrap();
+    assert_eq!(decoded.questions, packet.questions);
+    assert_eq!(decoded.answers, packet.answers);
+    assert_eq!(decoded.authorities, packet.authorities);
+    assert_eq!(decoded.additionals, packet.additionals);
+}
+
+#[test]
+fn test_compressor_reuses_longest_suffix() {
+    let mut buf = vec![];
+    let mut compressor = encode::Compressor::default();
+    assert_eq!(compressor.dns_name(&mut buf, "example.com").unwrap(), 13);
+    assert_eq!(
+        compressor.dns_name(&mut buf, "mail.example.com").unwrap(),
+        7
+    );
+    assert_eq!(
+        compressor.dns_name(&mut buf, "mail.example.com").unwrap(),
+        2
+    );
+    assert_eq!(compressor.dns_name(&mut buf, "").unwrap(), 1);
+    assert_eq!(
+        buf,
+        b"\x07example\x03com\0\x04mail\xc0\0\xc0\x0d\0".to_vec()
+    );
+}
+
+#[test]
+fn test_encode_rejects_overlong_label() {
+    let name = format!("{}.example.com", "a".repeat(64));
+    assert!(matches!(
+        encode::dns_name(&mut vec![], &name),
+        Err(DNSError::DomainNameHasTooLongPart { part, .. }) if part.len() == 64
+    ));
+
+    let mut buf = vec![];
+    assert!(matches!(
+        encode::Compressor::default().dns_name(&mut buf, &name),
+        Err(DNSError::DomainNameHasTooLongPart { .. })
+    ));
+    assert!(buf.is_empty());
+
+    // 63 is still fine.
+    let name = format!("{}.example.com", "a".repeat(63));
+    assert_eq!(encode::dns_name(&mut vec![], &name).unwrap(), 77);
+}
+
+#[test]
+fn test_encode_rejects_overlong_name() {
+    // Four labels of 63 take up 4 * 64 + 1 = 257 bytes.
+    let name = vec!["a".repeat(63); 4].join(".");
+    assert!(matches!(
+        encode::dns_name(&mut vec![], &name),
+        Err(DNSError::DomainNameTooLong(257))
+    ));
+
+    let mut buf = vec![];
+    assert!(matches!(
+        encode::Compressor::default().dns_name(&mut buf, &name),
+        Err(DNSError::DomainNameTooLong(257))
+    ));
+    assert!(buf.is_empty());
+
+    // Right at the limit: three labels of 63 and one of 61.
+    let name = format!("{}.{}", vec!["a".repeat(63); 3].join("."), "a".repeat(61));
+    assert_eq!(encode::dns_name(&mut vec![], &name).unwrap(), 255);
+}
+
+/// A header announcing one question followed by `name` as its QNAME.
+fn packet_with_question_name(name: &[u8]) -> Vec<u8> {
+    let mut packet = b"\x12\x34\x01\0\0\x01\0\0\0\0\0\0".to_vec();
+    packet.extend_from_slice(name);
+    packet.extend_from_slice(b"\0\x01\0\x01");
+    packet
+}
+
+#[test]
+fn test_decode_rejects_self_referencing_pointer() {
+    let packet = packet_with_question_name(b"\xc0\x0c");
+    let mut cursor = Cursor::new(packet);
+    assert!(matches!(
+        DNSPacket::from_bytes(&mut cursor),
+        Err(DNSError::NamePointerLoop {
+            pointer: 12,
+            position: 12
+        })
+    ));
+}
+
+#[test]
+fn test_decode_rejects_pointer_loop_through_labels() {
+    // "a" followed by a pointer back to the "a".
+    let packet = packet_with_question_name(b"\x01a\xc0\x0c");
+    let mut cursor = Cursor::new(packet);
+    assert!(matches!(
+        DNSPacket::from_bytes(&mut cursor),
+        Err(DNSError::NamePointerLoop {
+            pointer: 12,
+            position: 14
+        })
+    ));
+}
+
+#[test]
+fn test_decode_follows_chained_pointers() {
+    // "c" -> question name, then "d" -> the pointer inside "c"'s name.
+    let mut packet = packet_with_question_name(b"\x01a\x01b\0");
+    let first = packet.len() as u64;
+    packet.extend_from_slice(&[0x01, b'c', 0xc0, 12]);
+    packet.extend_from_slice(&[0x01, b'd', 0xc0, first as u8 + 2]);
+
+    let mut cursor = Cursor::new(packet);
+    cursor.set_position(first + 4);
+    let (name, size) = decode::dns_name(&mut cursor).unwrap();
+    assert_eq!(name, "d.a.b");
+    assert_eq!(size, 4);
+}
+
+#[test]
+fn test_decode_rejects_forward_pointer() {
+    let packet = packet_with_question_name(b"\xc0\x10");
+    let mut cursor = Cursor::new(packet);
+    assert!(matches!(
+        DNSPacket::from_bytes(&mut cursor),
+        Err(DNSError::ForwardNamePointer {
+            pointer: 0x10,
+            position: 12
+        })
+    ));
+}
+
+#[test]
+fn test_decode_rejects_pointer_outside_message() {
+    let packet = packet_with_question_name(b"\xff\xff");
+    let mut cursor = Cursor::new(packet);
+    assert!(matches!(
+        DNSPacket::from_bytes(&mut cursor),
+        Err(DNSError::NamePointerOutOfBounds {
+            pointer: 0x3fff,
+            message_length: 18
+        })
+    ));
+}
+
+#[test]
+fn test_decode_rejects_overlong_name() {
+    let mut name = vec![];
+    for _ in 0..4 {
+        name.push(63);
+        name.extend_from_slice(&[b'a'; 63]);
+    }
+    name.push(0);
+    let packet = packet_with_question_name(&name);
+    let mut cursor = Cursor::new(packet);
+    assert!(matches!(
+        DNSPacket::from_bytes(&mut cursor),
+        Err(DNSError::DomainNameTooLong(257))
+    ));
+
+    // Compression doesn't get around the limit either.
+    let mut packet = packet_with_question_name(&name[64..]);
+    let tail = packet.len() as u64;
+    packet.extend_from_slice(&name[..64]);
+    packet.extend_from_slice(&[0xc0, 12]);
+    let mut cursor = Cursor::new(packet);
+    cursor.set_position(tail);
+    assert!(matches!(
+        decode::dns_name(&mut cursor),
+        Err(DNSError::DomainNameTooLong(257))
+    ));
+}
+
+#[test_case(0x40; "extended label type")]
+#[test_case(0x7f; "label over 63 bytes")]
+#[test_case(0x80; "unallocated label type")]
+fn test_decode_rejects_reserved_label_types(length: u8) {
+    let packet = packet_with_question_name(&[length, 0]);
+    let mut cursor = Cursor::new(packet);
+    assert!(matches!(
+        DNSPacket::from_bytes(&mut cursor),
+        Err(DNSError::BadLabelType(observed)) if observed == length
+    ));
+}
+
+#[test]
+fn test_decode_reports_bytes_taken_at_position() {
+    let mut cursor = Cursor::new(COMPRESSED_RDATA_RESPONSE);
+    // The SOA's MNAME: "ns" and a pointer.
+    cursor.set_position(66);
+    let (name, size) = decode::dns_name(&mut cursor).unwrap();
+    assert_eq!(name, "ns.cdn.metafilter.com");
+    assert_eq!(size, 5);
+    assert_eq!(cursor.position(), 71);
+}
+
+#[test]
+fn test_query_advertises_edns() {
+    let mut edns = Edns::new(DEFAULT_EDNS_PAYLOAD_SIZE);
+    edns.dnssec_ok = true;
+    edns.options.push(EdnsOption {
+        code: 10,
+        data: b"\x01\x02\x03\x04\x05\x06\x07\x08".to_vec(),
+    });
+    let query = DNSQuery::new(
+        "example.com",
+        DNSRecordType::A,
+        DNSRecordClass::IN,
+        DNSHeaderFlags::RECURSION_DESIRED,
+    )
+    .with_edns(edns.clone());
+
+    let mut observed = vec![];
+    query.to_bytes(&mut observed).unwrap();
+    let expected_tail = b"\x01\0\0\x01\0\0\0\0\0\x01\x07example\x03com\0\0\x01\0\x01\
+        \0\0\x29\x04\xd0\0\0\x80\0\0\x0c\0\x0a\0\x08\x01\x02\x03\x04\x05\x06\x07\x08";
+    assert!(observed.ends_with(expected_tail));
+
+    let mut cursor = Cursor::new(observed);
+    let parsed = DNSQuery::from_bytes(&mut cursor).unwrap();
+    assert_eq!(parsed.question, query.question);
+    assert_eq!(parsed.edns, Some(edns));
+    assert_eq!(parsed.max_response_size(), 1232);
+}
+
+#[test_case(None, 512; "no edns")]
+#[test_case(Some(100), 512; "too small")]
+#[test_case(Some(4096), 4096; "large")]
+fn test_query_max_response_size(advertised: Option<Int>, expected: usize) {
+    let mut query = DNSQuery::new(
+        "example.com",
+        DNSRecordType::A,
+        DNSRecordClass::IN,
+        DNSHeaderFlags::NONE,
+    );
+    query.edns = advertised.map(Edns::new);
+    assert_eq!(query.max_response_size(), expected);
+}
+
+#[test]
+fn test_packet_opt_record_is_set_aside() {
+    let mut packet = COMPRESSED_RDATA_RESPONSE.to_vec();
+    packet[11] = 1; // one additional
+
+    // Extended RCODE 1, so BADVERS (16) with the header's NOERROR.
+    packet.extend_from_slice(b"\0\0\x29\x10\0\x01\0\0\0\0\0");
+
+    let mut cursor = Cursor::new(packet.clone());
+    let parsed = DNSPacket::from_bytes(&mut cursor).unwrap();
+    assert!(parsed.additionals.is_empty());
+    let edns = parsed.edns.as_ref().unwrap();
+    assert_eq!(edns.udp_payload_size, 4096);
+    assert_eq!(edns.extended_rcode, 1);
+    assert_eq!(parsed.rcode(), ResponseCode::Other(16));
+
+    let mut observed = vec![];
+    parsed.to_bytes(&mut observed).unwrap();
+    assert_eq!(observed, packet);
+}
+
+#[test]
+fn test_packet_rejects_two_opt_records() {
+    let mut packet = COMPRESSED_RDATA_RESPONSE.to_vec();
+    packet[11] = 2;
+    packet.extend_from_slice(b"\0\0\x29\x10\0\0\0\0\0\0\0");
+    packet.extend_from_slice(b"\0\0\x29\x10\0\0\0\0\0\0\0");
+
+    let mut cursor = Cursor::new(packet);
+    assert!(matches!(
+        DNSPacket::from_bytes(&mut cursor),
+        Err(DNSError::MultipleOptRecords)
+    ));
+}
+
+#[test]
+fn test_packet_truncated_to_limit() {
+    let mut cursor = Cursor::new(COMPRESSED_RDATA_RESPONSE);
+    let mut packet = DNSPacket::from_bytes(&mut cursor).unwrap();
+    packet.edns = Some(Edns::new(DEFAULT_EDNS_PAYLOAD_SIZE));
+    packet.additionals = (0..40)
+        .map(|index| DNSRecord {
+            name: format!("ns{}.metafilter.com", index),
+            r#type: DNSRecordType::A,
+            class: DNSRecordClass::IN,
+            ttl: 60,
+            data: RData::A(Ipv4Addr::new(10, 0, 0, index)),
+        })
+        .collect();
+
+    let full = packet.to_bytes_with_limit(usize::MAX).unwrap();
+    assert!(full.len() > 512);
+
+    // Dropping the glue is enough to fit.
+    let trimmed = packet.to_bytes_with_limit(512).unwrap();
+    let parsed = DNSPacket::from_bytes(&mut Cursor::new(trimmed)).unwrap();
+    assert!(!parsed.header.flags.truncated);
+    assert!(parsed.additionals.is_empty());
+    assert_eq!(parsed.answers.len(), 1);
+    assert_eq!(parsed.authorities.len(), 1);
+    assert!(parsed.edns.is_some());
+
+    // Not even the answers fit.
+    let truncated = packet.to_bytes_with_limit(60).unwrap();
+    let parsed = DNSPacket::from_bytes(&mut Cursor::new(truncated)).unwrap();
+    assert!(parsed.header.flags.truncated);
+    assert!(parsed.answers.is_empty());
+    assert!(parsed.authorities.is_empty());
+    assert_eq!(parsed.questions, packet.questions);
+}
+
+#[test]
+fn test_tcp_framing_roundtrip() {
+    let mut stream = vec![];
+    assert_eq!(write_framed(&mut stream, b"first").unwrap(), 7);
+    write_framed(&mut stream, b"").unwrap();
+    write_framed(&mut stream, COMPRESSED_RDATA_RESPONSE).unwrap();
+    assert_eq!(stream[..7], *b"\0\x05first");
+
+    let mut cursor = Cursor::new(stream);
+    assert_eq!(read_framed(&mut cursor).unwrap(), b"first");
+    assert_eq!(read_framed(&mut cursor).unwrap(), b"");
+    assert_eq!(read_framed(&mut cursor).unwrap(), COMPRESSED_RDATA_RESPONSE);
+    assert!(read_framed(&mut cursor).is_err());
+}
+
+/// Answer one query over UDP with TC set and nothing in it,
+/// then answer it properly over TCP on the same port.
+fn spawn_truncating_server() -> std::net::SocketAddr {
+    use std::net::{TcpListener, UdpSocket};
+
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = udp.local_addr().unwrap();
+    let tcp = TcpListener::bind(addr).unwrap();
+
+    let respond = |query: &[u8], truncated: bool| {
+        let query = DNSQuery::from_bytes(&mut Cursor::new(query)).unwrap();
+        let mut cursor = Cursor::new(COMPRESSED_RDATA_RESPONSE);
+        let mut packet = DNSPacket::from_bytes(&mut cursor).unwrap();
+        packet.header.id = query.header.id;
+        if truncated {
+            packet.header.flags.truncated = true;
+            packet.answers.clear();
+            packet.authorities.clear();
+        }
+        let mut response = vec![];
+        packet.to_bytes(&mut response).unwrap();
+        response
+    };
+
+    std::thread::spawn(move || {
+        let mut buf = [0; 512];
+        let (size, sender) = udp.recv_from(&mut buf).unwrap();
+        udp.send_to(&respond(&buf[..size], true), sender).unwrap();
+
+        let (mut stream, _) = tcp.accept().unwrap();
+        let query = read_framed(&mut stream).unwrap();
+        write_framed(&mut stream, &respond(&query, false)).unwrap();
+    });
+    addr
+}
+
+#[test]
+fn test_query_retries_truncated_response_over_tcp() {
+    let server = spawn_truncating_server();
+    let query = DNSQuery::new(
+        "www.metafilter.com",
+        DNSRecordType::A,
+        DNSRecordClass::IN,
+        DNSHeaderFlags::NONE,
+    );
+
+    let response = query.query(server).unwrap();
+    assert!(!response.header.flags.truncated);
+    assert_eq!(response.header.id, query.header.id);
+    assert_eq!(
+        response.answers[0].data,
+        RData::CNAME("cdn.metafilter.com".into())
+    );
+}
+
+#[test]
+fn test_query_times_out_on_silent_server() {
+    // Bound but never read from, so nothing ever answers.
+    let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let query = DNSQuery::new(
+        "example.com",
+        DNSRecordType::A,
+        DNSRecordClass::IN,
+        DNSHeaderFlags::NONE,
+    );
+
+    let started = std::time::Instant::now();
+    let err = query
+        .query_with_timeout(silent.local_addr().unwrap(), Duration::from_millis(100))
+        .unwrap_err();
+    assert!(err.is_timeout(), "{:?}", err);
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
+
+fn record(name: &str, ttl: u32, data: RData) -> DNSRecord {
+    let r#type = match &data {
+        RData::A(_) => DNSRecordType::A,
+        RData::AAAA(_) => DNSRecordType::AAAA,
+        RData::NS(_) => DNSRecordType::NS,
+        RData::CNAME(_) => DNSRecordType::CNAME,
+        RData::SOA(_) => DNSRecordType::SOA,
+        RData::TXT(_) => DNSRecordType::TXT,
+        RData::MX(_) => DNSRecordType::MX,
+        RData::PTR(_) => DNSRecordType::PTR,
+        RData::SRV(_) => DNSRecordType::SRV,
+        RData::NAPTR(_) => DNSRecordType::NAPTR,
+        RData::CAA(_) => DNSRecordType::CAA,
+        RData::DS(_) => DNSRecordType::DS,
+        RData::RRSIG(_) => DNSRecordType::RRSIG,
+        RData::NSEC(_) => DNSRecordType::NSEC,
+        RData::DNSKEY(_) => DNSRecordType::DNSKEY,
+        RData::NSEC3(_) => DNSRecordType::NSEC3,
+        RData::NSEC3PARAM(_) => DNSRecordType::NSEC3PARAM,
+        RData::OPT(_) | RData::Unknown(_) => unreachable!("not used in tests"),
+    };
+    DNSRecord {
+        name: name.to_string(),
+        r#type,
+        class: DNSRecordClass::IN,
+        ttl,
+        data,
+    }
+}
+
+/// A response to `name`/`type` with the given sections.
+fn response(
+    name: &str,
+    r#type: DNSRecordType,
+    answers: Vec<DNSRecord>,
+    authorities: Vec<DNSRecord>,
+    additionals: Vec<DNSRecord>,
+) -> DNSPacket {
+    DNSPacket {
+        header: DNSHeader {
+            id: 0,
+            flags: DNSHeaderFlags {
+                response: true,
+                ..DNSHeaderFlags::NONE
+            },
+            num_questions: 1,
+            num_answers: answers.len() as Int,
+            num_authorities: authorities.len() as Int,
+            num_additionals: additionals.len() as Int,
+        },
+        questions: vec![DNSQuestion::new(name, r#type, DNSRecordClass::IN)],
+        answers,
+        authorities,
+        additionals,
+        edns: None,
+    }
+}
+
+fn a_key(name: &str) -> CacheKey {
+    CacheKey::new(name, DNSRecordType::A, DNSRecordClass::IN)
+}
+
+#[test]
+fn test_cache_uses_smallest_ttl_and_counts_down() {
+    let now = std::time::Instant::now();
+    let mut cache = Cache::new();
+    let packet = response(
+        "www.example.com",
+        DNSRecordType::A,
+        vec![
+            record("www.example.com", 300, RData::CNAME("example.com".into())),
+            record("example.com", 60, RData::A(Ipv4Addr::new(10, 0, 0, 1))),
+        ],
+        vec![],
+        vec![],
+    );
+    cache.insert_at(a_key("www.example.com"), packet, now);
+
+    let fresh = cache.get_at(&a_key("www.example.com"), now).unwrap();
+    assert_eq!(fresh.answers[0].ttl, 300);
+    assert_eq!(fresh.answers[1].ttl, 60);
+
+    let later = cache
+        .get_at(&a_key("www.example.com"), now + Duration::from_secs(45))
+        .unwrap();
+    assert_eq!(later.answers[0].ttl, 255);
+    assert_eq!(later.answers[1].ttl, 15);
+
+    // Gone as soon as the shortest TTL runs out.
+    assert!(cache
+        .get_at(&a_key("www.example.com"), now + Duration::from_secs(60))
+        .is_none());
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn test_cache_skips_uncacheable_packets() {
+    let now = std::time::Instant::now();
+    let mut cache = Cache::new();
+    let zero_ttl = response(
+        "example.com",
+        DNSRecordType::A,
+        vec![record(
+            "example.com",
+            0,
+            RData::A(Ipv4Addr::new(10, 0, 0, 1)),
+        )],
+        vec![],
+        vec![],
+    );
+    cache.insert_at(a_key("example.com"), zero_ttl, now);
+    let empty = response("example.org", DNSRecordType::A, vec![], vec![], vec![]);
+    cache.insert_at(a_key("example.org"), empty, now);
+    assert!(cache.is_empty());
+
+    // Very long TTLs are capped.
+    let forever = response(
+        "example.net",
+        DNSRecordType::A,
+        vec![record(
+            "example.net",
+            u32::MAX,
+            RData::A(Ipv4Addr::new(10, 0, 0, 1)),
+        )],
+        vec![],
+        vec![],
     );
+    cache.insert_at(a_key("example.net"), forever, now);
+    let after_cap = now + Duration::from_secs(MAX_CACHE_TTL as u64);
+    assert!(cache.get_at(&a_key("example.net"), after_cap).is_none());
+}
+
+#[test]
+fn test_cache_remove_expired() {
+    let now = std::time::Instant::now();
+    let mut cache = Cache::new();
+    for (name, ttl) in [("short.example.com", 10), ("long.example.com", 100)] {
+        let packet = response(
+            name,
+            DNSRecordType::A,
+            vec![record(name, ttl, RData::A(Ipv4Addr::new(10, 0, 0, 1)))],
+            vec![],
+            vec![],
+        );
+        cache.insert_at(a_key(name), packet, now);
+    }
+
+    assert_eq!(cache.remove_expired(now + Duration::from_secs(10)), 1);
+    assert_eq!(cache.len(), 1);
+    assert!(cache
+        .get_at(&a_key("long.example.com"), now + Duration::from_secs(10))
+        .is_some());
+}
+
+#[test]
+fn test_cache_keeps_record_types_apart() {
+    let now = std::time::Instant::now();
+    let mut cache = Cache::new();
+    let name = "example.com";
+    let entries = [
+        (DNSRecordType::A, RData::A(Ipv4Addr::new(10, 0, 0, 1))),
+        (DNSRecordType::AAAA, RData::AAAA(Ipv6Addr::LOCALHOST)),
+        (
+            DNSRecordType::MX,
+            RData::MX(MXData {
+                preference: 10,
+                exchange: "mail.example.com".into(),
+            }),
+        ),
+    ];
+    for (r#type, data) in entries.iter().cloned() {
+        let packet = response(name, r#type, vec![record(name, 60, data)], vec![], vec![]);
+        cache.insert_at(CacheKey::new(name, r#type, DNSRecordClass::IN), packet, now);
+    }
+    assert_eq!(cache.len(), 3);
+
+    for (r#type, data) in entries {
+        let key = CacheKey::new(name, r#type, DNSRecordClass::IN);
+        let cached = cache.get_at(&key, now).unwrap();
+        assert_eq!(cached.questions[0].r#type, r#type);
+        assert_eq!(cached.answers, vec![record(name, 60, data)]);
+    }
+
+    let txt = CacheKey::new(name, DNSRecordType::TXT, DNSRecordClass::IN);
+    assert!(cache.get_at(&txt, now).is_none());
+    let chaos = CacheKey::new(name, DNSRecordType::A, DNSRecordClass::CH);
+    assert!(cache.get_at(&chaos, now).is_none());
+}
+
+#[test]
+fn test_cache_key_ignores_case() {
+    let now = std::time::Instant::now();
+    let mut cache = Cache::new();
+    let packet = response(
+        "Example.COM",
+        DNSRecordType::A,
+        vec![record(
+            "Example.COM",
+            60,
+            RData::A(Ipv4Addr::new(10, 0, 0, 1)),
+        )],
+        vec![],
+        vec![],
+    );
+    cache.insert_at(a_key("Example.COM"), packet, now);
+
+    assert!(cache.get_at(&a_key("example.com"), now).is_some());
+    assert!(cache.get_at(&a_key("EXAMPLE.com."), now).is_some());
+    let question = DNSQuestion::new("eXaMpLe.CoM", DNSRecordType::A, DNSRecordClass::IN);
+    assert!(cache.get_at(&CacheKey::from(&question), now).is_some());
+}
+
+fn a_response(name: &str, ttl: u32) -> DNSPacket {
+    response(
+        name,
+        DNSRecordType::A,
+        vec![record(name, ttl, RData::A(Ipv4Addr::new(10, 0, 0, 1)))],
+        vec![],
+        vec![],
+    )
+}
+
+#[test]
+fn test_cache_evicts_least_recently_used() {
+    let now = std::time::Instant::now();
+    let mut cache = Cache::with_config(CacheConfig {
+        max_entries: Some(2),
+        max_bytes: None,
+    });
+    cache.insert_at(
+        a_key("one.example.com"),
+        a_response("one.example.com", 60),
+        now,
+    );
+    cache.insert_at(
+        a_key("two.example.com"),
+        a_response("two.example.com", 60),
+        now,
+    );
+
+    // Touching "one" makes "two" the one to go.
+    assert!(cache.get_at(&a_key("one.example.com"), now).is_some());
+    cache.insert_at(
+        a_key("three.example.com"),
+        a_response("three.example.com", 60),
+        now,
+    );
+
+    assert_eq!(cache.len(), 2);
+    assert!(cache.get_at(&a_key("two.example.com"), now).is_none());
+    assert!(cache.get_at(&a_key("one.example.com"), now).is_some());
+    assert!(cache.get_at(&a_key("three.example.com"), now).is_some());
+
+    // Replacing an entry doesn't count against the limit.
+    cache.insert_at(
+        a_key("three.example.com"),
+        a_response("three.example.com", 30),
+        now,
+    );
+    assert_eq!(cache.len(), 2);
+
+    assert_eq!(
+        cache.stats(),
+        CacheStats {
+            hits: 3,
+            misses: 1,
+            evictions: 1,
+            expirations: 0,
+        }
+    );
+}
+
+#[test]
+fn test_cache_respects_byte_budget() {
+    let now = std::time::Instant::now();
+    let packet = a_response("one.example.com", 60);
+    let mut wire = vec![];
+    packet.to_bytes(&mut wire).unwrap();
+    let entry_size = wire.len() + "one.example.com".len();
+
+    let mut cache = Cache::with_config(CacheConfig {
+        max_entries: None,
+        max_bytes: Some(entry_size * 2),
+    });
+    cache.insert_at(a_key("one.example.com"), packet, now);
+    cache.insert_at(
+        a_key("two.example.com"),
+        a_response("two.example.com", 60),
+        now,
+    );
+    assert_eq!(cache.size_in_bytes(), entry_size * 2);
+
+    cache.insert_at(
+        a_key("six.example.com"),
+        a_response("six.example.com", 60),
+        now,
+    );
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.size_in_bytes(), entry_size * 2);
+    assert!(cache.get_at(&a_key("one.example.com"), now).is_none());
+    assert_eq!(cache.stats().evictions, 1);
+
+    // Something that could never fit is turned away without evicting anything.
+    let mut tiny = Cache::with_config(CacheConfig {
+        max_entries: None,
+        max_bytes: Some(entry_size - 1),
+    });
+    tiny.insert_at(
+        a_key("one.example.com"),
+        a_response("one.example.com", 60),
+        now,
+    );
+    assert!(tiny.is_empty());
+    assert_eq!(tiny.stats().evictions, 0);
+}
+
+#[test]
+fn test_cache_counts_expirations() {
+    let now = std::time::Instant::now();
+    let mut cache = Cache::new();
+    cache.insert_at(
+        a_key("one.example.com"),
+        a_response("one.example.com", 10),
+        now,
+    );
+    cache.insert_at(
+        a_key("two.example.com"),
+        a_response("two.example.com", 10),
+        now,
+    );
+    cache.insert_at(
+        a_key("six.example.com"),
+        a_response("six.example.com", 99),
+        now,
+    );
+
+    let later = now + Duration::from_secs(10);
+    assert!(cache.get_at(&a_key("one.example.com"), later).is_none());
+    assert_eq!(cache.remove_expired(later), 1);
+    assert_eq!(cache.len(), 1);
+
+    let stats = cache.stats();
+    assert_eq!(stats.expirations, 2);
+    assert_eq!(stats.misses, 1);
+    assert_eq!(stats.evictions, 0);
+}
+
+fn soa_record(zone: &str, ttl: u32, minimum: u32) -> DNSRecord {
+    record(
+        zone,
+        ttl,
+        RData::SOA(SOAData {
+            mname: format!("ns1.{}", zone),
+            rname: format!("hostmaster.{}", zone),
+            serial: 2023050101,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum,
+        }),
+    )
+}
+
+fn nxdomain(name: &str, r#type: DNSRecordType, authorities: Vec<DNSRecord>) -> DNSPacket {
+    let mut packet = response(name, r#type, vec![], authorities, vec![]);
+    packet.header.flags.rcode = ResponseCode::NXDomain;
+    packet
+}
+
+#[test]
+fn test_negative_ttl() {
+    let packet = nxdomain(
+        "typo.example.com",
+        DNSRecordType::A,
+        vec![soa_record("example.com", 3600, 300)],
+    );
+    assert!(packet.is_negative());
+    assert_eq!(packet.negative_ttl(), Some(300));
+
+    let packet = nxdomain(
+        "typo.example.com",
+        DNSRecordType::A,
+        vec![soa_record("example.com", 60, 300)],
+    );
+    assert_eq!(packet.negative_ttl(), Some(60));
+
+    // NODATA: the name exists, just not with this type.
+    let nodata = response(
+        "example.com",
+        DNSRecordType::AAAA,
+        vec![],
+        vec![soa_record("example.com", 3600, 300)],
+        vec![],
+    );
+    assert!(nodata.is_negative());
+    assert_eq!(nodata.negative_ttl(), Some(300));
+
+    // A referral isn't a "no".
+    let referral = response(
+        "www.example.com",
+        DNSRecordType::A,
+        vec![],
+        vec![record(
+            "example.com",
+            3600,
+            RData::NS("ns1.example.com".into()),
+        )],
+        vec![],
+    );
+    assert!(!referral.is_negative());
+    assert_eq!(referral.negative_ttl(), None);
+}
+
+#[test]
+fn test_cache_keeps_nxdomain_with_soa() {
+    let now = std::time::Instant::now();
+    let mut cache = Cache::new();
+    let packet = nxdomain(
+        "typo.example.com",
+        DNSRecordType::A,
+        vec![soa_record("example.com", 3600, 300)],
+    );
+    cache.insert_at(a_key("typo.example.com"), packet, now);
+
+    let cached = cache
+        .get_at(&a_key("typo.example.com"), now + Duration::from_secs(100))
+        .unwrap();
+    assert_eq!(cached.rcode(), ResponseCode::NXDomain);
+    assert!(cached.answers.is_empty());
+    let (soa, _) = cached.get_soa().unwrap();
+    assert_eq!(soa.name, "example.com");
+    assert_eq!(soa.ttl, 200);
+
+    assert!(cache
+        .get_at(&a_key("typo.example.com"), now + Duration::from_secs(300))
+        .is_none());
+}
+
+#[test]
+fn test_cache_keeps_nodata_per_type() {
+    let now = std::time::Instant::now();
+    let mut cache = Cache::new();
+    let nodata = response(
+        "example.com",
+        DNSRecordType::AAAA,
+        vec![],
+        vec![soa_record("example.com", 3600, 300)],
+        vec![],
+    );
+    let aaaa = CacheKey::new("example.com", DNSRecordType::AAAA, DNSRecordClass::IN);
+    cache.insert_at(aaaa.clone(), nodata, now);
+    cache.insert_at(a_key("example.com"), a_response("example.com", 60), now);
+
+    let cached = cache.get_at(&aaaa, now).unwrap();
+    assert!(cached.is_negative());
+    assert_eq!(cached.authorities.len(), 1);
+    assert!(!cache
+        .get_at(&a_key("example.com"), now)
+        .unwrap()
+        .is_negative());
+}
+
+#[test]
+fn test_cache_skips_negative_without_soa() {
+    let now = std::time::Instant::now();
+    let mut cache = Cache::new();
+    let packet = nxdomain("typo.example.com", DNSRecordType::A, vec![]);
+    cache.insert_at(a_key("typo.example.com"), packet, now);
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn test_error_response_echoes_query() {
+    let mut query = DNSQuery::new(
+        "example.com",
+        DNSRecordType::MX,
+        DNSRecordClass::IN,
+        DNSHeaderFlags::RECURSION_DESIRED,
+    );
+    query.header.flags.checking_disabled = true;
+
+    let packet = DNSPacket::error_response(
+        &query.header,
+        Some(query.question.clone()),
+        ResponseCode::ServFail,
+    );
+    let mut bytes = vec![];
+    packet.to_bytes(&mut bytes).unwrap();
+    let parsed = DNSPacket::from_bytes(&mut Cursor::new(bytes)).unwrap();
+
+    assert_eq!(parsed.header.id, query.header.id);
+    assert_eq!(parsed.questions, vec![query.question]);
+    assert_eq!(parsed.rcode(), ResponseCode::ServFail);
+    assert!(parsed.header.flags.response);
+    assert!(parsed.header.flags.recursion_desired);
+    assert!(parsed.header.flags.recursion_available);
+    assert!(parsed.header.flags.checking_disabled);
+    assert!(parsed.answers.is_empty());
+}
+
+#[test]
+fn test_error_response_without_question() {
+    let header = DNSHeader {
+        id: 0x4242,
+        flags: DNSHeaderFlags {
+            opcode: Opcode::Update,
+            ..DNSHeaderFlags::NONE
+        },
+        num_questions: 3,
+        num_answers: 0,
+        num_authorities: 0,
+        num_additionals: 0,
+    };
+    let packet = DNSPacket::error_response(&header, None, ResponseCode::FormErr);
+
+    let mut bytes = vec![];
+    packet.to_bytes(&mut bytes).unwrap();
+    assert_eq!(bytes, b"\x42\x42\xa8\x81\0\0\0\0\0\0\0\0".to_vec());
+}
+
+#[test]
+fn test_referral_nameservers_and_glue() {
+    let packet = response(
+        "example.com",
+        DNSRecordType::A,
+        vec![],
+        vec![
+            record("example.com", 3600, RData::NS("a.iana-servers.net".into())),
+            record("example.com", 3600, RData::NS("b.iana-servers.net".into())),
+        ],
+        vec![
+            record(
+                "A.iana-servers.net",
+                3600,
+                RData::A(Ipv4Addr::new(199, 43, 135, 53)),
+            ),
+            record(
+                "c.iana-servers.net",
+                3600,
+                RData::A(Ipv4Addr::new(199, 43, 133, 53)),
+            ),
+        ],
+    );
+
+    assert_eq!(
+        packet.get_nameservers(),
+        vec!["a.iana-servers.net", "b.iana-servers.net"]
+    );
+    assert_eq!(
+        packet.get_glue("a.iana-servers.net"),
+        vec![std::net::IpAddr::from([199, 43, 135, 53])]
+    );
+    assert!(packet.get_glue("b.iana-servers.net").is_empty());
+}
+
+#[test]
+fn test_parse_root_hints() {
+    let hints = "\
+;       This file holds the information on root name servers needed to
+;       initialize cache of Internet domain name servers
+.                        3600000      NS    A.ROOT-SERVERS.NET.
+A.ROOT-SERVERS.NET.      3600000      A     198.41.0.4
+A.ROOT-SERVERS.NET.      3600000      AAAA  2001:503:ba3e::2:30
+;
+.                        3600000      NS    B.ROOT-SERVERS.NET.
+B.ROOT-SERVERS.NET.      3600000      A     170.247.170.2 ; trailing comment
+";
+    let servers = parse_root_hints(hints).unwrap();
+    assert_eq!(
+        servers,
+        vec![
+            "198.41.0.4:53".parse().unwrap(),
+            "170.247.170.2:53".parse().unwrap(),
+            "[2001:503:ba3e::2:30]:53".parse().unwrap(),
+        ]
+    );
+
+    assert!(parse_root_hints("A.ROOT-SERVERS.NET. 3600000 A not-an-address").is_err());
+    assert!(parse_root_hints("; nothing here").is_err());
+}
+
+#[test]
+fn test_default_root_servers() {
+    let servers = default_root_servers();
+    assert_eq!(servers.len(), 26);
+    assert_eq!(servers[0], "198.41.0.4:53".parse().unwrap());
+    assert!(servers[..13].iter().all(|server| server.is_ipv4()));
+    assert!(servers[13..].iter().all(|server| server.is_ipv6()));
+}
+
+/// A UDP nameserver on localhost that answers every query with `answer`.
+fn spawn_udp_server<F>(answer: F) -> std::net::SocketAddr
+where
+    F: Fn(&DNSQuery) -> DNSPacket + Send + 'static,
+{
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    std::thread::spawn(move || loop {
+        let mut buf = [0; 512];
+        let (size, sender) = socket.recv_from(&mut buf).unwrap();
+        let query = DNSQuery::from_bytes(&mut Cursor::new(&buf[..size])).unwrap();
+        let mut packet = answer(&query);
+        packet.header.id = query.header.id;
+        let mut response = vec![];
+        packet.to_bytes(&mut response).unwrap();
+        socket.send_to(&response, sender).unwrap();
+    });
+    addr
+}
+
+#[test]
+fn test_resolver_falls_back_to_next_root() {
+    let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let root = spawn_udp_server(|query| {
+        response(
+            &query.question.name,
+            query.question.r#type,
+            vec![record(
+                &query.question.name,
+                300,
+                RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            )],
+            vec![],
+            vec![],
+        )
+    });
+    let resolver = Resolver::new(ResolverConfig {
+        root_servers: vec![silent.local_addr().unwrap(), root],
+        timeout: Duration::from_millis(50),
+        retries: 1,
+        ..ResolverConfig::default()
+    });
+
+    let packet = resolver.resolve("example.com", DNSRecordType::A).unwrap();
+    assert_eq!(
+        packet.get_answer(),
+        Some(Ipv4Addr::new(192, 0, 2, 1).into())
+    );
+}
+
+#[test]
+fn test_resolver_gives_up_when_no_root_answers() {
+    let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let resolver = Resolver::new(ResolverConfig {
+        root_servers: vec![silent.local_addr().unwrap()],
+        timeout: Duration::from_millis(50),
+        retries: 2,
+        ..ResolverConfig::default()
+    });
+
+    let err = resolver
+        .resolve("example.com", DNSRecordType::A)
+        .unwrap_err();
+    assert!(
+        matches!(err, DNSError::NoNameserverResponded(_)),
+        "{:?}",
+        err
+    );
+}
+
+const MOCK_ROOT: &str = "198.41.0.4:53";
+const MOCK_TLD: &str = "192.5.6.30:53";
+const MOCK_IANA: &str = "199.43.135.53:53";
+
+fn addr(addr: &str) -> std::net::SocketAddr {
+    addr.parse().unwrap()
+}
+
+/// A referral to `nameservers`, with glue for those that have an address.
+fn referral(zone: &str, nameservers: &[(&str, Option<Ipv4Addr>)]) -> DNSPacket {
+    let authorities = nameservers
+        .iter()
+        .map(|(name, _)| record(zone, 172800, RData::NS(name.to_string())))
+        .collect();
+    let additionals = nameservers
+        .iter()
+        .filter_map(|(name, glue)| glue.map(|ip| record(name, 172800, RData::A(ip))))
+        .collect();
+    response(zone, DNSRecordType::NS, vec![], authorities, additionals)
+}
+
+/// A root that sends com and net to one TLD server, which in turn sends
+/// example.com to a.iana-servers.net. That server has no glue, so its
+/// address has to be looked up under iana-servers.net first.
+fn mock_hierarchy() -> MockTransport {
+    let transport = MockTransport::new();
+    let tld = Some(Ipv4Addr::new(192, 5, 6, 30));
+    let iana = Ipv4Addr::new(199, 43, 135, 53);
+
+    transport.delegate(
+        addr(MOCK_ROOT),
+        "com",
+        referral("com", &[("a.gtld-servers.net", tld)]),
+    );
+    transport.delegate(
+        addr(MOCK_ROOT),
+        "net",
+        referral("net", &[("a.gtld-servers.net", tld)]),
+    );
+    transport.delegate(
+        addr(MOCK_TLD),
+        "example.com",
+        referral("example.com", &[("a.iana-servers.net", None)]),
+    );
+    transport.delegate(
+        addr(MOCK_TLD),
+        "iana-servers.net",
+        referral("iana-servers.net", &[("a.iana-servers.net", Some(iana))]),
+    );
+    transport.answer(
+        addr(MOCK_IANA),
+        "a.iana-servers.net",
+        DNSRecordType::A,
+        response(
+            "a.iana-servers.net",
+            DNSRecordType::A,
+            vec![record("a.iana-servers.net", 3600, RData::A(iana))],
+            vec![],
+            vec![],
+        ),
+    );
+    transport.answer(
+        addr(MOCK_IANA),
+        "example.com",
+        DNSRecordType::A,
+        response(
+            "example.com",
+            DNSRecordType::A,
+            vec![record(
+                "example.com",
+                3600,
+                RData::A(Ipv4Addr::new(93, 184, 216, 34)),
+            )],
+            vec![],
+            vec![],
+        ),
+    );
+    transport.answer(
+        addr(MOCK_IANA),
+        "typo.example.com",
+        DNSRecordType::A,
+        nxdomain(
+            "typo.example.com",
+            DNSRecordType::A,
+            vec![soa_record("example.com", 3600, 300)],
+        ),
+    );
+    transport
+}
+
+fn mock_resolver(transport: &std::sync::Arc<MockTransport>, roots: &[&str]) -> Resolver {
+    Resolver::with_transport(
+        ResolverConfig {
+            root_servers: roots.iter().map(|root| addr(root)).collect(),
+            ..ResolverConfig::default()
+        },
+        transport.clone(),
+    )
+}
+
+#[test]
+fn test_resolver_follows_referrals() {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    let resolver = mock_resolver(&transport, &[MOCK_ROOT]);
+
+    let packet = resolver.resolve("example.com", DNSRecordType::A).unwrap();
+    assert_eq!(
+        packet.get_answer(),
+        Some(Ipv4Addr::new(93, 184, 216, 34).into())
+    );
+
+    let asked: Vec<(std::net::SocketAddr, String)> = transport
+        .queries()
+        .into_iter()
+        .map(|(server, question)| (server, question.name))
+        .collect();
+    assert_eq!(
+        asked,
+        vec![
+            (addr(MOCK_ROOT), "example.com".into()),
+            (addr(MOCK_TLD), "example.com".into()),
+            // No glue for a.iana-servers.net, so off to find it.
+            (addr(MOCK_ROOT), "a.iana-servers.net".into()),
+            (addr(MOCK_TLD), "a.iana-servers.net".into()),
+            (addr(MOCK_IANA), "a.iana-servers.net".into()),
+            (addr(MOCK_IANA), "example.com".into()),
+        ]
+    );
+}
+
+#[test]
+fn test_resolver_returns_negative_responses() {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    let resolver = mock_resolver(&transport, &[MOCK_ROOT]);
+
+    let packet = resolver
+        .resolve("typo.example.com", DNSRecordType::A)
+        .unwrap();
+    assert_eq!(packet.rcode(), ResponseCode::NXDomain);
+    assert_eq!(packet.negative_ttl(), Some(300));
+}
+
+#[test]
+fn test_resolver_retries_then_moves_on() {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    // Nothing is scripted for this one, so it never answers.
+    let down = "192.0.2.53:53";
+    let resolver = Resolver::with_transport(
+        ResolverConfig {
+            root_servers: vec![addr(down), addr(MOCK_ROOT)],
+            retries: 2,
+            ..ResolverConfig::default()
+        },
+        transport.clone(),
+    );
+
+    resolver.resolve("example.com", DNSRecordType::A).unwrap();
+    let queries = transport.queries();
+    assert!(queries[..3].iter().all(|(server, _)| *server == addr(down)));
+    assert_eq!(queries[3].0, addr(MOCK_ROOT));
+}
+
+#[test]
+fn test_resolver_skips_failing_nameservers() {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    let broken = "192.0.2.1:53";
+    let mut servfail = response("example.com", DNSRecordType::A, vec![], vec![], vec![]);
+    servfail.header.flags.rcode = ResponseCode::ServFail;
+    transport.delegate(addr(broken), "", servfail);
+
+    let resolver = mock_resolver(&transport, &[broken, MOCK_ROOT]);
+    let packet = resolver.resolve("example.com", DNSRecordType::A).unwrap();
+    assert!(packet.get_answer().is_some());
+    assert_eq!(transport.queries()[0].0, addr(broken));
+}
+
+#[test]
+fn test_resolver_fails_when_every_nameserver_fails() {
+    let transport = std::sync::Arc::new(MockTransport::new());
+    let resolver = mock_resolver(&transport, &["192.0.2.1:53", "192.0.2.2:53"]);
+
+    let err = resolver
+        .resolve("example.com", DNSRecordType::A)
+        .unwrap_err();
+    assert!(
+        matches!(err, DNSError::NoNameserverResponded(_)),
+        "{:?}",
+        err
+    );
+    // The default is one retry each.
+    assert_eq!(transport.queries().len(), 4);
+}
+
+#[test]
+fn test_mock_transport_refuses_unknown_zones() {
+    let transport = mock_hierarchy();
+    let query = DNSQuery::new(
+        "example.org",
+        DNSRecordType::A,
+        DNSRecordClass::IN,
+        DNSHeaderFlags::NONE,
+    );
+
+    let response = transport
+        .query(&query, addr(MOCK_ROOT), Duration::from_secs(1))
+        .unwrap();
+    assert_eq!(response.header.id, query.header.id);
+    assert_eq!(response.rcode(), ResponseCode::Refused);
+}
+
+#[test_case(&[("loop.com", "ns.loop.com")], "www.loop.com"; "zone served from inside itself")]
+#[test_case(&[("a.com", "ns.b.com"), ("b.com", "ns.a.com")], "www.a.com"; "zones served from each other")]
+#[test_case(&[("a.com", "ns.b.com"), ("b.com", "ns.c.com"), ("c.com", "ns.d.com"), ("d.com", "ns.e.com"), ("e.com", "ns.f.com"), ("f.com", "ns.a.com")], "www.a.com"; "long chain")]
+fn test_resolver_gives_up_on_nameservers_without_glue(zones: &[(&str, &str)], name: &str) {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    for (zone, nameserver) in zones {
+        transport.delegate(addr(MOCK_TLD), zone, referral(zone, &[(nameserver, None)]));
+    }
+    let resolver = mock_resolver(&transport, &[MOCK_ROOT]);
+
+    let err = resolver.resolve(name, DNSRecordType::A).unwrap_err();
+    assert!(
+        matches!(err, DNSError::NoNameserverResponded(_)),
+        "{:?}",
+        err
+    );
+    assert!(
+        transport.queries().len() < 100,
+        "{}",
+        transport.queries().len()
+    );
+}
+
+/// Have the example.com server say `name` is an alias for `target`.
+fn mock_alias(transport: &MockTransport, name: &str, target: &str) {
+    transport.answer(
+        addr(MOCK_IANA),
+        name,
+        DNSRecordType::A,
+        response(
+            name,
+            DNSRecordType::A,
+            vec![record(name, 300, RData::CNAME(target.into()))],
+            vec![],
+            vec![],
+        ),
+    );
+}
+
+#[test]
+fn test_resolver_restarts_for_cname_target() {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    mock_alias(&transport, "www.example.com", "cdn.example.net");
+    transport.delegate(
+        addr(MOCK_TLD),
+        "example.net",
+        referral(
+            "example.net",
+            &[("a.iana-servers.net", Some(Ipv4Addr::new(199, 43, 135, 53)))],
+        ),
+    );
+    transport.answer(
+        addr(MOCK_IANA),
+        "cdn.example.net",
+        DNSRecordType::A,
+        a_response("cdn.example.net", 60),
+    );
+    let resolver = mock_resolver(&transport, &[MOCK_ROOT]);
+
+    let packet = resolver
+        .resolve("www.example.com", DNSRecordType::A)
+        .unwrap();
+    assert_eq!(packet.questions[0].name, "www.example.com");
+    assert_eq!(
+        packet.answers,
+        vec![
+            record(
+                "www.example.com",
+                300,
+                RData::CNAME("cdn.example.net".into())
+            ),
+            record("cdn.example.net", 60, RData::A(Ipv4Addr::new(10, 0, 0, 1))),
+        ]
+    );
+    // The target is looked up from the net delegation learned on the
+    // way to a.iana-servers.net, not from the alias's server.
+    let queries = transport.queries();
+    let restart = queries
+        .iter()
+        .position(|(_, question)| question.name == "cdn.example.net")
+        .unwrap();
+    assert_eq!(queries[restart].0, addr(MOCK_TLD));
+}
+
+#[test]
+fn test_resolver_uses_chain_followed_by_server() {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    let chain = vec![
+        record(
+            "www.example.com",
+            300,
+            RData::CNAME("web.example.com".into()),
+        ),
+        record("web.example.com", 300, RData::A(Ipv4Addr::new(10, 0, 0, 2))),
+    ];
+    transport.answer(
+        addr(MOCK_IANA),
+        "www.example.com",
+        DNSRecordType::A,
+        response(
+            "www.example.com",
+            DNSRecordType::A,
+            chain.clone(),
+            vec![],
+            vec![],
+        ),
+    );
+    let resolver = mock_resolver(&transport, &[MOCK_ROOT]);
+
+    let packet = resolver
+        .resolve("www.example.com", DNSRecordType::A)
+        .unwrap();
+    assert_eq!(packet.answers, chain);
+    assert!(transport
+        .queries()
+        .iter()
+        .all(|(_, question)| question.name != "web.example.com"));
+}
+
+#[test]
+fn test_resolver_keeps_chain_for_missing_target() {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    mock_alias(&transport, "www.example.com", "typo.example.com");
+    let resolver = mock_resolver(&transport, &[MOCK_ROOT]);
+
+    let packet = resolver
+        .resolve("www.example.com", DNSRecordType::A)
+        .unwrap();
+    assert_eq!(packet.rcode(), ResponseCode::NXDomain);
+    assert_eq!(
+        packet.answers,
+        vec![record(
+            "www.example.com",
+            300,
+            RData::CNAME("typo.example.com".into())
+        )]
+    );
+}
+
+#[test]
+fn test_resolver_detects_cname_loops() {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    mock_alias(&transport, "a.example.com", "b.example.com");
+    mock_alias(&transport, "b.example.com", "A.example.com");
+    let resolver = mock_resolver(&transport, &[MOCK_ROOT]);
+
+    let err = resolver
+        .resolve("a.example.com", DNSRecordType::A)
+        .unwrap_err();
+    assert!(matches!(err, DNSError::CnameLoop(_)), "{:?}", err);
+}
+
+#[test]
+fn test_resolver_limits_cname_chains() {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    for i in 0..=MAX_CNAME_CHAIN {
+        mock_alias(
+            &transport,
+            &format!("{}.example.com", i),
+            &format!("{}.example.com", i + 1),
+        );
+    }
+    transport.answer(
+        addr(MOCK_IANA),
+        &format!("{}.example.com", MAX_CNAME_CHAIN),
+        DNSRecordType::A,
+        a_response(&format!("{}.example.com", MAX_CNAME_CHAIN), 60),
+    );
+    let resolver = mock_resolver(&transport, &[MOCK_ROOT]);
+
+    // Exactly as many aliases as allowed.
+    let packet = resolver.resolve("0.example.com", DNSRecordType::A).unwrap();
+    assert_eq!(packet.answers.len(), MAX_CNAME_CHAIN + 1);
+
+    // One too many.
+    mock_alias(&transport, "start.example.com", "0.example.com");
+    let err = resolver
+        .resolve("start.example.com", DNSRecordType::A)
+        .unwrap_err();
+    assert!(matches!(err, DNSError::CnameChainTooLong(_)), "{:?}", err);
+}
+
+#[test]
+fn test_get_answers_matches_question_type() {
+    let packet = response(
+        "www.example.com",
+        DNSRecordType::AAAA,
+        vec![
+            record(
+                "www.example.com",
+                300,
+                RData::CNAME("web.example.com".into()),
+            ),
+            record(
+                "web.example.com",
+                300,
+                RData::AAAA("2001:db8::1".parse().unwrap()),
+            ),
+            record(
+                "web.example.com",
+                300,
+                RData::AAAA("2001:db8::2".parse().unwrap()),
+            ),
+        ],
+        vec![],
+        vec![],
+    );
+
+    assert_eq!(
+        packet.get_answers(),
+        packet.answers[1..].iter().collect::<Vec<_>>()
+    );
+    assert_eq!(
+        packet.ips(),
+        vec![
+            "2001:db8::1".parse::<std::net::IpAddr>().unwrap(),
+            "2001:db8::2".parse().unwrap(),
+        ]
+    );
+    assert_eq!(packet.get_answer(), Some("2001:db8::1".parse().unwrap()));
+    assert_eq!(packet.ip(), Some("2001:db8::1".to_string()));
+}
+
+#[test_case(DNSRecordType::A, RData::A(Ipv4Addr::new(192, 0, 2, 1)), RData::A(Ipv4Addr::new(192, 0, 2, 2)) ; "a")]
+#[test_case(DNSRecordType::AAAA, RData::AAAA("2001:db8::1".parse().unwrap()), RData::AAAA("2001:db8::2".parse().unwrap()) ; "aaaa")]
+#[test_case(DNSRecordType::MX, RData::MX(MXData { preference: 10, exchange: "mx1.example.com".into() }), RData::MX(MXData { preference: 20, exchange: "mx2.example.com".into() }) ; "mx")]
+#[test_case(DNSRecordType::TXT, RData::TXT(vec![b"v=spf1 -all".to_vec()]), RData::TXT(vec![b"hello".to_vec()]) ; "txt")]
+fn test_resolver_returns_whole_rrset(r#type: DNSRecordType, first: RData, second: RData) {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    let rrset = vec![
+        record("example.com", 300, first),
+        record("example.com", 300, second),
+    ];
+    transport.answer(
+        addr(MOCK_IANA),
+        "example.com",
+        r#type,
+        response("example.com", r#type, rrset.clone(), vec![], vec![]),
+    );
+    let resolver = mock_resolver(&transport, &[MOCK_ROOT]);
+
+    let packet = resolver.resolve("example.com", r#type).unwrap();
+    assert_eq!(packet.get_answers(), rrset.iter().collect::<Vec<_>>());
+}
+
+const MOCK_ROOT_V6: &str = "[2001:503:ba3e::2:30]:53";
+
+#[test]
+fn test_referral_glue_includes_ipv6() {
+    let mut packet = referral(
+        "example.org",
+        &[("ns.example.org", Some(Ipv4Addr::new(192, 0, 2, 53)))],
+    );
+    packet.additionals.push(record(
+        "ns.example.org",
+        172800,
+        RData::AAAA("2001:db8::53".parse().unwrap()),
+    ));
+
+    assert_eq!(
+        packet.get_glue("ns.example.org"),
+        vec![
+            std::net::IpAddr::from([192, 0, 2, 53]),
+            "2001:db8::53".parse().unwrap(),
+        ]
+    );
+}
+
+#[test]
+fn test_resolver_follows_ipv6_only_glue() {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    let mut to_example_org = referral("example.org", &[("ns.example.org", None)]);
+    to_example_org.additionals.push(record(
+        "ns.example.org",
+        172800,
+        RData::AAAA("2001:db8::53".parse().unwrap()),
+    ));
+    transport.delegate(addr(MOCK_ROOT), "org", to_example_org);
+    transport.answer(
+        addr("[2001:db8::53]:53"),
+        "example.org",
+        DNSRecordType::A,
+        a_response("example.org", 300),
+    );
+    let resolver = mock_resolver(&transport, &[MOCK_ROOT]);
+
+    let packet = resolver.resolve("example.org", DNSRecordType::A).unwrap();
+    assert!(packet.get_answer().is_some());
+    assert_eq!(transport.queries()[1].0, addr("[2001:db8::53]:53"));
+}
+
+#[test]
+fn test_resolver_prefers_configured_family_and_falls_back() {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    let resolver = Resolver::with_transport(
+        ResolverConfig {
+            root_servers: vec![addr(MOCK_ROOT), addr(MOCK_ROOT_V6)],
+            preferred_family: AddressFamily::Ipv6,
+            retries: 0,
+            ..ResolverConfig::default()
+        },
+        transport.clone(),
+    );
+
+    // The IPv6 root doesn't answer, so the IPv4 one is tried next.
+    let packet = resolver.resolve("example.com", DNSRecordType::A).unwrap();
+    assert!(packet.get_answer().is_some());
+    let queries = transport.queries();
+    assert_eq!(queries[0].0, addr(MOCK_ROOT_V6));
+    assert_eq!(queries[1].0, addr(MOCK_ROOT));
+}
+
+#[test]
+fn test_resolver_looks_up_ipv6_nameserver_addresses() {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    transport.delegate(
+        addr(MOCK_TLD),
+        "example.net",
+        referral("example.net", &[("ns.example.com", None)]),
+    );
+    // ns.example.com only has an IPv6 address.
+    transport.answer(
+        addr(MOCK_IANA),
+        "ns.example.com",
+        DNSRecordType::AAAA,
+        response(
+            "ns.example.com",
+            DNSRecordType::AAAA,
+            vec![record(
+                "ns.example.com",
+                300,
+                RData::AAAA("2001:db8::53".parse().unwrap()),
+            )],
+            vec![],
+            vec![],
+        ),
+    );
+    transport.answer(
+        addr(MOCK_IANA),
+        "ns.example.com",
+        DNSRecordType::A,
+        response(
+            "ns.example.com",
+            DNSRecordType::A,
+            vec![],
+            vec![soa_record("example.com", 300, 300)],
+            vec![],
+        ),
+    );
+    transport.answer(
+        addr("[2001:db8::53]:53"),
+        "example.net",
+        DNSRecordType::A,
+        a_response("example.net", 300),
+    );
+    let resolver = mock_resolver(&transport, &[MOCK_ROOT]);
+
+    let packet = resolver.resolve("example.net", DNSRecordType::A).unwrap();
+    assert!(packet.get_answer().is_some());
+    assert_eq!(
+        transport.queries().last().unwrap().0,
+        addr("[2001:db8::53]:53")
+    );
+}
+
+#[test]
+fn test_query_over_ipv6() {
+    let server = std::net::UdpSocket::bind("[::1]:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let mut buf = [0; 512];
+        let (size, sender) = server.recv_from(&mut buf).unwrap();
+        let query = DNSQuery::from_bytes(&mut Cursor::new(&buf[..size])).unwrap();
+        let mut packet = a_response("example.com", 300);
+        packet.header.id = query.header.id;
+        let mut response = vec![];
+        packet.to_bytes(&mut response).unwrap();
+        server.send_to(&response, sender).unwrap();
+    });
+    let query = DNSQuery::new(
+        "example.com",
+        DNSRecordType::A,
+        DNSRecordClass::IN,
+        DNSHeaderFlags::NONE,
+    );
+
+    let response = query.query_udp(addr, Duration::from_secs(1)).unwrap();
+    assert_eq!(response.header.id, query.header.id);
+}
+
+#[test]
+fn test_delegation_from_referral() {
+    let mut packet = referral(
+        "example.com",
+        &[
+            ("a.iana-servers.net", Some(Ipv4Addr::new(199, 43, 135, 53))),
+            ("b.iana-servers.net", None),
+        ],
+    );
+    packet.authorities[1].ttl = 3600;
+    packet.additionals[0].ttl = 600;
+
+    let delegation = Delegation::from_referral(&packet).unwrap();
+    assert_eq!(delegation.zone, "example.com");
+    assert_eq!(
+        delegation.nameservers,
+        vec![
+            (
+                "a.iana-servers.net".to_string(),
+                vec![std::net::IpAddr::from([199, 43, 135, 53])]
+            ),
+            ("b.iana-servers.net".to_string(), vec![]),
+        ]
+    );
+    assert_eq!(delegation.ttl, 600);
+
+    assert!(Delegation::from_referral(&a_response("example.com", 60)).is_none());
+}
+
+#[test]
+fn test_delegation_cache_finds_closest_zone_until_expiry() {
+    let now = std::time::Instant::now();
+    let mut cache = DelegationCache::new();
+    let mut com =
+        Delegation::from_referral(&referral("com", &[("a.gtld-servers.net", None)])).unwrap();
+    com.ttl = 172800;
+    let mut example =
+        Delegation::from_referral(&referral("Example.COM", &[("a.iana-servers.net", None)]))
+            .unwrap();
+    example.ttl = 60;
+    cache.insert_at(com.clone(), now);
+    cache.insert_at(example.clone(), now);
+
+    assert_eq!(cache.closest_at("www.example.com.", now), Some(example));
+    assert_eq!(cache.closest_at("example.org", now), None);
+    assert_eq!(cache.closest_at("notexample.com", now), Some(com.clone()));
+
+    let later = now + Duration::from_secs(60);
+    assert_eq!(cache.closest_at("www.example.com", later), Some(com));
+    assert_eq!(cache.len(), 1);
+    assert_eq!(cache.remove_expired(later + Duration::from_secs(172800)), 1);
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn test_delegation_cache_evicts_least_recently_used() {
+    let now = std::time::Instant::now();
+    let mut cache = DelegationCache::with_config(CacheConfig {
+        max_entries: Some(2),
+        max_bytes: None,
+    });
+    let delegation = |zone: &str| {
+        Delegation::from_referral(&referral(zone, &[("ns.example.net", None)])).unwrap()
+    };
+    cache.insert_at(delegation("a.com"), now);
+    cache.insert_at(delegation("b.com"), now);
+    assert!(cache.closest_at("www.a.com", now).is_some());
+    cache.insert_at(delegation("c.com"), now);
+
+    assert_eq!(cache.len(), 2);
+    assert!(cache.closest_at("www.b.com", now).is_none());
+    assert!(cache.closest_at("www.a.com", now).is_some());
+    assert!(cache.closest_at("www.c.com", now).is_some());
+}
+
+#[test]
+fn test_resolver_starts_from_cached_delegation() {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    for name in ["a.example.com", "b.example.com"] {
+        transport.answer(
+            addr(MOCK_IANA),
+            name,
+            DNSRecordType::A,
+            a_response(name, 300),
+        );
+    }
+    let resolver = mock_resolver(&transport, &[MOCK_ROOT]);
+
+    resolver.resolve("b.example.com", DNSRecordType::A).unwrap();
+    let asked_before = transport.queries().len();
+    resolver.resolve("a.example.com", DNSRecordType::A).unwrap();
+
+    // Neither the root nor the TLD server is needed the second time round,
+    // for the name or for the address of the example.com nameserver.
+    let queries = transport.queries();
+    assert!(queries[asked_before..]
+        .iter()
+        .all(|(server, _)| *server == addr(MOCK_IANA)));
+    assert_eq!(
+        queries.last().unwrap().1,
+        DNSQuestion::new("a.example.com", DNSRecordType::A, DNSRecordClass::IN)
+    );
+}
+
+#[test]
+fn test_resolver_starts_over_when_cached_delegation_fails() {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    let resolver = mock_resolver(&transport, &[MOCK_ROOT]);
+    // Nothing answers at this address.
+    resolver.delegations().lock().unwrap().insert(
+        Delegation::from_referral(&referral(
+            "example.com",
+            &[("gone.example.net", Some(Ipv4Addr::new(192, 0, 2, 99)))],
+        ))
+        .unwrap(),
+    );
+
+    let packet = resolver.resolve("example.com", DNSRecordType::A).unwrap();
+    assert!(packet.get_answer().is_some());
+    assert_eq!(transport.queries()[0].0, addr("192.0.2.99:53"));
+}
+
+#[test]
+fn test_check_response() {
+    let query = DNSQuery::new(
+        "example.com",
+        DNSRecordType::A,
+        DNSRecordClass::IN,
+        DNSHeaderFlags::NONE,
+    );
+    let mut good = a_response("example.com.", 300);
+    good.header.id = query.header.id;
+    assert!(query.check_response(&good).is_ok());
+
+    let mut wrong_id = good.clone();
+    wrong_id.header.id = query.header.id.wrapping_add(1);
+    let mut wrong_type = good.clone();
+    wrong_type.questions[0].r#type = DNSRecordType::AAAA;
+    let mut wrong_name = good.clone();
+    wrong_name.questions[0].name = "example.net".into();
+    let mut no_question = good.clone();
+    no_question.questions.clear();
+    let mut not_a_response = good.clone();
+    not_a_response.header.flags.response = false;
+
+    for bad in [
+        wrong_id,
+        wrong_type,
+        wrong_name,
+        no_question,
+        not_a_response,
+    ] {
+        assert!(matches!(
+            query.check_response(&bad),
+            Err(DNSError::ResponseMismatch(_))
+        ));
+    }
+}
+
+#[test]
+fn test_check_response_case() {
+    let query = DNSQuery::new(
+        "Example.com",
+        DNSRecordType::A,
+        DNSRecordClass::IN,
+        DNSHeaderFlags::NONE,
+    );
+    let mut lowercased = a_response("example.com", 300);
+    lowercased.header.id = query.header.id;
+    assert!(query.check_response(&lowercased).is_ok());
+
+    let query = query.with_randomized_case();
+    let mut echoed = lowercased.clone();
+    echoed.questions[0].name = query.question.name.clone();
+    assert!(query.check_response(&echoed).is_ok());
+    let mut wrong_case = echoed.clone();
+    wrong_case.questions[0].name = query
+        .question
+        .name
+        .chars()
+        .map(|c| match c.is_ascii_uppercase() {
+            true => c.to_ascii_lowercase(),
+            false => c.to_ascii_uppercase(),
+        })
+        .collect();
+    assert!(matches!(
+        query.check_response(&wrong_case),
+        Err(DNSError::ResponseMismatch(_))
+    ));
+}
+
+#[test]
+fn test_resolver_accepts_lowercased_question() {
+    let root = spawn_udp_server(|query| {
+        let name = query.question.name.to_ascii_lowercase();
+        response(
+            &name,
+            DNSRecordType::A,
+            vec![record(&name, 300, RData::A(Ipv4Addr::new(192, 0, 2, 1)))],
+            vec![],
+            vec![],
+        )
+    });
+    let resolver = Resolver::new(ResolverConfig {
+        root_servers: vec![root],
+        timeout: Duration::from_millis(500),
+        retries: 0,
+        ..ResolverConfig::default()
+    });
+
+    let packet = resolver
+        .resolve("WWW.Example.com", DNSRecordType::A)
+        .unwrap();
+    assert_eq!(packet.ips(), [Ipv4Addr::new(192, 0, 2, 1)]);
+}
+
+#[test]
+fn test_query_ignores_spoofed_responses() {
+    let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let mut buf = [0; 512];
+        let (size, sender) = server.recv_from(&mut buf).unwrap();
+        let query = DNSQuery::from_bytes(&mut Cursor::new(&buf[..size])).unwrap();
+        let send = |socket: &std::net::UdpSocket, id: u16, ip: [u8; 4]| {
+            let mut packet = response(
+                "example.com",
+                DNSRecordType::A,
+                vec![record("example.com", 300, RData::A(ip.into()))],
+                vec![],
+                vec![],
+            );
+            packet.header.id = id;
+            let mut bytes = vec![];
+            packet.to_bytes(&mut bytes).unwrap();
+            socket.send_to(&bytes, sender).unwrap();
+        };
+
+        // From somewhere else, then with the wrong ID, then the real thing.
+        let spoofer = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        send(&spoofer, query.header.id, [6, 6, 6, 6]);
+        send(&server, query.header.id.wrapping_add(1), [6, 6, 6, 6]);
+        send(&server, query.header.id, [192, 0, 2, 1]);
+    });
+    let query = DNSQuery::new(
+        "example.com",
+        DNSRecordType::A,
+        DNSRecordClass::IN,
+        DNSHeaderFlags::NONE,
+    );
+
+    let packet = query
+        .query_udp(server_addr, Duration::from_secs(1))
+        .unwrap();
+    assert_eq!(packet.get_answer(), Some([192, 0, 2, 1].into()));
+}
+
+#[test]
+fn test_resolver_drops_out_of_bailiwick_records() {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    transport.answer(
+        addr(MOCK_IANA),
+        "example.com",
+        DNSRecordType::A,
+        response(
+            "example.com",
+            DNSRecordType::A,
+            vec![
+                record("example.com", 300, RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+                record("bank.com", 300, RData::A(Ipv4Addr::new(6, 6, 6, 6))),
+                record("www.example.com", 300, RData::A(Ipv4Addr::new(6, 6, 6, 6))),
+            ],
+            vec![
+                record("com", 172800, RData::NS("ns.example.com".into())),
+                record("example.com", 3600, RData::NS("ns.example.com".into())),
+            ],
+            vec![
+                record(
+                    "ns.example.com",
+                    3600,
+                    RData::A(Ipv4Addr::new(192, 0, 2, 53)),
+                ),
+                record(
+                    "a.gtld-servers.net",
+                    3600,
+                    RData::A(Ipv4Addr::new(6, 6, 6, 6)),
+                ),
+            ],
+        ),
+    );
+    let resolver = mock_resolver(&transport, &[MOCK_ROOT]);
+
+    let packet = resolver.resolve("example.com", DNSRecordType::A).unwrap();
+    assert_eq!(
+        packet.answers,
+        vec![record(
+            "example.com",
+            300,
+            RData::A(Ipv4Addr::new(192, 0, 2, 1))
+        )]
+    );
+    assert_eq!(
+        packet.authorities,
+        vec![record(
+            "example.com",
+            3600,
+            RData::NS("ns.example.com".into())
+        )]
+    );
+    assert_eq!(
+        packet.additionals,
+        vec![record(
+            "ns.example.com",
+            3600,
+            RData::A(Ipv4Addr::new(192, 0, 2, 53))
+        )]
+    );
+}
+
+#[test]
+fn test_resolver_rejects_referrals_that_dont_lead_down() {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    // Back up to com, which would send us round in circles.
+    transport.delegate(
+        addr(MOCK_TLD),
+        "loop.com",
+        referral(
+            "com",
+            &[("a.gtld-servers.net", Some(Ipv4Addr::new(192, 5, 6, 30)))],
+        ),
+    );
+    // Over to a zone the question isn't in.
+    transport.delegate(
+        addr(MOCK_TLD),
+        "sideways.com",
+        referral("example.com", &[("a.iana-servers.net", None)]),
+    );
+    let resolver = mock_resolver(&transport, &[MOCK_ROOT]);
+
+    let err = resolver.resolve("loop.com", DNSRecordType::A).unwrap_err();
+    assert!(matches!(err, DNSError::OutOfBailiwick { .. }), "{:?}", err);
+    assert!(resolver.resolve("sideways.com", DNSRecordType::A).is_err());
+    assert!(resolver
+        .delegations()
+        .lock()
+        .unwrap()
+        .closest("sideways.com")
+        .is_some_and(|delegation| delegation.zone == "com"));
+}
+
+#[test]
+fn test_randomized_case() {
+    let name = "abcdefghijklmnopqrstuvwxyz.abcdefghijklmnopqrstuvwxyz-0123456789.example";
+    let query = DNSQuery::new(
+        name,
+        DNSRecordType::A,
+        DNSRecordClass::IN,
+        DNSHeaderFlags::NONE,
+    )
+    .with_randomized_case();
+
+    assert!(query.question.name.eq_ignore_ascii_case(name));
+    // The odds of every one of 59 letters coming out lower case are negligible.
+    assert_ne!(query.question.name, name);
+}
+
+#[test]
+fn test_resolver_randomizes_case_and_restores_it() {
+    let root = spawn_udp_server(|query| {
+        let name = &query.question.name;
+        response(
+            name,
+            DNSRecordType::A,
+            vec![record(name, 300, RData::A(Ipv4Addr::new(192, 0, 2, 1)))],
+            vec![record(name, 300, RData::NS(format!("ns1.{}", name)))],
+            vec![],
+        )
+    });
+    let resolver = Resolver::new(ResolverConfig {
+        root_servers: vec![root],
+        randomize_case: true,
+        ..ResolverConfig::default()
+    });
+
+    let packet = resolver
+        .resolve("www.example.com", DNSRecordType::A)
+        .unwrap();
+    assert_eq!(packet.questions[0].name, "www.example.com");
+    assert_eq!(packet.answers[0].name, "www.example.com");
+    assert_eq!(
+        packet.authorities[0].data,
+        RData::NS("ns1.www.example.com".into())
+    );
+}
+
+#[test]
+fn test_resolver_rejects_responses_that_lose_the_case() {
+    let root = spawn_udp_server(|query| {
+        let name = query.question.name.to_ascii_lowercase();
+        let mut packet = a_response(&name, 300);
+        packet.questions[0].name = name;
+        packet
+    });
+    let resolver = Resolver::new(ResolverConfig {
+        root_servers: vec![root],
+        randomize_case: true,
+        timeout: Duration::from_millis(100),
+        retries: 0,
+        ..ResolverConfig::default()
+    });
+
+    let err = resolver
+        .resolve("www.example.com", DNSRecordType::A)
+        .unwrap_err();
+    assert!(
+        matches!(err, DNSError::NoNameserverResponded(_)),
+        "{:?}",
+        err
+    );
+}
+
+fn minimising_resolver(transport: &std::sync::Arc<MockTransport>) -> Resolver {
+    Resolver::with_transport(
+        ResolverConfig {
+            root_servers: vec![addr(MOCK_ROOT)],
+            qname_minimization: true,
+            ..ResolverConfig::default()
+        },
+        transport.clone(),
+    )
+}
+
+fn txt_response(name: &str) -> DNSPacket {
+    response(
+        name,
+        DNSRecordType::TXT,
+        vec![record(name, 300, RData::TXT(vec![b"hello".to_vec()]))],
+        vec![],
+        vec![],
+    )
+}
+
+#[test]
+fn test_qname_minimisation_reveals_one_label_at_a_time() {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    transport.answer(
+        addr(MOCK_IANA),
+        "www.example.com",
+        DNSRecordType::TXT,
+        txt_response("www.example.com"),
+    );
+    let resolver = minimising_resolver(&transport);
+
+    let packet = resolver
+        .resolve("www.example.com", DNSRecordType::TXT)
+        .unwrap();
+    assert_eq!(packet.get_answers().len(), 1);
+
+    let queries = transport.queries();
+    assert_eq!(
+        queries[..2],
+        [
+            (
+                addr(MOCK_ROOT),
+                DNSQuestion::new("com", DNSRecordType::A, DNSRecordClass::IN)
+            ),
+            (
+                addr(MOCK_TLD),
+                DNSQuestion::new("example.com", DNSRecordType::A, DNSRecordClass::IN)
+            ),
+        ]
+    );
+    assert_eq!(
+        queries.last().unwrap(),
+        &(
+            addr(MOCK_IANA),
+            DNSQuestion::new("www.example.com", DNSRecordType::TXT, DNSRecordClass::IN)
+        )
+    );
+    for (server, question) in queries {
+        let labels = question.name.split('.').count();
+        if server == addr(MOCK_ROOT) {
+            assert_eq!(labels, 1, "{:?}", question);
+        }
+        if server == addr(MOCK_TLD) {
+            assert_eq!(labels, 2, "{:?}", question);
+        }
+    }
+}
+
+#[test]
+fn test_qname_minimisation_steps_over_empty_non_terminals() {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    transport.answer(
+        addr(MOCK_IANA),
+        "b.example.com",
+        DNSRecordType::A,
+        response(
+            "b.example.com",
+            DNSRecordType::A,
+            vec![],
+            vec![soa_record("example.com", 3600, 300)],
+            vec![],
+        ),
+    );
+    transport.answer(
+        addr(MOCK_IANA),
+        "a.b.example.com",
+        DNSRecordType::TXT,
+        txt_response("a.b.example.com"),
+    );
+    let resolver = minimising_resolver(&transport);
+
+    let packet = resolver
+        .resolve("a.b.example.com", DNSRecordType::TXT)
+        .unwrap();
+    assert_eq!(packet.get_answers().len(), 1);
+    let asked: Vec<String> = transport
+        .queries()
+        .into_iter()
+        .filter(|(server, question)| {
+            *server == addr(MOCK_IANA) && question.name.ends_with("example.com")
+        })
+        .map(|(_, question)| question.name)
+        .collect();
+    assert_eq!(asked, ["b.example.com", "a.b.example.com"]);
+}
+
+#[test_case(Some(nxdomain("b.example.com", DNSRecordType::A, vec![])) ; "nxdomain on the empty non terminal")]
+#[test_case(Some(response("b.example.com", DNSRecordType::A, vec![], vec![], vec![])) ; "empty noerror without soa")]
+#[test_case(None ; "refused for the empty non terminal")]
+fn test_qname_minimisation_falls_back_to_full_name(intermediate: Option<DNSPacket>) {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    if let Some(packet) = intermediate {
+        transport.answer(addr(MOCK_IANA), "b.example.com", DNSRecordType::A, packet);
+    }
+    // Nothing else is scripted for b.example.com, so it gets REFUSED.
+    transport.answer(
+        addr(MOCK_IANA),
+        "a.b.example.com",
+        DNSRecordType::TXT,
+        txt_response("a.b.example.com"),
+    );
+    let resolver = minimising_resolver(&transport);
+
+    let packet = resolver
+        .resolve("a.b.example.com", DNSRecordType::TXT)
+        .unwrap();
+    assert_eq!(packet.rcode(), ResponseCode::NoError);
+    assert_eq!(packet.get_answers().len(), 1);
+    let (server, question) = transport.queries().last().unwrap().clone();
+    assert_eq!(server, addr(MOCK_IANA));
+    assert_eq!(question.name, "a.b.example.com");
+}
+
+/// The SIP over UDP rule from RFC 3403 6.2.
+fn sip_naptr() -> NAPTRData {
+    NAPTRData {
+        order: 100,
+        preference: 10,
+        flags: b"S".to_vec(),
+        services: b"SIP+D2U".to_vec(),
+        regexp: vec![],
+        replacement: "_sip._udp.example.com".into(),
+    }
+}
+
+fn dnssec_rrsig() -> RRSIGData {
+    RRSIGData {
+        type_covered: Int::from(DNSRecordType::A),
+        algorithm: 8,
+        labels: 2,
+        original_ttl: 3600,
+        expiration: 1_704_067_200,
+        inception: 1_701_388_800,
+        key_tag: 12345,
+        signer_name: "example.com".into(),
+        signature: vec![0xde, 0xad, 0xbe, 0xef],
+    }
+}
+
+fn dnssec_nsec3() -> NSEC3Data {
+    NSEC3Data {
+        hash_algorithm: 1,
+        flags: 1,
+        iterations: 12,
+        salt: vec![0xaa, 0xbb, 0xcc, 0xdd],
+        next_hashed_owner: vec![0x11; 20],
+        types: vec![1, 46],
+    }
+}
+
+#[test_case(RData::A(Ipv4Addr::new(10, 0, 0, 1)), "10.0.0.1"; "a")]
+#[test_case(RData::NS("ns1.example.com".into()), "ns1.example.com."; "ns")]
+#[test_case(RData::PTR("host.example.com".into()), "host.example.com."; "ptr")]
+#[test_case(RData::MX(MXData { preference: 10, exchange: "mail.example.com".into() }), "10 mail.example.com."; "mx")]
+#[test_case(RData::SRV(SRVData { priority: 1, weight: 2, port: 5060, target: "sip.example.com".into() }), "1 2 5060 sip.example.com."; "srv")]
+#[test_case(RData::NAPTR(sip_naptr()), r#"100 10 "S" "SIP+D2U" "" _sip._udp.example.com."#; "naptr")]
+#[test_case(RData::NAPTR(NAPTRData { order: 100, preference: 10, flags: b"u".to_vec(), services: b"E2U+sip".to_vec(), regexp: br"!^.*$!sip:info@example.com!".to_vec(), replacement: "".into() }), r#"100 10 "u" "E2U+sip" "!^.*$!sip:info@example.com!" ."#; "naptr with regexp")]
+#[test_case(RData::CAA(CAAData { flags: 0, tag: "issue".into(), value: b"letsencrypt.org".to_vec() }), r#"0 issue "letsencrypt.org""#; "caa")]
+#[test_case(RData::CAA(CAAData { flags: 128, tag: "iodef".into(), value: b"mailto:security@example.com".to_vec() }), r#"128 iodef "mailto:security@example.com""#; "critical caa")]
+#[test_case(RData::TXT(vec![b"say \"hi\"".to_vec(), vec![0x07]]), r#""say \"hi\"" "\007""#; "txt")]
+#[test_case(RData::DS(DSData { key_tag: 20326, algorithm: 8, digest_type: 2, digest: vec![0xe0, 0x6d] }), "20326 8 2 E06D"; "ds")]
+#[test_case(RData::DNSKEY(DNSKEYData { flags: 257, protocol: 3, algorithm: 8, public_key: b"key".to_vec() }), "257 3 8 a2V5"; "dnskey")]
+#[test_case(RData::RRSIG(dnssec_rrsig()), "A 8 2 3600 20240101000000 20231201000000 12345 example.com. 3q2+7w=="; "rrsig")]
+#[test_case(RData::NSEC(NSECData { next_domain_name: "host.example.com".into(), types: vec![1, 15, 46, 47, 1234] }), "host.example.com. A MX RRSIG NSEC TYPE1234"; "nsec")]
+#[test_case(RData::NSEC3(dnssec_nsec3()), "1 1 12 AABBCCDD 248H248H248H248H248H248H248H248H A RRSIG"; "nsec3")]
+#[test_case(RData::NSEC3PARAM(NSEC3PARAMData { hash_algorithm: 1, flags: 0, iterations: 0, salt: vec![] }), "1 0 0 -"; "nsec3param without salt")]
+#[test_case(RData::Unknown(vec![10, 0, 0, 1]), "\\# 4 0A000001"; "unknown")]
+fn test_rdata_presentation(data: RData, expected: &str) {
+    assert_eq!(data.to_string(), expected);
+}
+
+#[test]
+fn test_record_presentation() {
+    let ds = RData::DS(DSData {
+        key_tag: 370,
+        algorithm: 13,
+        digest_type: 2,
+        digest: vec![0xbe, 0x74],
+    });
+    assert_eq!(
+        record("example.com", 86400, ds).to_string(),
+        "example.com. 86400 IN DS 370 13 2 BE74"
+    );
+}
+
+#[test]
+fn test_data_as_string() {
+    let data = RData::MX(MXData {
+        preference: 10,
+        exchange: "mail.example.com".into(),
+    });
+    let record = record("example.com", 300, data.clone());
+    assert_eq!(record.try_get_data_as_string(), Some(data.to_string()));
+}
+
+#[test]
+fn test_unknown_record_presentation() {
+    let record = DNSRecord {
+        name: "example.com".to_string(),
+        r#type: DNSRecordType::Unknown(1234),
+        class: DNSRecordClass::Unknown(32),
+        ttl: 300,
+        data: RData::Unknown(vec![10, 0, 0, 1]),
+    };
+    assert_eq!(
+        record.to_string(),
+        "example.com. 300 CLASS32 TYPE1234 \\# 4 0A000001"
+    );
+    assert_eq!(
+        record.try_get_data_as_string(),
+        Some("\\# 4 0A000001".to_string())
+    );
+}
+
+#[test_case(0, "19700101000000"; "epoch")]
+#[test_case(951_782_400, "20000229000000"; "leap day")]
+#[test_case(4_294_967_295, "21060207062815"; "last second")]
+fn test_format_timestamp(timestamp: u32, expected: &str) {
+    assert_eq!(format_timestamp(timestamp), expected);
+}
+
+#[test]
+fn test_type_bitmap_encoding() {
+    // The example from RFC 4034 4.3.
+    let types = [1, 15, 46, 47, 1234];
+    let mut expected = vec![0x00, 0x06, 0x40, 0x01, 0x00, 0x00, 0x00, 0x03];
+    expected.extend([0x04, 0x1b]);
+    expected.extend([0; 26]);
+    expected.push(0x20);
+    assert_eq!(type_bitmap::encode(&types), expected);
+    assert_eq!(type_bitmap::decode(&expected).unwrap(), types);
+}
+
+#[test_case(&[0x00]; "truncated header")]
+#[test_case(&[0x00, 0x00]; "empty window")]
+#[test_case(&[0x00, 0x02, 0x40]; "short window")]
+#[test_case(&[0x01, 0x01, 0x40, 0x00, 0x01, 0x40]; "out of order")]
+fn test_bad_type_bitmap(bytes: &[u8]) {
+    assert!(matches!(
+        type_bitmap::decode(bytes),
+        Err(DNSError::BadTypeBitmap(_))
+    ));
+}
+
+#[test]
+fn test_canonical_name_order() {
+    // The example from RFC 4034 6.1, less the name with a \200 label.
+    let expected = [
+        "example",
+        "a.example",
+        "yljkjljk.a.example",
+        "Z.a.example",
+        "zABC.a.EXAMPLE",
+        "z.example",
+        "\u{1}.z.example",
+        "*.z.example",
+    ];
+    let mut names = expected;
+    names.reverse();
+    names.swap(2, 6);
+    names.sort_by(|a, b| canonical_name_cmp(a, b));
+    assert_eq!(names, expected);
+}
+
+#[test]
+fn test_canonical_rrset() {
+    let mx = |preference, exchange: &str| {
+        RData::MX(MXData {
+            preference,
+            exchange: exchange.into(),
+        })
+    };
+    let rrset = [
+        record("Example.COM.", 300, mx(20, "Mail.example.com")),
+        record("example.com", 300, mx(10, "mail.example.com")),
+        record("example.com", 300, mx(20, "mail.EXAMPLE.com")),
+    ];
+
+    let canonical = canonical_rrset(&rrset).unwrap();
+    assert_eq!(
+        canonical,
+        [
+            record("example.com", 300, mx(10, "mail.example.com")),
+            record("example.com", 300, mx(20, "mail.example.com")),
+        ]
+    );
+
+    let mut bytes = vec![];
+    canonical[0].to_bytes(&mut bytes).unwrap();
+    let mut expected = b"\x07example\x03com\x00\x00\x0f\x00\x01\x00\x00\x01\x2c".to_vec();
+    expected.extend(b"\x00\x14\x00\x0a\x04mail\x07example\x03com\x00");
+    assert_eq!(bytes, expected);
+}
+
+#[test]
+fn test_canonical_form_keeps_nsec_next_name_case() {
+    let nsec = RData::NSEC(NSECData {
+        next_domain_name: "Beta.Example".into(),
+        types: vec![1],
+    });
+    let canonical = record("Alpha.Example", 300, nsec.clone()).to_canonical();
+    assert_eq!(canonical.name, "alpha.example");
+    assert_eq!(canonical.data, nsec);
+}
+
+const ROOT_ZONE: &str = include_str!("tests/dnssec/root.zone");
+const COM_ZONE: &str = include_str!("tests/dnssec/com.zone");
+const EXAMPLE_ZONE: &str = include_str!("tests/dnssec/example.com.zone");
+const ROOT_ANCHOR: &str = include_str!("tests/dnssec/anchor.zone");
+
+/// Read one of the zones signed by `src/tests/dnssec/sign.py`, where
+/// every record is written as `owner ttl IN TYPE \# length hex`.
+fn fixture_zone(contents: &str) -> Vec<DNSRecord> {
+    contents
+        .lines()
+        .filter(|line| !line.starts_with(';') && !line.trim().is_empty())
+        .map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [owner, ttl, "IN", r#type, "\\#", length, data @ ..] = fields.as_slice() else {
+                panic!("unexpected fixture line {:?}", line);
+            };
+            let r#type: DNSRecordType = r#type.parse().unwrap();
+            let data = data_encoding::HEXUPPER
+                .decode(data.concat().as_bytes())
+                .unwrap();
+            let data = RData::from_bytes(
+                &mut Cursor::new(data),
+                r#type,
+                DNSRecordClass::IN,
+                length.parse().unwrap(),
+            );
+            DNSRecord {
+                name: owner.trim_end_matches('.').to_string(),
+                r#type,
+                class: DNSRecordClass::IN,
+                ttl: ttl.parse().unwrap(),
+                data: data.unwrap(),
+            }
+        })
+        .collect()
+}
+
+fn fixture_anchors() -> Vec<DSData> {
+    fixture_zone(ROOT_ANCHOR)
+        .into_iter()
+        .filter_map(|record| match record.data {
+            RData::DS(ds) => Some(ds),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The `r#type` records for `name` in `zone`, and the signatures over them.
+fn signed(zone: &[DNSRecord], name: &str, r#type: DNSRecordType) -> Vec<DNSRecord> {
+    zone.iter()
+        .filter(|record| record.name == name)
+        .filter(|record| match &record.data {
+            RData::RRSIG(sig) => sig.type_covered == Int::from(r#type),
+            _ => record.r#type == r#type,
+        })
+        .cloned()
+        .collect()
+}
+
+fn signed_answer(zone: &[DNSRecord], name: &str, r#type: DNSRecordType) -> DNSPacket {
+    response(name, r#type, signed(zone, name, r#type), vec![], vec![])
+}
+
+/// Every NSEC3 record in `zone`, which for a zone this small
+/// is enough to prove anything that can be proven.
+fn nsec3_chain(zone: &[DNSRecord]) -> Vec<DNSRecord> {
+    zone.iter()
+        .filter(|record| match &record.data {
+            RData::RRSIG(sig) => sig.type_covered == Int::from(DNSRecordType::NSEC3),
+            _ => record.r#type == DNSRecordType::NSEC3,
+        })
+        .cloned()
+        .collect()
+}
+
+/// The mock hierarchy again, but serving the signed zones from
+/// `src/tests/dnssec`, and with insecure.com delegated from com
+/// without a DS.
+fn dnssec_hierarchy() -> MockTransport {
+    let root = fixture_zone(ROOT_ZONE);
+    let com = fixture_zone(COM_ZONE);
+    let example = fixture_zone(EXAMPLE_ZONE);
+    let transport = MockTransport::new();
+    let tld = Some(Ipv4Addr::new(192, 5, 6, 30));
+    let iana = Some(Ipv4Addr::new(199, 43, 135, 53));
+
+    transport.delegate(
+        addr(MOCK_ROOT),
+        "com",
+        referral("com", &[("a.gtld-servers.net", tld)]),
+    );
+    for zone in ["example.com", "insecure.com"] {
+        transport.delegate(
+            addr(MOCK_TLD),
+            zone,
+            referral(zone, &[(&format!("ns.{}", zone), iana)]),
+        );
+    }
+
+    for (server, zone, name, r#type) in [
+        (MOCK_ROOT, &root, "", DNSRecordType::DNSKEY),
+        (MOCK_ROOT, &root, "com", DNSRecordType::DS),
+        (MOCK_TLD, &com, "com", DNSRecordType::DNSKEY),
+        (MOCK_TLD, &com, "example.com", DNSRecordType::DS),
+        (MOCK_IANA, &example, "example.com", DNSRecordType::DNSKEY),
+        (MOCK_IANA, &example, "example.com", DNSRecordType::A),
+    ] {
+        transport.answer(
+            addr(server),
+            name,
+            r#type,
+            signed_answer(zone, name, r#type),
+        );
+    }
+
+    let mut com_denial = signed(&com, "com", DNSRecordType::SOA);
+    com_denial.extend(nsec3_chain(&com));
+    transport.answer(
+        addr(MOCK_TLD),
+        "insecure.com",
+        DNSRecordType::DS,
+        response(
+            "insecure.com",
+            DNSRecordType::DS,
+            vec![],
+            com_denial.clone(),
+            vec![],
+        ),
+    );
+    transport.answer(
+        addr(MOCK_TLD),
+        "nonexistent.com",
+        DNSRecordType::A,
+        nxdomain("nonexistent.com", DNSRecordType::A, com_denial),
+    );
+
+    let mut answers = signed(&example, "www.example.com", DNSRecordType::CNAME);
+    answers.extend(signed(&example, "example.com", DNSRecordType::A));
+    transport.answer(
+        addr(MOCK_IANA),
+        "www.example.com",
+        DNSRecordType::A,
+        response("www.example.com", DNSRecordType::A, answers, vec![], vec![]),
+    );
+
+    let mut example_denial = signed(&example, "example.com", DNSRecordType::SOA);
+    example_denial.extend(signed(&example, "example.com", DNSRecordType::NSEC));
+    transport.answer(
+        addr(MOCK_IANA),
+        "typo.example.com",
+        DNSRecordType::A,
+        nxdomain("typo.example.com", DNSRecordType::A, example_denial.clone()),
+    );
+    transport.answer(
+        addr(MOCK_IANA),
+        "example.com",
+        DNSRecordType::AAAA,
+        response(
+            "example.com",
+            DNSRecordType::AAAA,
+            vec![],
+            example_denial,
+            vec![],
+        ),
+    );
+
+    // What the server makes up from *.wild.example.com.
+    let expanded = signed(&example, "*.wild.example.com", DNSRecordType::A)
+        .into_iter()
+        .map(|record| DNSRecord {
+            name: "foo.wild.example.com".to_string(),
+            ..record
+        })
+        .collect();
+    transport.answer(
+        addr(MOCK_IANA),
+        "foo.wild.example.com",
+        DNSRecordType::A,
+        response(
+            "foo.wild.example.com",
+            DNSRecordType::A,
+            expanded,
+            signed(&example, "*.wild.example.com", DNSRecordType::NSEC),
+            vec![],
+        ),
+    );
+
+    transport.answer(
+        addr(MOCK_IANA),
+        "insecure.com",
+        DNSRecordType::A,
+        response(
+            "insecure.com",
+            DNSRecordType::A,
+            vec![record(
+                "insecure.com",
+                3600,
+                RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            )],
+            vec![],
+            vec![],
+        ),
+    );
+    transport
+}
+
+fn validating_resolver(transport: &std::sync::Arc<MockTransport>) -> Resolver {
+    Resolver::with_transport(
+        ResolverConfig {
+            root_servers: vec![addr(MOCK_ROOT)],
+            dnssec_validation: true,
+            trust_anchors: fixture_anchors(),
+            ..ResolverConfig::default()
+        },
+        transport.clone(),
+    )
+}
+
+#[test]
+fn test_fixture_anchor_matches_root_key() {
+    let root = fixture_zone(ROOT_ZONE);
+    let anchor = &fixture_anchors()[0];
+    let key = signed(&root, "", DNSRecordType::DNSKEY)
+        .into_iter()
+        .find_map(|record| match record.data {
+            RData::DNSKEY(key) => Some(key),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(key.key_tag(), anchor.key_tag);
+    assert!(anchor.matches("", &key));
+    assert!(!anchor.matches("com", &key));
+}
+
+#[test]
+fn test_root_trust_anchors() {
+    let anchors = root_trust_anchors();
+    assert_eq!(
+        anchors.iter().map(|ds| ds.key_tag).collect::<Vec<_>>(),
+        [20326, 38696]
+    );
+    assert!(anchors.iter().all(|ds| ds.digest.len() == 32));
+}
+
+#[test]
+fn test_verify_rrsig_checks_validity_period() {
+    let example = fixture_zone(EXAMPLE_ZONE);
+    let key = signed(&example, "example.com", DNSRecordType::DNSKEY)
+        .into_iter()
+        .find_map(|record| match record.data {
+            RData::DNSKEY(key) => Some(key),
+            _ => None,
+        })
+        .unwrap();
+    let mut rrset = signed(&example, "example.com", DNSRecordType::A);
+    let RData::RRSIG(sig) = rrset.pop().unwrap().data else {
+        panic!("the signature comes last");
+    };
+
+    assert!(verify_rrsig(&rrset, &sig, &key, sig.inception).is_ok());
+    assert!(verify_rrsig(&rrset, &sig, &key, sig.expiration).is_ok());
+    for now in [sig.inception - 1, sig.expiration + 1] {
+        assert!(matches!(
+            verify_rrsig(&rrset, &sig, &key, now),
+            Err(DNSError::Bogus(_))
+        ));
+    }
+
+    rrset[0].data = RData::A(Ipv4Addr::new(10, 9, 9, 9));
+    assert!(verify_rrsig(&rrset, &sig, &key, sig.inception).is_err());
+}
+
+#[test]
+fn test_nsec3_hash() {
+    // From RFC 5155 Appendix A.
+    let hash = nsec3_hash("example", &[0xaa, 0xbb, 0xcc, 0xdd], 12).unwrap();
+    assert_eq!(
+        data_encoding::BASE32HEX_NOPAD.encode(&hash),
+        "0P9MHAVEQVM6T7VBL5LOP2U3T2RP3TOM"
+    );
+}
+
+#[test_case("example.com", DNSRecordType::A, ResponseCode::NoError; "signed answer")]
+#[test_case("www.example.com", DNSRecordType::A, ResponseCode::NoError; "signed alias")]
+#[test_case("foo.wild.example.com", DNSRecordType::A, ResponseCode::NoError; "wildcard")]
+#[test_case("typo.example.com", DNSRecordType::A, ResponseCode::NXDomain; "nxdomain with nsec")]
+#[test_case("example.com", DNSRecordType::AAAA, ResponseCode::NoError; "nodata with nsec")]
+#[test_case("nonexistent.com", DNSRecordType::A, ResponseCode::NXDomain; "nxdomain with nsec3")]
+fn test_dnssec_validates(name: &str, r#type: DNSRecordType, rcode: ResponseCode) {
+    let transport = std::sync::Arc::new(dnssec_hierarchy());
+    let resolver = validating_resolver(&transport);
+
+    let packet = resolver.resolve(name, r#type).unwrap();
+    assert_eq!(packet.rcode(), rcode);
+    assert!(packet.header.flags.authentic_data);
+}
+
+#[test]
+fn test_dnssec_accepts_unsigned_zone_without_ad() {
+    let transport = std::sync::Arc::new(dnssec_hierarchy());
+    let resolver = validating_resolver(&transport);
+
+    let packet = resolver.resolve("insecure.com", DNSRecordType::A).unwrap();
+    assert_eq!(packet.ips(), [Ipv4Addr::new(192, 0, 2, 1)]);
+    assert!(!packet.header.flags.authentic_data);
+}
+
+#[test]
+fn test_dnssec_reuses_validated_keys() {
+    let transport = std::sync::Arc::new(dnssec_hierarchy());
+    let resolver = validating_resolver(&transport);
+
+    resolver.resolve("example.com", DNSRecordType::A).unwrap();
+    let before = transport.queries().len();
+    resolver
+        .resolve("example.com", DNSRecordType::AAAA)
+        .unwrap();
+    let queries = transport.queries()[before..].to_vec();
+    assert_eq!(
+        queries
+            .iter()
+            .map(|(_, question)| question.r#type)
+            .collect::<Vec<_>>(),
+        [DNSRecordType::AAAA]
+    );
+}
+
+#[test]
+fn test_dnssec_remembers_only_zone_cuts() {
+    let transport = std::sync::Arc::new(dnssec_hierarchy());
+    let hosts = ["a.insecure.com", "b.insecure.com", "c.b.insecure.com"];
+    for host in hosts {
+        transport.answer(
+            addr(MOCK_IANA),
+            host,
+            DNSRecordType::A,
+            a_response(host, 3600),
+        );
+    }
+    let resolver = validating_resolver(&transport);
+
+    for (name, r#type) in [
+        ("example.com", DNSRecordType::A),
+        ("www.example.com", DNSRecordType::A),
+        ("typo.example.com", DNSRecordType::A),
+        ("insecure.com", DNSRecordType::A),
+    ] {
+        resolver.resolve(name, r#type).unwrap();
+    }
+    // The root, com, example.com and insecure.com.
+    assert_eq!(resolver.known_zone_cuts(), 4);
+
+    let before = transport.queries().len();
+    for host in hosts {
+        let packet = resolver.resolve(host, DNSRecordType::A).unwrap();
+        assert!(!packet.header.flags.authentic_data);
+    }
+    assert_eq!(resolver.known_zone_cuts(), 4);
+    assert!(transport.queries()[before..]
+        .iter()
+        .all(|(_, question)| question.r#type == DNSRecordType::A));
+
+    assert_eq!(resolver.remove_expired_trust(Instant::now()), 0);
+    assert_eq!(
+        resolver.remove_expired_trust(Instant::now() + Duration::from_secs(MAX_CACHE_TTL as u64)),
+        4
+    );
+}
+
+#[test]
+fn test_dnssec_zone_cuts_are_bounded() {
+    let transport = std::sync::Arc::new(dnssec_hierarchy());
+    let resolver = Resolver::with_transport(
+        ResolverConfig {
+            root_servers: vec![addr(MOCK_ROOT)],
+            dnssec_validation: true,
+            trust_anchors: fixture_anchors(),
+            delegation_cache: CacheConfig {
+                max_entries: Some(2),
+                max_bytes: None,
+            },
+            ..ResolverConfig::default()
+        },
+        transport.clone(),
+    );
+
+    for name in ["example.com", "insecure.com"] {
+        resolver.resolve(name, DNSRecordType::A).unwrap();
+        assert!(resolver.known_zone_cuts() <= 2);
+    }
+}
+
+#[test]
+fn test_dnssec_off_leaves_ad_clear() {
+    let transport = std::sync::Arc::new(dnssec_hierarchy());
+    let resolver = mock_resolver(&transport, &[MOCK_ROOT]);
+
+    let packet = resolver.resolve("example.com", DNSRecordType::A).unwrap();
+    assert!(!packet.header.flags.authentic_data);
+    assert!(transport
+        .queries()
+        .iter()
+        .all(|(_, question)| question.r#type == DNSRecordType::A));
+}
+
+/// Sets the mock hierarchy up to give a bogus answer,
+/// returning the question that gets it.
+type BogusSetup = fn(&MockTransport, &mut ResolverConfig) -> (&'static str, DNSRecordType);
+
+fn setup_tampered_answer(
+    transport: &MockTransport,
+    _: &mut ResolverConfig,
+) -> (&'static str, DNSRecordType) {
+    let example = fixture_zone(EXAMPLE_ZONE);
+    let mut packet = signed_answer(&example, "example.com", DNSRecordType::A);
+    packet.answers[0].data = RData::A(Ipv4Addr::new(6, 6, 6, 6));
+    transport.answer(addr(MOCK_IANA), "example.com", DNSRecordType::A, packet);
+    ("example.com", DNSRecordType::A)
+}
+
+fn setup_missing_signature(
+    transport: &MockTransport,
+    _: &mut ResolverConfig,
+) -> (&'static str, DNSRecordType) {
+    let example = fixture_zone(EXAMPLE_ZONE);
+    let mut packet = signed_answer(&example, "example.com", DNSRecordType::A);
+    packet.answers.truncate(1);
+    transport.answer(addr(MOCK_IANA), "example.com", DNSRecordType::A, packet);
+    ("example.com", DNSRecordType::A)
+}
+
+fn setup_missing_denial(
+    transport: &MockTransport,
+    _: &mut ResolverConfig,
+) -> (&'static str, DNSRecordType) {
+    let example = fixture_zone(EXAMPLE_ZONE);
+    let soa = signed(&example, "example.com", DNSRecordType::SOA);
+    let packet = nxdomain("typo.example.com", DNSRecordType::A, soa);
+    transport.answer(
+        addr(MOCK_IANA),
+        "typo.example.com",
+        DNSRecordType::A,
+        packet,
+    );
+    ("typo.example.com", DNSRecordType::A)
+}
+
+fn setup_unproven_wildcard(
+    transport: &MockTransport,
+    _: &mut ResolverConfig,
+) -> (&'static str, DNSRecordType) {
+    let example = fixture_zone(EXAMPLE_ZONE);
+    let answers = signed(&example, "*.wild.example.com", DNSRecordType::A)
+        .into_iter()
+        .map(|record| DNSRecord {
+            name: "foo.wild.example.com".to_string(),
+            ..record
+        })
+        .collect();
+    let packet = response(
+        "foo.wild.example.com",
+        DNSRecordType::A,
+        answers,
+        vec![],
+        vec![],
+    );
+    transport.answer(
+        addr(MOCK_IANA),
+        "foo.wild.example.com",
+        DNSRecordType::A,
+        packet,
+    );
+    ("foo.wild.example.com", DNSRecordType::A)
+}
+
+fn setup_unsigned_insecure_delegation(
+    transport: &MockTransport,
+    _: &mut ResolverConfig,
+) -> (&'static str, DNSRecordType) {
+    let packet = response(
+        "insecure.com",
+        DNSRecordType::DS,
+        vec![],
+        vec![soa_record("com", 900, 86400)],
+        vec![],
+    );
+    transport.answer(addr(MOCK_TLD), "insecure.com", DNSRecordType::DS, packet);
+    ("insecure.com", DNSRecordType::A)
+}
+
+fn setup_wrong_trust_anchor(
+    _: &MockTransport,
+    config: &mut ResolverConfig,
+) -> (&'static str, DNSRecordType) {
+    config.trust_anchors[0].digest[0] ^= 0xff;
+    ("example.com", DNSRecordType::A)
+}
+
+#[test_case(setup_tampered_answer; "tampered answer")]
+#[test_case(setup_missing_signature; "missing signature")]
+#[test_case(setup_missing_denial; "missing denial")]
+#[test_case(setup_unproven_wildcard; "unproven wildcard")]
+#[test_case(setup_unsigned_insecure_delegation; "unsigned insecure delegation")]
+#[test_case(setup_wrong_trust_anchor; "wrong trust anchor")]
+fn test_dnssec_rejects_bogus(setup: BogusSetup) {
+    let transport = std::sync::Arc::new(dnssec_hierarchy());
+    let mut config = ResolverConfig {
+        root_servers: vec![addr(MOCK_ROOT)],
+        dnssec_validation: true,
+        trust_anchors: fixture_anchors(),
+        ..ResolverConfig::default()
+    };
+    let (name, r#type) = setup(&transport, &mut config);
+    let resolver = Resolver::with_transport(config, transport.clone());
+
+    let err = resolver.resolve(name, r#type).unwrap_err();
+    assert!(matches!(err, DNSError::Bogus(_)), "{:?}", err);
+}
+
+#[test]
+fn test_dnssec_checking_disabled() {
+    let transport = std::sync::Arc::new(dnssec_hierarchy());
+    let (name, r#type) = setup_tampered_answer(&transport, &mut ResolverConfig::default());
+    let resolver = std::sync::Arc::new(validating_resolver(&transport));
+    let cache: Database = std::sync::Arc::new(std::sync::Mutex::new(Cache::new()));
+    let mut query = DNSQuery::new(
+        name,
+        r#type,
+        DNSRecordClass::IN,
+        DNSHeaderFlags::RECURSION_DESIRED,
+    );
+
+    query.header.flags.checking_disabled = true;
+    let (packet, _) = respond(
+        Cursor::new(query_bytes(&query)),
+        cache.clone(),
+        resolver.clone(),
+    )
+    .unwrap();
+    assert_eq!(packet.rcode(), ResponseCode::NoError);
+    assert_eq!(packet.ips(), [Ipv4Addr::new(6, 6, 6, 6)]);
+    assert!(!packet.header.flags.authentic_data);
+    assert!(packet.header.flags.checking_disabled);
+
+    // What the CD query got mustn't be handed to one that wants it checked.
+    query.header.flags.checking_disabled = false;
+    let (packet, _) = respond(Cursor::new(query_bytes(&query)), cache, resolver).unwrap();
+    assert_eq!(packet.rcode(), ResponseCode::ServFail);
+    assert!(packet.answers.is_empty());
+}
+
+#[test_case(Some(Edns::new(1232)), ResponseCode::Other(16); "edns")]
+#[test_case(None, ResponseCode::ServFail; "no edns")]
+fn test_prepare_response_extended_rcode(edns: Option<Edns>, rcode: ResponseCode) {
+    // BADVERS from upstream: 16 doesn't fit in the header on its own.
+    let mut packet = a_response("example.com", 300);
+    packet.header.flags.rcode = ResponseCode::NoError;
+    packet.edns = Some(Edns {
+        extended_rcode: 1,
+        ..Edns::new(4096)
+    });
+    let mut query = DNSQuery::new(
+        "example.com",
+        DNSRecordType::A,
+        DNSRecordClass::IN,
+        DNSHeaderFlags::RECURSION_DESIRED,
+    );
+    query.edns = edns;
+
+    packet.prepare_response(&query);
+    assert_eq!(packet.rcode(), rcode);
+
+    let mut bytes = vec![];
+    packet.to_bytes(&mut bytes).unwrap();
+    let parsed = DNSPacket::from_bytes(&mut Cursor::new(bytes)).unwrap();
+    assert_eq!(parsed.rcode(), rcode);
+}
+
+#[test_case(None, false, false, false; "no edns")]
+#[test_case(Some(false), false, false, false; "do clear")]
+#[test_case(Some(true), false, true, true; "do set")]
+#[test_case(None, true, false, true; "ad set")]
+fn test_prepare_response_dnssec(
+    dnssec_ok: Option<bool>,
+    authentic_data: bool,
+    keeps_dnssec_records: bool,
+    keeps_ad: bool,
+) {
+    let example = fixture_zone(EXAMPLE_ZONE);
+    let mut packet = response(
+        "example.com",
+        DNSRecordType::A,
+        signed(&example, "example.com", DNSRecordType::A),
+        signed(&example, "example.com", DNSRecordType::NSEC),
+        vec![],
+    );
+    packet.header.flags.authentic_data = true;
+    let mut query = DNSQuery::new(
+        "example.com",
+        DNSRecordType::A,
+        DNSRecordClass::IN,
+        DNSHeaderFlags::RECURSION_DESIRED,
+    );
+    query.header.flags.authentic_data = authentic_data;
+    if let Some(dnssec_ok) = dnssec_ok {
+        query = query.with_edns(Edns {
+            dnssec_ok,
+            ..Edns::new(1232)
+        });
+    }
+
+    packet.prepare_response(&query);
+    let types = |records: &[DNSRecord]| {
+        records
+            .iter()
+            .map(|record| record.r#type)
+            .collect::<Vec<_>>()
+    };
+    match keeps_dnssec_records {
+        true => {
+            assert_eq!(
+                types(&packet.answers),
+                [DNSRecordType::A, DNSRecordType::RRSIG]
+            );
+            assert_eq!(
+                types(&packet.authorities),
+                [DNSRecordType::NSEC, DNSRecordType::RRSIG]
+            );
+        }
+        false => {
+            assert_eq!(types(&packet.answers), [DNSRecordType::A]);
+            assert!(packet.authorities.is_empty());
+        }
+    }
+    assert_eq!(packet.header.flags.authentic_data, keeps_ad);
+    assert_eq!(packet.edns.map(|edns| edns.dnssec_ok), dnssec_ok);
+}
+
+/// What the server sends back for `message`, resolving
+/// against the mock hierarchy with an empty cache.
+fn serve(message: Vec<u8>) -> Option<DNSPacket> {
+    let transport = std::sync::Arc::new(mock_hierarchy());
+    let resolver = std::sync::Arc::new(mock_resolver(&transport, &[MOCK_ROOT]));
+    let cache: Database = std::sync::Arc::new(std::sync::Mutex::new(Cache::new()));
+    respond(Cursor::new(message), cache, resolver).map(|(packet, _)| packet)
+}
+
+fn query_bytes(query: &DNSQuery) -> Vec<u8> {
+    let mut bytes = vec![];
+    query.to_bytes(&mut bytes).unwrap();
+    bytes
+}
+
+#[test_case(|_| {}, ResponseCode::NoError; "answer")]
+#[test_case(|query| query.question.name = "example.test".into(), ResponseCode::ServFail; "resolution fails")]
+#[test_case(|query| query.header.flags.opcode = Opcode::Status, ResponseCode::NotImp; "other opcode")]
+#[test_case(|query| query.question.class = DNSRecordClass::CH, ResponseCode::Refused; "other class")]
+fn test_respond_rcode(change: fn(&mut DNSQuery), rcode: ResponseCode) {
+    let mut query = DNSQuery::new(
+        "example.com",
+        DNSRecordType::A,
+        DNSRecordClass::IN,
+        DNSHeaderFlags::RECURSION_DESIRED,
+    );
+    change(&mut query);
+
+    let packet = serve(query_bytes(&query)).unwrap();
+    assert_eq!(packet.rcode(), rcode);
+    assert_eq!(packet.header.id, query.header.id);
+    assert_eq!(packet.questions, vec![query.question]);
+    assert_eq!(packet.header.flags.opcode, query.header.flags.opcode);
+    assert!(packet.header.flags.response);
+    assert_eq!(packet.answers.is_empty(), rcode != ResponseCode::NoError);
+}
+
+#[test_case(|bytes| bytes[5] = 2, true; "two questions")]
+#[test_case(|bytes| bytes[5] = 0, false; "no question")]
+#[test_case(|bytes| bytes.truncate(14), false; "unreadable question")]
+#[test_case(|bytes| { bytes[11] = 1; bytes.extend([0, 0, 41]) }, true; "broken additional")]
+fn test_respond_formerr(change: fn(&mut Vec<u8>), echoes_question: bool) {
+    let query = DNSQuery::new(
+        "example.com",
+        DNSRecordType::A,
+        DNSRecordClass::IN,
+        DNSHeaderFlags::RECURSION_DESIRED,
+    );
+    let mut bytes = query_bytes(&query);
+    change(&mut bytes);
+
+    let packet = serve(bytes).unwrap();
+    assert_eq!(packet.rcode(), ResponseCode::FormErr);
+    assert_eq!(packet.header.id, query.header.id);
+    match echoes_question {
+        true => assert_eq!(packet.questions, vec![query.question]),
+        false => assert!(packet.questions.is_empty()),
+    }
+}
+
+#[test_case(vec![0x12, 0x34, 0x01]; "too short for a header")]
+#[test_case(query_bytes(&DNSQuery::new("example.com", DNSRecordType::A, DNSRecordClass::IN, DNSHeaderFlags { response: true, ..DNSHeaderFlags::NONE })); "response")]
+fn test_respond_drops(message: Vec<u8>) {
+    assert!(serve(message).is_none());
 }
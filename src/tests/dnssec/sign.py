@@ -0,0 +1,249 @@
+#!/usr/bin/env python3
+"""Sign the zones that the DNSSEC tests in src/tests.rs resolve against.
+
+Run it from this directory with `python3 sign.py`, which needs the
+`cryptography` package. Fresh keys are made every time, so the zone files
+and the trust anchor for the root always change together.
+
+    .                 RSASHA256, NSEC
+    com.              ED25519, NSEC3 without a salt or extra iterations
+    example.com.      ECDSAP256SHA256, NSEC, with a wildcard under wild.
+    insecure.com.     delegated from com. without a DS, so left unsigned
+
+Every RDATA is written in the generic form from RFC 3597 section 5 so
+that the tests can read the zones back without a zone file parser.
+"""
+
+import calendar
+import hashlib
+import struct
+
+from cryptography.hazmat.primitives import hashes
+from cryptography.hazmat.primitives.asymmetric import ec, ed25519, padding, rsa
+from cryptography.hazmat.primitives.asymmetric.utils import decode_dss_signature
+
+# Signatures are valid from 2024 until 2088, as far apart as serial number
+# arithmetic on the 32 bit timestamps allows with room to spare.
+INCEPTION = calendar.timegm((2024, 1, 1, 0, 0, 0))
+EXPIRATION = calendar.timegm((2088, 1, 1, 0, 0, 0))
+
+TYPES = {
+    "A": 1,
+    "NS": 2,
+    "CNAME": 5,
+    "SOA": 6,
+    "DS": 43,
+    "RRSIG": 46,
+    "NSEC": 47,
+    "DNSKEY": 48,
+    "NSEC3": 50,
+    "NSEC3PARAM": 51,
+}
+
+
+def labels(name):
+    return [label for label in name.split(".") if label]
+
+
+def wire_name(name):
+    return b"".join(bytes([len(label)]) + label.encode() for label in labels(name)) + b"\0"
+
+
+def canonical_key(name):
+    return [label.lower().encode() for label in reversed(labels(name))]
+
+
+def type_bitmap(types):
+    numbers = sorted({TYPES[t] for t in types})
+    out = b""
+    for window in sorted({n >> 8 for n in numbers}):
+        bits = bytearray(32)
+        for n in numbers:
+            if n >> 8 == window:
+                bits[(n & 0xFF) // 8] |= 0x80 >> (n % 8)
+        length = max((n & 0xFF) // 8 for n in numbers if n >> 8 == window) + 1
+        out += bytes([window, length]) + bytes(bits[:length])
+    return out
+
+
+def nsec3_hash(name, salt=b"", iterations=0):
+    digest = hashlib.sha1(wire_name(name.lower()) + salt).digest()
+    for _ in range(iterations):
+        digest = hashlib.sha1(digest + salt).digest()
+    return digest
+
+
+def base32hex(data):
+    alphabet = "0123456789ABCDEFGHIJKLMNOPQRSTUV"
+    bits = int.from_bytes(data, "big")
+    count = len(data) * 8 // 5
+    return "".join(alphabet[(bits >> (5 * (count - 1 - i))) & 31] for i in range(count))
+
+
+class Key:
+    def __init__(self, algorithm):
+        self.algorithm = algorithm
+        if algorithm == 8:
+            self.private = rsa.generate_private_key(public_exponent=65537, key_size=2048)
+            numbers = self.private.public_key().public_numbers()
+            exponent = numbers.e.to_bytes(3, "big")
+            self.public = bytes([len(exponent)]) + exponent + numbers.n.to_bytes(256, "big")
+        elif algorithm == 13:
+            self.private = ec.generate_private_key(ec.SECP256R1())
+            numbers = self.private.public_key().public_numbers()
+            self.public = numbers.x.to_bytes(32, "big") + numbers.y.to_bytes(32, "big")
+        elif algorithm == 15:
+            self.private = ed25519.Ed25519PrivateKey.generate()
+            self.public = self.private.public_key().public_bytes_raw()
+
+    def rdata(self):
+        return struct.pack("!HBB", 257, 3, self.algorithm) + self.public
+
+    def key_tag(self):
+        data = self.rdata()
+        total = sum(byte << 8 if i % 2 == 0 else byte for i, byte in enumerate(data))
+        return (total + (total >> 16)) & 0xFFFF
+
+    def ds(self, owner):
+        digest = hashlib.sha256(wire_name(owner.lower()) + self.rdata()).digest()
+        return struct.pack("!HBB", self.key_tag(), self.algorithm, 2) + digest
+
+    def sign(self, data):
+        if self.algorithm == 8:
+            return self.private.sign(data, padding.PKCS1v15(), hashes.SHA256())
+        if self.algorithm == 13:
+            r, s = decode_dss_signature(self.private.sign(data, ec.ECDSA(hashes.SHA256())))
+            return r.to_bytes(32, "big") + s.to_bytes(32, "big")
+        return self.private.sign(data)
+
+
+class Zone:
+    def __init__(self, origin, key):
+        self.origin = origin
+        self.key = key
+        self.records = []
+        # RRsets that belong to the child side of a delegation, which the
+        # parent doesn't sign.
+        self.unsigned = set()
+
+    def add(self, owner, ttl, rtype, rdata, signed=True):
+        self.records.append((owner, ttl, rtype, rdata))
+        if not signed:
+            self.unsigned.add((owner, rtype))
+
+    def soa(self, ttl=3600, minimum=300):
+        rdata = wire_name("ns." + self.origin) + wire_name("hostmaster." + self.origin)
+        rdata += struct.pack("!IIIII", 2024010100, 7200, 3600, 1209600, minimum)
+        self.add(self.origin, ttl, "SOA", rdata)
+
+    def rrsig(self, owner, ttl, rtype, rdatas):
+        count = len(labels(owner)) - (1 if owner.startswith("*.") else 0)
+        header = struct.pack(
+            "!HBBIIIH",
+            TYPES[rtype],
+            self.key.algorithm,
+            count,
+            ttl,
+            EXPIRATION,
+            INCEPTION,
+            self.key.key_tag(),
+        ) + wire_name(self.origin.lower())
+        data = header
+        for rdata in sorted(rdatas):
+            data += wire_name(owner.lower())
+            data += struct.pack("!HHIH", TYPES[rtype], 1, ttl, len(rdata)) + rdata
+        return header + self.key.sign(data)
+
+    def signed(self):
+        rrsets = {}
+        for owner, ttl, rtype, rdata in self.records:
+            rrsets.setdefault((owner, rtype, ttl), []).append(rdata)
+        out = list(self.records)
+        for (owner, rtype, ttl), rdatas in rrsets.items():
+            if (owner, rtype) not in self.unsigned:
+                out.append((owner, ttl, "RRSIG", self.rrsig(owner, ttl, rtype, rdatas)))
+        return out
+
+    def write(self, path):
+        with open(path, "w") as file:
+            file.write("; Generated by sign.py, do not edit.\n")
+            for owner, ttl, rtype, rdata in self.signed():
+                owner = owner if owner.endswith(".") else owner + "."
+                hex_data = (" " + rdata.hex().upper()) if rdata else ""
+                file.write(f"{owner} {ttl} IN {rtype} \\# {len(rdata)}{hex_data}\n")
+
+
+def add_nsec_chain(zone, names):
+    """Link `names`, each with the types it has, into an NSEC chain."""
+    owners = sorted(names, key=canonical_key)
+    for index, owner in enumerate(owners):
+        following = owners[(index + 1) % len(owners)]
+        types = names[owner] + ["RRSIG", "NSEC"]
+        zone.add(owner, 300, "NSEC", wire_name(following) + type_bitmap(types))
+
+
+def add_nsec3_chain(zone, names):
+    """The same for NSEC3, with the names hashed and no opt-out."""
+    hashed = sorted((nsec3_hash(name), types) for name, types in names.items())
+    for index, (digest, types) in enumerate(hashed):
+        following = hashed[(index + 1) % len(hashed)][0]
+        rdata = struct.pack("!BBHB", 1, 0, 0, 0) + bytes([len(following)]) + following
+        owner = base32hex(digest).lower() + "." + zone.origin
+        zone.add(owner, 300, "NSEC3", rdata + type_bitmap(types))
+
+
+def main():
+    root_key, com_key, example_key = Key(8), Key(15), Key(13)
+
+    root = Zone(".", root_key)
+    root.soa(ttl=86400, minimum=86400)
+    root.add(".", 518400, "NS", wire_name("a.root-servers.net"))
+    root.add(".", 172800, "DNSKEY", root_key.rdata())
+    root.add("com", 172800, "NS", wire_name("a.gtld-servers.net"), signed=False)
+    root.add("com", 86400, "DS", com_key.ds("com"))
+    add_nsec_chain(root, {".": ["NS", "SOA", "DNSKEY"], "com": ["NS", "DS"]})
+    root.write("root.zone")
+
+    com = Zone("com", com_key)
+    com.soa(ttl=900, minimum=86400)
+    com.add("com", 172800, "NS", wire_name("a.gtld-servers.net"))
+    com.add("com", 86400, "DNSKEY", com_key.rdata())
+    com.add("com", 0, "NSEC3PARAM", struct.pack("!BBHB", 1, 0, 0, 0))
+    com.add("example.com", 172800, "NS", wire_name("a.iana-servers.net"), signed=False)
+    com.add("example.com", 86400, "DS", example_key.ds("example.com"))
+    com.add("insecure.com", 172800, "NS", wire_name("ns.insecure.com"), signed=False)
+    add_nsec3_chain(
+        com,
+        {
+            "com": ["NS", "SOA", "RRSIG", "DNSKEY", "NSEC3PARAM"],
+            "example.com": ["NS", "DS", "RRSIG"],
+            "insecure.com": ["NS"],
+        },
+    )
+    com.write("com.zone")
+
+    example = Zone("example.com", example_key)
+    example.soa()
+    example.add("example.com", 3600, "NS", wire_name("a.iana-servers.net"))
+    example.add("example.com", 3600, "A", bytes([93, 184, 216, 34]))
+    example.add("example.com", 3600, "DNSKEY", example_key.rdata())
+    example.add("*.wild.example.com", 3600, "A", bytes([10, 0, 0, 2]))
+    example.add("www.example.com", 3600, "CNAME", wire_name("example.com"))
+    add_nsec_chain(
+        example,
+        {
+            "example.com": ["A", "NS", "SOA", "DNSKEY"],
+            "*.wild.example.com": ["A"],
+            "www.example.com": ["CNAME"],
+        },
+    )
+    example.write("example.com.zone")
+
+    with open("anchor.zone", "w") as file:
+        file.write("; Generated by sign.py, do not edit.\n")
+        ds = root_key.ds(".")
+        file.write(f". 172800 IN DS \\# {len(ds)} {ds.hex().upper()}\n")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,191 @@
+use std::fmt::Debug;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use log::debug;
+use structure::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::dns::{DNSError, DNSPacket, DNSQuery};
+
+/// Write a message prefixed with its two byte length, as
+/// messages are sent over TCP (RFC 1035 4.2.2).
+pub fn write_framed<W: Write>(writer: &mut W, message: &[u8]) -> Result<usize, DNSError> {
+    let length: u16 = message.len().try_into()?;
+
+    // One write so the length and message go out in the same segment.
+    let mut buf = Vec::with_capacity(2 + message.len());
+    buf.write_u16::<BigEndian>(length)?;
+    buf.extend_from_slice(message);
+    writer.write_all(&buf)?;
+    writer.flush()?;
+
+    Ok(buf.len())
+}
+
+/// Read one length-prefixed message.
+pub fn read_framed<R: Read>(reader: &mut R) -> Result<Vec<u8>, DNSError> {
+    let length = reader.read_u16::<BigEndian>()?;
+    let mut message = vec![0; length as usize];
+    reader.read_exact(&mut message)?;
+    Ok(message)
+}
+
+/// How queries get to a nameserver and responses get back. The resolver
+/// only ever talks to the outside world through one of these, so that it
+/// can be pointed at something other than the network.
+pub trait Transport: Debug + Send + Sync {
+    fn query(
+        &self,
+        query: &DNSQuery,
+        server: SocketAddr,
+        timeout: Duration,
+    ) -> Result<DNSPacket, DNSError>;
+}
+
+/// The real thing: UDP, and again over TCP if the answer didn't fit.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NetworkTransport;
+
+impl Transport for NetworkTransport {
+    fn query(
+        &self,
+        query: &DNSQuery,
+        server: SocketAddr,
+        timeout: Duration,
+    ) -> Result<DNSPacket, DNSError> {
+        let response = query.query_udp(server, timeout)?;
+        if !response.header.flags.truncated {
+            return Ok(response);
+        }
+        debug!(
+            "Response from {} for {} was truncated, retrying over TCP",
+            server, query.question.name
+        );
+        query.query_tcp(server, timeout)
+    }
+}
+
+#[cfg(any(test, feature = "mock"))]
+pub use mock::MockTransport;
+
+/// Only built for our own tests, or for others' with the `mock` feature.
+#[cfg(any(test, feature = "mock"))]
+mod mock {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use super::Transport;
+    use crate::dns::{DNSError, DNSPacket, DNSQuery, DNSQuestion, DNSRecordType, ResponseCode};
+
+    /// Scripted responses for a made up DNS hierarchy, for testing
+    /// the resolver without a network.
+    ///
+    /// Each server answers a question with the response scripted for exactly
+    /// that name and type if there is one, or else with the one scripted for
+    /// the closest enclosing zone, which is how referrals are set up. Anything
+    /// else gets REFUSED. Servers nothing was scripted for never answer, and
+    /// queries to them time out.
+    #[derive(Debug, Default)]
+    pub struct MockTransport {
+        servers: Mutex<HashMap<SocketAddr, MockServer>>,
+        queries: Mutex<Vec<(SocketAddr, DNSQuestion)>>,
+    }
+
+    #[derive(Debug, Default)]
+    struct MockServer {
+        answers: HashMap<(String, DNSRecordType), DNSPacket>,
+        zones: HashMap<String, DNSPacket>,
+    }
+
+    impl MockTransport {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Have `server` respond with `packet` when asked for `record_type` records for `name`.
+        pub fn answer(
+            &self,
+            server: SocketAddr,
+            name: &str,
+            record_type: DNSRecordType,
+            packet: DNSPacket,
+        ) {
+            let mut servers = self.servers.lock().unwrap();
+            servers
+                .entry(server)
+                .or_default()
+                .answers
+                .insert((normalize(name), record_type), packet);
+        }
+
+        /// Have `server` respond with `packet` to anything at or below `zone`
+        /// that it has no exact answer for.
+        pub fn delegate(&self, server: SocketAddr, zone: &str, packet: DNSPacket) {
+            let mut servers = self.servers.lock().unwrap();
+            servers
+                .entry(server)
+                .or_default()
+                .zones
+                .insert(normalize(zone), packet);
+        }
+
+        /// Every query sent so far, oldest first.
+        pub fn queries(&self) -> Vec<(SocketAddr, DNSQuestion)> {
+            self.queries.lock().unwrap().clone()
+        }
+    }
+
+    impl Transport for MockTransport {
+        fn query(
+            &self,
+            query: &DNSQuery,
+            server: SocketAddr,
+            _timeout: Duration,
+        ) -> Result<DNSPacket, DNSError> {
+            self.queries
+                .lock()
+                .unwrap()
+                .push((server, query.question.clone()));
+
+            let servers = self.servers.lock().unwrap();
+            let Some(scripted) = servers.get(&server) else {
+                return Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into());
+            };
+
+            let name = normalize(&query.question.name);
+            let exact = scripted.answers.get(&(name.clone(), query.question.r#type));
+            let closest_zone = || {
+                let mut zone = name.as_str();
+                loop {
+                    if let Some(packet) = scripted.zones.get(zone) {
+                        return Some(packet);
+                    }
+                    if zone.is_empty() {
+                        return None;
+                    }
+                    zone = zone.split_once('.').map_or("", |(_, parent)| parent);
+                }
+            };
+
+            let mut response = match exact.or_else(closest_zone) {
+                Some(packet) => packet.clone(),
+                None => DNSPacket::error_response(
+                    &query.header,
+                    Some(query.question.clone()),
+                    ResponseCode::Refused,
+                ),
+            };
+            response.header.id = query.header.id;
+            response.header.flags.response = true;
+            response.questions = vec![query.question.clone()];
+            Ok(response)
+        }
+    }
+
+    fn normalize(name: &str) -> String {
+        name.trim_end_matches('.').to_ascii_lowercase()
+    }
+}